@@ -0,0 +1,76 @@
+//! The copy engine used before `CopyPool`: every copy wakes all workers through a `Barrier` rendezvous.
+//! Only kept to compare latencies against.
+
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread::JoinHandle;
+
+#[derive(Copy, Clone)]
+enum Job {
+    Copy { src: *const u8, dst: *mut u8, length: usize },
+    Exit,
+}
+
+unsafe impl Send for Job {}
+unsafe impl Sync for Job {}
+
+pub struct BarrierCopyEngine {
+    thread_count: usize,
+    state: Arc<RwLock<Job>>,
+    barrier: Arc<Barrier>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl BarrierCopyEngine {
+    pub fn new(num_threads: usize) -> Self {
+        let state = Arc::new(RwLock::new(Job::Exit));
+        let barrier = Arc::new(Barrier::new(num_threads));
+        let workers = (1..num_threads)
+            .map(|thread_id| {
+                let state = Arc::clone(&state);
+                let barrier = Arc::clone(&barrier);
+                std::thread::spawn(move || loop {
+                    barrier.wait();
+                    let job = *state.read().unwrap();
+                    if unsafe { Self::handle(job, thread_id, num_threads) } {
+                        break;
+                    }
+                    barrier.wait();
+                })
+            })
+            .collect();
+        Self { thread_count: num_threads, state, barrier, workers }
+    }
+
+    unsafe fn handle(job: Job, thread_num: usize, num_threads: usize) -> bool {
+        match job {
+            Job::Copy { src, dst, length } => {
+                let segment_size = length / num_threads;
+                let start = segment_size * thread_num;
+                let to_copy = if thread_num == num_threads - 1 { length - start } else { segment_size };
+                std::ptr::copy_nonoverlapping(src.add(start), dst.add(start), to_copy);
+                false
+            }
+            Job::Exit => true,
+        }
+    }
+
+    pub fn copy(&mut self, src: &[u8], dst: &mut [u8]) {
+        assert_eq!(src.len(), dst.len());
+        *self.state.write().unwrap() = Job::Copy { src: src.as_ptr(), dst: dst.as_mut_ptr(), length: src.len() };
+        self.barrier.wait();
+        unsafe {
+            Self::handle(*self.state.read().unwrap(), 0, self.thread_count);
+        }
+        self.barrier.wait();
+    }
+}
+
+impl Drop for BarrierCopyEngine {
+    fn drop(&mut self) {
+        *self.state.write().unwrap() = Job::Exit;
+        self.barrier.wait();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,6 +1,15 @@
 extern crate ivshmemmap;
 
 use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use ivshmemmap::kernel::CopyKernel;
+#[cfg(target_os = "linux")]
+use ivshmemmap::pool::CopyPool;
+
+#[cfg(target_os = "linux")]
+mod legacy;
 
 #[cfg(windows)]
 fn main() {
@@ -24,11 +33,224 @@ fn main() {
     }
 }
 
-#[cfg(unix)]
+/// Verifies that ranged copies touch every byte of the range and nothing outside of it,
+/// for every combination of length, offset and amount of worker threads.
+#[cfg(target_os = "linux")]
+fn check_partitioning() {
+    const SIZE: usize = 64 * 1024;
+    const GUARD: usize = 4096;
+    const CANARY: u8 = 0xAA;
+
+    let shm = ivshmemmap::SharedMemoryBuilder::shm(&format!("/ivshmemmap-tests-check-{}", std::process::id()))
+        .create(true)
+        .size(SIZE)
+        .unlink_on_drop(true)
+        .open()
+        .unwrap();
+
+    let mut lengths: Vec<usize> = (0..=520).collect();
+    for pages in 1usize..=8 {
+        for delta in [-65isize, -64, -63, -1, 0, 1, 63, 64, 65] {
+            lengths.push((pages * 4096).saturating_add_signed(delta));
+        }
+    }
+    lengths.push(SIZE - 2 * GUARD);
+
+    for (kernel, threads) in CopyKernel::ALL.into_iter().filter(CopyKernel::is_supported).flat_map(|kernel| (1..=9).map(move |threads| (kernel, threads))) {
+        let mut device = shm.map(1).unwrap();
+        // Disable inline copies, so even the smallest copies are partitioned across the workers.
+        device.set_copy_pool(Arc::new(CopyPool::with_inline_threshold(threads, 0).with_kernel(kernel)));
+        device.set_all_bytes(CANARY);
+        for &offset in &[GUARD, GUARD + 1, GUARD + 63, GUARD + 4095] {
+            for &length in &lengths {
+                if offset + length + GUARD > SIZE {
+                    continue;
+                }
+                let pattern: Vec<u8> = (0..length).map(|index| (index % 251) as u8 ^ 0x55).collect();
+                device.write_at(offset, &pattern).unwrap();
+                assert!(device[offset - GUARD..offset].iter().all(|&byte| byte == CANARY), "Write before range: threads={threads} offset={offset} length={length}");
+                assert!(device[offset + length..offset + length + GUARD].iter().all(|&byte| byte == CANARY), "Write after range: threads={threads} offset={offset} length={length}");
+                assert_eq!(&device[offset..offset + length], &pattern[..], "Incomplete write: threads={threads} offset={offset} length={length}");
+
+                let mut read_back = vec![CANARY; length + 2];
+                device.read_at(offset, &mut read_back[1..length + 1]).unwrap();
+                assert_eq!(&read_back[1..length + 1], &pattern[..], "Incomplete read: threads={threads} offset={offset} length={length}");
+                assert_eq!((read_back[0], read_back[length + 1]), (CANARY, CANARY), "Read outside range: threads={threads} offset={offset} length={length}");
+
+                device[offset..offset + length].fill(CANARY);
+            }
+        }
+        println!("Partitioning verified with {threads} worker threads using {kernel:?}");
+    }
+}
+
+/// Compares the median copy latency of `CopyPool` against the previous barrier based copy engine.
+#[cfg(target_os = "linux")]
+fn compare_latency(threads: usize) {
+    const SIZE: usize = 64 * 1024 * 1024;
+
+    let shm = ivshmemmap::SharedMemoryBuilder::shm(&format!("/ivshmemmap-tests-latency-{}", std::process::id()))
+        .create(true)
+        .size(SIZE)
+        .unlink_on_drop(true)
+        .open()
+        .unwrap();
+    let mut device = shm.map(threads).unwrap();
+    let source = vec![0x5Au8; SIZE];
+
+    let mut legacy = legacy::BarrierCopyEngine::new(threads);
+    let always_parallel = Arc::new(CopyPool::with_inline_threshold(threads, 0));
+    let default_pool = Arc::clone(device.copy_pool());
+
+    let median = |timings: &mut Vec<Duration>| {
+        timings.sort();
+        timings[timings.len() / 2]
+    };
+
+    println!("{:>10} {:>14} {:>14} {:>14}", "bytes", "barrier", "pool", "pool+inline");
+    for length in [64, 1024, 4096, 16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024, 16 * 1024 * 1024, SIZE] {
+        let iterations = (256 * 1024 * 1024 / length).clamp(8, 10_000);
+        let mut results = Vec::new();
+
+        let mut timings = Vec::with_capacity(iterations);
+        for _ in 0..iterations {
+            let start = Instant::now();
+            legacy.copy(&source[..length], &mut device[..length]);
+            timings.push(start.elapsed());
+        }
+        results.push(median(&mut timings));
+
+        for pool in [&always_parallel, &default_pool] {
+            device.set_copy_pool(Arc::clone(pool));
+            let mut timings = Vec::with_capacity(iterations);
+            for _ in 0..iterations {
+                let start = Instant::now();
+                device.write_at(0, &source[..length]).unwrap();
+                timings.push(start.elapsed());
+            }
+            results.push(median(&mut timings));
+        }
+
+        println!("{:>10} {:>14?} {:>14?} {:>14?}", length, results[0], results[1], results[2]);
+    }
+}
+
+/// Reports the bandwidth of every supported copy kernel, writing into and reading from a /dev/shm file.
+#[cfg(target_os = "linux")]
+fn benchmark_kernels(threads: usize) {
+    const SIZE: usize = 256 * 1024 * 1024;
+    const ITERATIONS: usize = 10;
+
+    let shm = ivshmemmap::SharedMemoryBuilder::shm(&format!("/ivshmemmap-tests-bench-{}", std::process::id()))
+        .create(true)
+        .size(SIZE)
+        .unlink_on_drop(true)
+        .open()
+        .unwrap();
+    let mut device = shm.map(1).unwrap();
+    let source = vec![0xC3u8; SIZE];
+    let mut destination = vec![0u8; SIZE];
+    let gibs = |duration: Duration| (SIZE * ITERATIONS) as f64 / duration.as_secs_f64() / 1073741824f64;
+
+    println!("{:>16} {:>14} {:>14}", "kernel", "write GiB/s", "read GiB/s");
+    for kernel in CopyKernel::ALL.into_iter().filter(CopyKernel::is_supported) {
+        device.set_copy_pool(Arc::new(CopyPool::new(threads).with_kernel(kernel)));
+        // Fault in every page before measuring.
+        device.write_to_all(&source);
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            device.write_to_all(&source);
+        }
+        let write = gibs(start.elapsed());
+
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            device.read_all_into(&mut destination);
+        }
+        let read = gibs(start.elapsed());
+
+        assert_eq!(destination, source);
+        println!("{:>16} {:>14.2} {:>14.2}", format!("{kernel:?}"), write, read);
+    }
+}
+
+/// Hammers a sequence lock with two writers and several readers, each through its own mapping of the same memory,
+/// and fails on the first torn read.
+#[cfg(target_os = "linux")]
+fn stress_seqlock(duration: Duration) {
+    use ivshmemmap::seqlock::SeqLock;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    type Status = [u64; 64];
+    const READERS: usize = 4;
+
+    let shm = ivshmemmap::SharedMemoryBuilder::shm(&format!("/ivshmemmap-tests-seqlock-{}", std::process::id()))
+        .create(true)
+        .size(4096)
+        .unlink_on_drop(true)
+        .open()
+        .unwrap();
+    let mut mappings: Vec<_> = (0..READERS + 2).map(|_| shm.map(1).unwrap()).collect();
+    let mut locks: Vec<_> = mappings.iter_mut().map(|device| SeqLock::<Status>::attach(&mut device[..], 0).unwrap()).collect();
+    let readers = locks.split_off(2);
+
+    let stop = AtomicBool::new(false);
+    let reads = AtomicU64::new(0);
+    let retries = AtomicU64::new(0);
+    std::thread::scope(|scope| {
+        for (writer_id, lock) in locks.iter().enumerate() {
+            let stop = &stop;
+            scope.spawn(move || {
+                let mut generation = writer_id as u64;
+                while !stop.load(Ordering::Relaxed) {
+                    generation += 2;
+                    lock.write(&[generation; 64]);
+                }
+            });
+        }
+        for lock in &readers {
+            let (stop, reads, retries) = (&stop, &reads, &retries);
+            scope.spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match lock.try_read() {
+                        Some(status) => {
+                            assert!(status.iter().all(|&value| value == status[0]), "Torn read: {status:?}");
+                            reads.fetch_add(1, Ordering::Relaxed);
+                        }
+                        None => {
+                            retries.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+        std::thread::sleep(duration);
+        stop.store(true, Ordering::Relaxed);
+    });
+
+    println!(
+        "No torn reads in {:?}: {} writes, {} consistent reads, {} retried reads",
+        duration,
+        readers[0].sequence() / 2,
+        reads.into_inner(),
+        retries.into_inner()
+    );
+}
+
+#[cfg(target_os = "linux")]
 fn main() {
     use std::path::PathBuf;
     use std::str::FromStr;
 
+    match std::env::args().nth(1).as_deref() {
+        Some("check") => return check_partitioning(),
+        Some("latency") => return compare_latency(4),
+        Some("bench") => return benchmark_kernels(4),
+        Some("seqlock") => return stress_seqlock(Duration::from_secs(10)),
+        _ => {}
+    }
+
     let mut device = ivshmemmap::linux_ivshmem_device(&PathBuf::from_str("/dev/shm/shm-portal").unwrap(), 4).unwrap();
     println!("Size: {:?}", device.len());
     println!("Testing manipulation...");
@@ -41,6 +263,13 @@ fn main() {
         device.write_to_all(&bytes);
         let duration = start.elapsed();
         println!("Changed value: {:?} -> {:?} ({:.2} GiB/s, {:.2} GB/s)", existing_byte, next_byte, bytes.len() as f64 / duration.as_secs_f64() / 1073741824f64, bytes.len() as f64 / duration.as_secs_f64() / 1000000000f64);
+
+        let mut read_back = vec![0u8; device.len()];
+        let start = Instant::now();
+        device.read_all_into(&mut read_back);
+        let duration = start.elapsed();
+        assert_eq!(read_back, bytes);
+        println!("Read back: {:?} ({:.2} GiB/s, {:.2} GB/s)", read_back[1], read_back.len() as f64 / duration.as_secs_f64() / 1073741824f64, read_back.len() as f64 / duration.as_secs_f64() / 1000000000f64);
         std::thread::sleep(Duration::from_millis(100));
     }
 }
@@ -0,0 +1,234 @@
+//! A common interface over the ways to reach ivshmem shared memory, so applications can be written once and tested
+//! without a VM.
+//!
+//! | Backend         | Platform | Shared memory                        | Doorbells                     |
+//! |-----------------|----------|--------------------------------------|-------------------------------|
+//! | `SharedMemory`  | Linux    | a file or POSIX shared memory object | none                          |
+//! | `PciBackend`    | Linux    | BAR2 of the PCI device, in a guest   | through UIO or VFIO           |
+//! | `WindowsBackend`| Windows  | the virtio-win IVSHMEM driver        | ring only, `wait` unsupported |
+//! | `MockBackend`   | any      | a heap allocation shared in-process  | in-process, between mock peers|
+//!
+//! # Examples
+//!
+//! ```
+//! use ivshmemmap::backend::{Backend, MockBackend, MockOptions};
+//! use ivshmemmap::error::Error;
+//!
+//! // Application code only depends on the trait.
+//! fn send_greeting<B: Backend>(backend: &B, peer: u16) -> Result<(), Error> {
+//!     let mut device = backend.map(1)?;
+//!     device.write_volatile(0, u64::from_le_bytes(*b"greeting"))?;
+//!     backend.ring(peer, 0)
+//! }
+//!
+//! let host = MockBackend::open(MockOptions { size: 4096, vectors: 1 }).unwrap();
+//! let guest = host.connect();
+//!
+//! std::thread::scope(|scope| {
+//!     scope.spawn(|| send_greeting(&host, guest.peer_id().unwrap()).unwrap());
+//!     assert_eq!(guest.wait(0).unwrap(), 1);
+//! });
+//! let device = guest.map(1).unwrap();
+//! assert_eq!(&device[..8], b"greeting");
+//! ```
+
+use crate::device::IvshmemDevice;
+use crate::error::Error;
+use crate::pool::CopyPool;
+use std::alloc::Layout;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A source of ivshmem shared memory, together with the doorbells of its peers.
+pub trait Backend: Send + Sync {
+    /// What `open` needs to find the shared memory.
+    type Options;
+
+    /// Opens the shared memory without mapping it.
+    fn open(options: Self::Options) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    /// Size of the shared memory in bytes.
+    fn size(&self) -> usize;
+
+    /// Maps the entire shared memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_threads`: Amount of worker threads for copy operations.
+    fn map(&self, worker_threads: usize) -> Result<IvshmemDevice, Error>;
+
+    /// Our own peer ID, if the backend takes part in a doorbell protocol and the ID is known.
+    fn peer_id(&self) -> Option<u16>;
+
+    /// Amount of interrupt vectors per peer. Backends without doorbells have none.
+    fn vectors(&self) -> usize;
+
+    /// Rings the doorbell of `peer`, raising interrupt `vector` on its side.
+    fn ring(&self, peer: u16, vector: usize) -> Result<(), Error>;
+
+    /// Blocks until interrupt `vector` is raised by any peer.
+    ///
+    /// returns: The amount of times the doorbell was rung since the last wait.
+    fn wait(&self, vector: usize) -> Result<u64, Error>;
+}
+
+/// Alignment of the mock memory, so it lines up with pages like real shared memory.
+const MOCK_ALIGNMENT: usize = 4096;
+
+/// Options of `MockBackend::open`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockOptions {
+    /// Size of the shared memory in bytes. Must not be 0.
+    pub size: usize,
+    /// Amount of interrupt vectors per peer.
+    pub vectors: usize,
+}
+
+/// Memory and doorbells shared by all peers of a mock.
+struct MockShared {
+    memory: *mut u8,
+    layout: Layout,
+    vectors: usize,
+    /// Pending doorbell counts of every connected peer, indexed by vector.
+    doorbells: Mutex<BTreeMap<u16, Vec<u64>>>,
+    rung: Condvar,
+}
+
+// The memory is only accessed through the devices created by `map`, which synchronize like real shared memory.
+unsafe impl Send for MockShared {}
+unsafe impl Sync for MockShared {}
+
+impl Drop for MockShared {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.memory, self.layout) };
+    }
+}
+
+/// A peer of in-process shared memory, for tests of code written against `Backend`.
+///
+/// The memory is zeroed on creation and freed once every peer and every mapped device is dropped. All peers
+/// obtained through `connect` map the same memory, and can ring each other's doorbells.
+pub struct MockBackend {
+    id: u16,
+    shared: Arc<MockShared>,
+}
+
+impl Debug for MockBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MockBackend{{ id: {:?} size: {:?} }}", self.id, self.shared.layout.size())
+    }
+}
+
+impl MockBackend {
+    /// Adds another peer to the shared memory.
+    ///
+    /// Panics if all 65536 peer IDs are in use.
+    pub fn connect(&self) -> MockBackend {
+        let mut doorbells = self.shared.doorbells.lock().unwrap();
+        let id = (0..=u16::MAX)
+            .find(|id| !doorbells.contains_key(id))
+            .expect("All peer IDs are in use");
+        doorbells.insert(id, vec![0; self.shared.vectors]);
+        MockBackend {
+            id,
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// IDs of all peers that are currently connected, including our own.
+    pub fn peers(&self) -> Vec<u16> {
+        self.shared.doorbells.lock().unwrap().keys().copied().collect()
+    }
+}
+
+impl Backend for MockBackend {
+    type Options = MockOptions;
+
+    fn open(options: MockOptions) -> Result<Self, Error> {
+        if options.size == 0 {
+            return Err(Error::Unsupported("empty shared memory"));
+        }
+        let layout = Layout::from_size_align(options.size, MOCK_ALIGNMENT).map_err(|_| Error::MapFailed {
+            device: "mock".to_string(),
+            size: options.size as u64,
+            protection: None,
+            flags: None,
+            source: std::io::ErrorKind::OutOfMemory.into(),
+        })?;
+        let memory = unsafe { std::alloc::alloc_zeroed(layout) };
+        if memory.is_null() {
+            return Err(Error::MapFailed {
+                device: "mock".to_string(),
+                size: options.size as u64,
+                protection: None,
+                flags: None,
+                source: std::io::ErrorKind::OutOfMemory.into(),
+            });
+        }
+        Ok(MockBackend {
+            id: 0,
+            shared: Arc::new(MockShared {
+                memory,
+                layout,
+                vectors: options.vectors,
+                doorbells: Mutex::new(BTreeMap::from([(0, vec![0; options.vectors])])),
+                rung: Condvar::new(),
+            }),
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.shared.layout.size()
+    }
+
+    fn map(&self, worker_threads: usize) -> Result<IvshmemDevice, Error> {
+        let pool = Arc::new(CopyPool::new(worker_threads));
+        Ok(IvshmemDevice::with_mapping(
+            self.shared.memory,
+            self.size(),
+            Some(Box::new(Arc::clone(&self.shared))),
+            pool,
+        ))
+    }
+
+    fn peer_id(&self) -> Option<u16> {
+        Some(self.id)
+    }
+
+    fn vectors(&self) -> usize {
+        self.shared.vectors
+    }
+
+    fn ring(&self, peer: u16, vector: usize) -> Result<(), Error> {
+        let mut doorbells = self.shared.doorbells.lock().unwrap();
+        let counts = doorbells.get_mut(&peer).ok_or(Error::UnknownPeer(peer))?;
+        *counts.get_mut(vector).ok_or(Error::InvalidVector(vector))? += 1;
+        self.shared.rung.notify_all();
+        Ok(())
+    }
+
+    fn wait(&self, vector: usize) -> Result<u64, Error> {
+        if vector >= self.shared.vectors {
+            return Err(Error::InvalidVector(vector));
+        }
+        let mut doorbells = self.shared.doorbells.lock().unwrap();
+        loop {
+            let count = &mut doorbells.get_mut(&self.id).expect("Connected peers have doorbells")[vector];
+            if *count != 0 {
+                return Ok(std::mem::take(count));
+            }
+            doorbells = self.shared.rung.wait(doorbells).unwrap();
+        }
+    }
+}
+
+impl Drop for MockBackend {
+    fn drop(&mut self) {
+        if let Ok(mut doorbells) = self.shared.doorbells.lock() {
+            doorbells.remove(&self.id);
+        }
+    }
+}
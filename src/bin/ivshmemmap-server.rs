@@ -0,0 +1,138 @@
+//! Drop-in replacement for QEMU's `ivshmem-server`, accepting the same options.
+
+#[cfg(target_os = "linux")]
+const USAGE: &str = "Usage: ivshmemmap-server [OPTION]...
+  -h: show this help
+  -v: verbose mode
+  -F: foreground mode (default is to daemonize)
+  -p <pid-file>: path to the PID file (used in daemon mode only)
+     default /var/run/ivshmem-server.pid
+  -S <unix-socket-path>: path to the unix socket to listen to
+     default /tmp/ivshmem_socket
+  -M <name>: POSIX shared memory object to use
+     default ivshmem
+  -m <dir>: where to create shared memory
+  -l <size>: size of shared memory in bytes
+     suffixes K, M and G can be used, e.g. 1K means 1024
+     default 4194304
+  -n <nvectors>: number of vectors
+     default 1";
+
+/// Same limit as QEMU's `IVSHMEM_SERVER_MAX_VECTORS`.
+#[cfg(target_os = "linux")]
+const MAX_VECTORS: usize = 64;
+
+#[cfg(target_os = "linux")]
+fn parse_size(value: &str) -> Option<usize> {
+    let (digits, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1usize << 10),
+        'M' => (&value[..value.len() - 1], 1usize << 20),
+        'G' => (&value[..value.len() - 1], 1usize << 30),
+        _ => (value, 1usize),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(target_os = "linux")]
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    use ivshmemmap::{IvshmemServer, SharedMemoryBuilder};
+    use std::path::PathBuf;
+
+    // Defaults are equal to those of QEMU's ivshmem-server.
+    let mut verbose = false;
+    let mut foreground = false;
+    let mut pid_file = PathBuf::from("/var/run/ivshmem-server.pid");
+    let mut socket_path = PathBuf::from("/tmp/ivshmem_socket");
+    let mut shm_name = String::from("ivshmem");
+    let mut shm_dir = None;
+    let mut size = 4usize << 20;
+    let mut vectors = 1usize;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| fail(&format!("Missing value for {arg}\n{USAGE}")));
+        match arg.as_str() {
+            "-h" => {
+                println!("{USAGE}");
+                return;
+            }
+            "-v" => verbose = true,
+            "-F" => foreground = true,
+            "-p" => pid_file = PathBuf::from(value()),
+            "-S" => socket_path = PathBuf::from(value()),
+            "-M" => shm_name = value(),
+            "-m" => shm_dir = Some(PathBuf::from(value())),
+            "-l" => {
+                let value = value();
+                size = parse_size(&value).unwrap_or_else(|| fail(&format!("Invalid size: {value}")));
+                if !size.is_power_of_two() {
+                    fail(&format!("Size should be a power of 2: {value}"));
+                }
+            }
+            "-n" => {
+                let value = value();
+                vectors = value
+                    .parse()
+                    .ok()
+                    .filter(|vectors| *vectors <= MAX_VECTORS)
+                    .unwrap_or_else(|| fail(&format!("Invalid amount of vectors: {value}")));
+            }
+            _ => fail(USAGE),
+        }
+    }
+
+    // Like QEMU, a directory gets a file that is unlinked right away. Peers only receive its descriptor.
+    let shm = match &shm_dir {
+        Some(dir) => SharedMemoryBuilder::file(&dir.join(format!("ivshmem.{}", std::process::id())))
+            .create(true)
+            .exclusive(true)
+            .size(size)
+            .open()
+            .and_then(|mut shm| shm.unlink().map(|_| shm)),
+        None => SharedMemoryBuilder::shm(&shm_name).create(true).size(size).mode(0o700).open(),
+    }
+    .unwrap_or_else(|error| fail(&format!("Unable to open the shared memory: {error}")));
+    let mut server = IvshmemServer::with_shared_memory(&socket_path, shm, vectors)
+        .unwrap_or_else(|error| fail(&format!("Unable to start the server: {error}")));
+    if verbose {
+        println!("Listening on {:?} ({:?})", socket_path, server);
+    }
+
+    if !foreground {
+        if unsafe { libc::daemon(1, 1) } == -1 {
+            fail(&format!("Unable to daemonize: {}", std::io::Error::last_os_error()));
+        }
+        if let Err(error) = std::fs::write(&pid_file, format!("{}\n", std::process::id())) {
+            fail(&format!("Unable to write the PID file {pid_file:?}: {error}"));
+        }
+    }
+
+    let mut peers: Vec<u16> = Vec::new();
+    loop {
+        if let Err(error) = server.process(None) {
+            fail(&format!("Server stopped: {error}"));
+        }
+        if verbose {
+            let current: Vec<u16> = server.peer_ids().collect();
+            for id in current.iter().filter(|id| !peers.contains(id)) {
+                println!("Peer {id} connected");
+            }
+            for id in peers.iter().filter(|id| !current.contains(id)) {
+                println!("Peer {id} disconnected");
+            }
+            peers = current;
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("ivshmemmap-server is only supported on Linux.");
+    std::process::exit(1);
+}
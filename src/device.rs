@@ -1,104 +1,82 @@
+use crate::error::DeviceError;
 use std::fmt::Debug;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
-use std::sync::{Arc, Barrier, RwLock};
+use crate::pod::Pod;
+use crate::pool::{CopyHandle, CopyPool};
+use crate::region::{Region, RegionMut};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 pub struct IvshmemDevice {
-    memory: &'static mut [u8],
-    thread_count: usize,
-    state: Arc<RwLock<Job>>,
-    barrier: Arc<Barrier>
+    memory: *mut u8,
+    length: usize,
+    pool: Arc<CopyPool>,
+    /// Owner of the mapping. Dropping it releases the memory, so it must outlive all access through `memory`.
+    /// Declared last, so it is dropped after the pool.
+    mapping: Option<Box<dyn Send + Sync>>,
 }
 
-#[derive(Copy, Clone)]
-enum Job {
-    RELEASE,
-    COPY{
-        src: *const u8, dst: *mut u8, length: usize
-    },
-    EXIT
-}
-
-unsafe impl Send for Job {}
-unsafe impl Sync for Job {}
+// The raw pointer is only ever handed out through borrows of the device itself.
+unsafe impl Send for IvshmemDevice {}
+unsafe impl Sync for IvshmemDevice {}
 
 impl Debug for IvshmemDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Memory size: {}", self.memory.len())
+        write!(f, "Memory size: {}", self.length)
     }
 }
 
 impl IvshmemDevice {
-    pub(crate) fn with_memory(map: &'static mut [u8], num_threads: usize) -> Self {
-        assert!(num_threads > 0, "Tried to create mapped memory without worker threads. Requires at least 1.");
-
-        let zelf = Self{
-            memory: map,
-            thread_count: num_threads,
-            state: Arc::new(RwLock::new(Job::RELEASE)),
-            barrier: Arc::new(Barrier::new(num_threads)),
-        };
-
-        for thread_id in 1..num_threads {
-            let barrier_clone = Arc::clone(&zelf.barrier);
-            let state_clone = Arc::clone(&zelf.state);
-
-            std::thread::Builder::new().name(format!("CopyWorker{thread_id}")).spawn(move || unsafe {
-                loop{
-                    barrier_clone.wait();
-                    if Self::handle_worker_state(state_clone.read().unwrap().clone(), thread_id, num_threads) {
-                        break;
-                    }
-                }
-            }).expect("Unable to spawn worker thread");
+    /// Creates a device on memory that is owned by `mapping`.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory`: Start of the mapped memory.
+    /// * `length`: Size of the mapped memory in bytes.
+    /// * `mapping`: Releases the memory when dropped. This happens after the device stopped copying.
+    /// * `pool`: The worker threads that perform copies.
+    pub(crate) fn with_mapping(memory: *mut u8, length: usize, mapping: Option<Box<dyn Send + Sync>>, pool: Arc<CopyPool>) -> Self {
+        Self{
+            memory,
+            length,
+            pool,
+            mapping,
         }
+    }
 
-        zelf
+    /// The pool that performs copies for this device.
+    pub fn copy_pool(&self) -> &Arc<CopyPool> {
+        &self.pool
     }
 
+    /// Replaces the pool that performs copies for this device. Use this to share a single pool between devices.
+    /// The previous pool stops its worker threads once no device uses it anymore.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    ///
+    /// let first = ivshmemmap::linux_ivshmem_device(Path::new("/dev/shm/first"), 8).unwrap();
+    /// let mut second = ivshmemmap::linux_ivshmem_device(Path::new("/dev/shm/second"), 1).unwrap();
+    /// second.set_copy_pool(first.copy_pool().clone());
+    /// ```
+    pub fn set_copy_pool(&mut self, pool: Arc<CopyPool>) {
+        self.pool = pool;
+    }
+
+    /// Stops using worker threads. All further copies are performed by the caller.
+    /// The worker threads are joined, unless the pool is shared with another device.
     pub fn exit_workers(&mut self) {
-        *self.state.write().unwrap() = Job::EXIT;
-        self.barrier.wait();
+        self.pool = Arc::new(CopyPool::new(1));
     }
 
+    /// Stops the worker threads and leaks the mapping, so the memory stays valid for the remainder of the process.
     pub fn into_memory(mut self) -> &'static mut [u8]{
         self.exit_workers();
-        self.memory
-    }
-
-    /// Executes work such as copying a memory fragment.
-    ///
-    /// # Arguments
-    ///
-    /// * `job`: A description of the work to be done.
-    /// * `thread_num`: The current worker thread number. Used to determine which section of the object to copy for concurrency.
-    /// * `num_threads`: Total amount of worker threads
-    ///
-    /// returns: true if the thread is requested to exit
-    unsafe fn handle_worker_state(job: Job, thread_num: usize, num_threads: usize) -> bool {
-        match job {
-            Job::COPY { src, dst, length } => {
-                let segment_size = length / num_threads;
-                let remainder = length % segment_size;
-                let to_copy = if thread_num == num_threads - 1 {
-                    segment_size + remainder
-                }else{
-                    segment_size
-                };
-
-                let src_index = src.byte_add(segment_size * thread_num);
-                let dst_index = dst.byte_add(segment_size * thread_num);
-                std::ptr::copy_nonoverlapping(
-                    src_index,
-                    dst_index,
-                    to_copy,
-                );
-                false
-            },
-            Job::EXIT => {
-                true
-            },
-            _ => false
-        }
+        std::mem::forget(self.mapping.take());
+        unsafe { std::slice::from_raw_parts_mut(self.memory, self.length) }
     }
 
     /// Sets all bytes in the memory buffer to `byte`.
@@ -109,7 +87,7 @@ impl IvshmemDevice {
     /// * `byte`: the byte to change the memory buffer to.
     pub fn set_all_bytes(&mut self, byte: u8) {
         unsafe {
-            std::ptr::write_bytes(self.memory.as_mut_ptr(), byte, self.memory.len())
+            std::ptr::write_bytes(self.memory, byte, self.length)
         }
     }
 
@@ -120,30 +98,405 @@ impl IvshmemDevice {
     ///
     /// * `buf`: The source. Length must be equal to the length of the shared memory.
     pub fn write_to_all(&mut self, buf: &[u8]) {
+        assert_eq!(
+            buf.len(),
+            self.length,
+            "Size of bytes should be equal to the whole memory buffer size."
+        );
         unsafe {
-            #[cfg(debug_assertions)]
-            assert_eq!(
-                buf.len(),
-                self.memory.len(),
-                "Size of bytes should be equal to the whole memory buffer size."
-            );
-
-            *self.state.write().unwrap() = Job::COPY {
-                src: buf.as_ptr(),
-                dst: self.memory.as_mut_ptr(),
-                length: self.memory.len(),
-            };
-            self.barrier.wait();
-            Self::handle_worker_state(self.state.read().unwrap().clone(), 0, self.thread_count);
+            self.parallel_copy(buf.as_ptr(), self.memory, self.length);
         }
     }
+
+    /// Overwrites `buf.len()` bytes of the shared memory starting at `offset`, using all worker threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory.
+    /// * `buf`: The source.
+    ///
+    /// returns: An error if the written range does not fit inside the shared memory. Nothing is written in that case.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-write", 4096).unwrap();
+    /// let mut device = shm.map(4).unwrap();
+    ///
+    /// device.write_at(1024, &[1, 2, 3]).unwrap();
+    /// let mut slot = [0u8; 3];
+    /// device.read_at(1024, &mut slot).unwrap();
+    /// assert_eq!(slot, [1, 2, 3]);
+    ///
+    /// assert!(device.write_at(4095, &[1, 2]).is_err());
+    /// ```
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), DeviceError> {
+        self.check_bounds(offset, buf.len())?;
+        unsafe {
+            self.parallel_copy(buf.as_ptr(), self.memory.byte_add(offset), buf.len());
+        }
+        Ok(())
+    }
+
+    /// Copies `buf.len()` bytes of the shared memory starting at `offset` into `buf`, using all worker threads.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory.
+    /// * `buf`: The destination.
+    ///
+    /// returns: An error if the read range does not fit inside the shared memory. `buf` is untouched in that case.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check_bounds(offset, buf.len())?;
+        unsafe {
+            self.parallel_copy(self.memory.byte_add(offset), buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), DeviceError> {
+        check_bounds(offset, length, self.length)
+    }
+
+    /// Runs `f` with a scope in which copies can be submitted to the worker threads without blocking.
+    ///
+    /// Every copy submitted through the scope is complete when this function returns, even if `f` panics or leaks
+    /// a handle. Buffers are borrowed for the lifetime of the scope, which guarantees they outlive their copies.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use std::future::Future;
+    /// use std::task::{Context, Poll, Wake};
+    ///
+    /// struct ThreadWaker(std::thread::Thread);
+    ///
+    /// impl Wake for ThreadWaker {
+    ///     fn wake(self: Arc<Self>) {
+    ///         self.0.unpark();
+    ///     }
+    /// }
+    ///
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-async", 4 << 20).unwrap();
+    /// let mut device = shm.map(4).unwrap();
+    ///
+    /// let frame = vec![9u8; 2 << 20];
+    /// let mut previous = vec![0u8; 2 << 20];
+    /// device.copy_scope(|scope| {
+    ///     let write = scope.write_async(0, &frame).unwrap();
+    ///     let mut read = scope.read_async(2 << 20, &mut previous).unwrap();
+    ///
+    ///     // Overlap other work with the transfer, then wait for it to finish.
+    ///     write.wait();
+    ///
+    ///     // Handles are also futures.
+    ///     let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+    ///     let mut context = Context::from_waker(&waker);
+    ///     while std::pin::Pin::new(&mut read).poll(&mut context) == Poll::Pending {
+    ///         std::thread::park();
+    ///     }
+    /// });
+    /// assert!(device[..2 << 20].iter().all(|&byte| byte == 9));
+    /// ```
+    pub fn copy_scope<'env, F, T>(&'env mut self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope CopyScope<'scope, 'env>) -> T,
+    {
+        let scope = CopyScope {
+            memory: self.memory,
+            length: self.length,
+            pool: Arc::clone(&self.pool),
+            pending: Mutex::new(Vec::new()),
+            _scope: PhantomData,
+            _env: PhantomData,
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&scope)));
+        scope.wait_all();
+        match result {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    /// Copies the entire contents of the shared memory into `buf`, using all worker threads.
+    /// Panics if the size of `buf` does not equal the size of the shared memory buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: The destination. Length must be equal to the length of the shared memory.
+    pub fn read_all_into(&self, buf: &mut [u8]) {
+        assert_eq!(
+            buf.len(),
+            self.length,
+            "Size of bytes should be equal to the whole memory buffer size."
+        );
+        unsafe {
+            self.parallel_copy(self.memory, buf.as_mut_ptr(), self.length);
+        }
+    }
+
+    /// The entire shared memory as a region, which can be split and shared with other threads.
+    pub fn as_region(&self) -> Region<'_> {
+        unsafe { Region::new(self.memory, self.length, 0, Arc::clone(&self.pool)) }
+    }
+
+    /// The entire shared memory as a mutable region, which can be split into parts that are used by different threads.
+    ///
+    /// The device cannot be used while the parts are alive:
+    ///
+    /// ```compile_fail,E0499
+    /// # let shm = ivshmemmap::SharedMemoryBuilder::shm("/ivshmemmap-doc-never-created").open().unwrap();
+    /// let mut device = shm.map(1).unwrap();
+    /// let (header, frames) = device.as_region_mut().split_at_mut(4096).unwrap();
+    /// device.write_at(0, &[1]).unwrap();
+    /// drop((header, frames));
+    /// ```
+    pub fn as_region_mut(&mut self) -> RegionMut<'_> {
+        unsafe { RegionMut::new(self.memory, self.length, 0, Arc::clone(&self.pool)) }
+    }
+
+    /// Interprets the shared memory at `offset` as a `T`.
+    ///
+    /// The other side may modify the memory at any time. Use a `SeqLock` for values that must be read consistently.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory. Must be aligned for `T`.
+    ///
+    /// returns: An error if the value does not fit inside the shared memory, or if it is misaligned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// ivshmemmap::pod! {
+    ///     pub struct Status {
+    ///         pub width: u32,
+    ///         pub height: u32,
+    ///         pub frames: u64,
+    ///     }
+    /// }
+    ///
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-view", 4096).unwrap();
+    /// let mut device = shm.map(1).unwrap();
+    ///
+    /// *device.view_mut::<Status>(64).unwrap() = Status { width: 1920, height: 1080, frames: 1 };
+    /// assert_eq!(device.view::<Status>(64).unwrap().height, 1080);
+    /// assert_eq!(device.view::<u32>(68).unwrap(), &1080);
+    ///
+    /// assert!(device.view::<Status>(65).is_err()); // Misaligned
+    /// assert!(device.view::<Status>(4090).is_err()); // Out of bounds
+    /// ```
+    pub fn view<T: Pod>(&self, offset: usize) -> Result<&T, DeviceError> {
+        let pointer = self.typed_pointer::<T>(offset, 1)?;
+        Ok(unsafe { &*pointer })
+    }
+
+    /// Interprets the shared memory at `offset` as a mutable `T`. See `view`.
+    pub fn view_mut<T: Pod>(&mut self, offset: usize) -> Result<&mut T, DeviceError> {
+        let pointer = self.typed_pointer::<T>(offset, 1)?;
+        Ok(unsafe { &mut *pointer })
+    }
+
+    /// Interprets `length` consecutive values of the shared memory, starting at `offset`, as a slice of `T`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory. Must be aligned for `T`.
+    /// * `length`: Amount of values in the slice.
+    ///
+    /// returns: An error if the slice does not fit inside the shared memory, or if it is misaligned.
+    pub fn view_slice<T: Pod>(&self, offset: usize, length: usize) -> Result<&[T], DeviceError> {
+        let pointer = self.typed_pointer::<T>(offset, length)?;
+        Ok(unsafe { std::slice::from_raw_parts(pointer, length) })
+    }
+
+    /// Interprets `length` consecutive values of the shared memory, starting at `offset`, as a mutable slice of `T`.
+    /// See `view_slice`.
+    pub fn view_slice_mut<T: Pod>(&mut self, offset: usize, length: usize) -> Result<&mut [T], DeviceError> {
+        let pointer = self.typed_pointer::<T>(offset, length)?;
+        Ok(unsafe { std::slice::from_raw_parts_mut(pointer, length) })
+    }
+
+    /// Reads a `T` at `offset` with a volatile read, which the compiler may not elide or merge with other accesses.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory. Must be aligned for `T`.
+    ///
+    /// returns: An error if the value does not fit inside the shared memory, or if it is misaligned.
+    pub fn read_volatile<T: Pod>(&self, offset: usize) -> Result<T, DeviceError> {
+        let pointer = self.typed_pointer::<T>(offset, 1)?;
+        Ok(unsafe { std::ptr::read_volatile(pointer) })
+    }
+
+    /// Writes `value` at `offset` with a volatile write, which the compiler may not elide or merge with other accesses.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory. Must be aligned for `T`.
+    /// * `value`: The value to write.
+    ///
+    /// returns: An error if the value does not fit inside the shared memory, or if it is misaligned.
+    pub fn write_volatile<T: Pod>(&mut self, offset: usize, value: T) -> Result<(), DeviceError> {
+        let pointer = self.typed_pointer::<T>(offset, 1)?;
+        unsafe { std::ptr::write_volatile(pointer, value) };
+        Ok(())
+    }
+
+    /// Accesses the 4 bytes at `offset` as an atomic. Atomics are the way to synchronize with the other side.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: Offset in bytes from the start of the shared memory. Must be aligned to 4 bytes.
+    ///
+    /// returns: An error if the atomic does not fit inside the shared memory, or if it is misaligned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-atomic", 4096).unwrap();
+    /// let mut producer = shm.map(1).unwrap();
+    /// let consumer = shm.map(1).unwrap();
+    ///
+    /// std::thread::scope(|scope| {
+    ///     scope.spawn(|| {
+    ///         producer.write_volatile::<u64>(64, 0xC0FFEE).unwrap();
+    ///         // Orders the payload before the flag.
+    ///         producer.fence(Ordering::Release);
+    ///         producer.atomic_u32(0).unwrap().store(1, Ordering::Relaxed);
+    ///     });
+    ///
+    ///     let ready = consumer.atomic_u32(0).unwrap();
+    ///     while ready.load(Ordering::Relaxed) == 0 {
+    ///         std::hint::spin_loop();
+    ///     }
+    ///     consumer.fence(Ordering::Acquire);
+    ///     assert_eq!(consumer.read_volatile::<u64>(64).unwrap(), 0xC0FFEE);
+    /// });
+    /// ```
+    pub fn atomic_u32(&self, offset: usize) -> Result<&AtomicU32, DeviceError> {
+        let pointer = self.typed_pointer::<u32>(offset, 1)?;
+        Ok(unsafe { AtomicU32::from_ptr(pointer) })
+    }
+
+    /// Accesses the 8 bytes at `offset` as an atomic. See `atomic_u32`.
+    pub fn atomic_u64(&self, offset: usize) -> Result<&AtomicU64, DeviceError> {
+        let pointer = self.typed_pointer::<u64>(offset, 1)?;
+        Ok(unsafe { AtomicU64::from_ptr(pointer) })
+    }
+
+    /// Issues a memory fence. Orders volatile accesses and copies relative to atomics used as flags, e.g. a
+    /// `Release` fence between writing a payload and setting its flag, and an `Acquire` fence after observing it.
+    pub fn fence(&self, ordering: Ordering) {
+        fence(ordering);
+    }
+
+    fn typed_pointer<T: Pod>(&self, offset: usize, count: usize) -> Result<*mut T, DeviceError> {
+        let length = std::mem::size_of::<T>().checked_mul(count).ok_or(DeviceError::OutOfBounds {
+            offset,
+            length: usize::MAX,
+            size: self.length,
+        })?;
+        self.check_bounds(offset, length)?;
+        let pointer = unsafe { self.memory.add(offset) };
+        if pointer.align_offset(std::mem::align_of::<T>()) != 0 {
+            return Err(DeviceError::Misaligned {
+                offset,
+                alignment: std::mem::align_of::<T>(),
+            });
+        }
+        Ok(pointer as *mut T)
+    }
+
+    /// Splits a copy across all worker threads and blocks until every thread is done.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads and `dst` must be valid for writes of `length` bytes. The regions may not overlap.
+    unsafe fn parallel_copy(&self, src: *const u8, dst: *mut u8, length: usize) {
+        self.pool.copy_raw(src, dst, length);
+    }
+}
+
+pub(crate) fn check_bounds(offset: usize, length: usize, size: usize) -> Result<(), DeviceError> {
+    match offset.checked_add(length) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(DeviceError::OutOfBounds { offset, length, size }),
+    }
+}
+
+/// Submits non-blocking copies between buffers and the shared memory of an `IvshmemDevice`.
+/// Created by `IvshmemDevice::copy_scope`, which waits for all submitted copies before returning.
+///
+/// Buffers must outlive the scope. A buffer that is dropped while its copy may still be running is rejected:
+///
+/// ```compile_fail,E0597
+/// fn render(device: &mut ivshmemmap::device::IvshmemDevice) {
+///     device.copy_scope(|scope| {
+///         let frame = vec![0u8; 16];
+///         scope.write_async(0, &frame).unwrap();
+///     });
+/// }
+/// ```
+pub struct CopyScope<'scope, 'env: 'scope> {
+    memory: *mut u8,
+    length: usize,
+    pool: Arc<CopyPool>,
+    pending: Mutex<Vec<CopyHandle>>,
+    _scope: PhantomData<&'scope mut &'scope ()>,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+// The raw pointer is derived from the exclusively borrowed device.
+unsafe impl Send for CopyScope<'_, '_> {}
+unsafe impl Sync for CopyScope<'_, '_> {}
+
+impl<'scope> CopyScope<'scope, '_> {
+    /// Starts overwriting `buf.len()` bytes of the shared memory at `offset` and returns immediately.
+    ///
+    /// returns: A handle that completes once the copy is done, or an error if the range is out of bounds.
+    pub fn write_async(&'scope self, offset: usize, buf: &'scope [u8]) -> Result<CopyHandle, DeviceError> {
+        check_bounds(offset, buf.len(), self.length)?;
+        let handle = unsafe { self.pool.submit(buf.as_ptr(), self.memory.byte_add(offset), buf.len()) };
+        self.pending.lock().unwrap().push(handle.share());
+        Ok(handle)
+    }
+
+    /// Starts copying `buf.len()` bytes of the shared memory at `offset` into `buf` and returns immediately.
+    ///
+    /// returns: A handle that completes once the copy is done, or an error if the range is out of bounds.
+    pub fn read_async(&'scope self, offset: usize, buf: &'scope mut [u8]) -> Result<CopyHandle, DeviceError> {
+        check_bounds(offset, buf.len(), self.length)?;
+        let handle = unsafe { self.pool.submit(self.memory.byte_add(offset), buf.as_mut_ptr(), buf.len()) };
+        self.pending.lock().unwrap().push(handle.share());
+        Ok(handle)
+    }
+}
+
+impl CopyScope<'_, '_> {
+    /// Waits for all submitted copies, so no worker touches a buffer after its borrow ends.
+    fn wait_all(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        for handle in pending {
+            handle.wait();
+        }
+    }
+}
+
+impl Debug for CopyScope<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CopyScope{{ size: {:?} }}", self.length)
+    }
 }
 
 impl Deref for IvshmemDevice {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        self.memory
+        unsafe { std::slice::from_raw_parts(self.memory, self.length) }
     }
 }
 
@@ -151,7 +504,7 @@ impl DerefMut for IvshmemDevice {
     /// Notice:
     /// If for some reason the underlying pointer is replaced with another, the shared memory will no longer work.
     fn deref_mut(&mut self) -> &mut Self::Target {
-        self.memory
+        unsafe { std::slice::from_raw_parts_mut(self.memory, self.length) }
     }
 }
 
@@ -1,14 +1,450 @@
+use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
+/// Errors of opening an ivshmem device, independent of the platform.
+///
+/// Failures that have a common meaning on every platform get their own variant. Everything else keeps the error of
+/// the platform, which carries the failing system call and its context.
+///
+/// # Examples
+///
+/// ```
+/// use ivshmemmap::error::{Error, UnixError, WindowsError};
+///
+/// let error = Error::from(WindowsError::AlreadyAttached("\\\\?\\pci#ven_1af4&dev_1110".to_string()));
+/// assert!(matches!(error, Error::AlreadyAttached(_)));
+///
+/// let error = Error::from(WindowsError::IoctlFailed {
+///     device: "\\\\?\\pci#ven_1af4&dev_1110".to_string(),
+///     request: "IOCTL_IVSHMEM_REQUEST_SIZE",
+///     code: 31,
+/// });
+/// assert_eq!(error.to_string(), "IOCTL_IVSHMEM_REQUEST_SIZE on \\\\?\\pci#ven_1af4&dev_1110 failed with error code 0x1f");
+///
+/// let error = Error::from(UnixError::MapFailed {
+///     path: Some("/dev/shm/ivshmem".into()),
+///     size: 4096,
+///     protection: 0x3,
+///     flags: 0x1,
+///     source: std::io::ErrorKind::OutOfMemory.into(),
+/// });
+/// assert!(matches!(error, Error::MapFailed { protection: Some(0x3), flags: Some(0x1), .. }));
+/// assert!(error.to_string().starts_with("Failed to map 4096 bytes of \"/dev/shm/ivshmem\" with protection 0x3 and flags 0x1"));
+///
+/// let error = Error::from(UnixError::SizeMismatch { path: "/dev/shm/ivshmem".into(), expected: 4096, actual: 8192 });
+/// assert!(matches!(&error, Error::SizeMismatch { device, .. } if device == "\"/dev/shm/ivshmem\""));
+/// ```
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No ivshmem device found{}", match device { Some(device) => format!(" at {device}"), None => String::new() })]
+    DeviceNotFound {
+        device: Option<String>,
+        /// The failed attempt to open the device, if the platform reports one.
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+    #[error("The ivshmem device {0} is already attached by another process")]
+    AlreadyAttached(String),
+    #[error("Shared memory of {device} has an unexpected size. Expected {expected} bytes but found {actual} bytes")]
+    SizeMismatch { device: String, expected: u64, actual: u64 },
+    #[error("{request} on {device} failed with error code {code:#x}")]
+    IoctlFailed { device: String, request: &'static str, code: u32 },
+    #[error("Failed to map {size} bytes of {device}{}: {source}", describe_mapping(*.protection, *.flags))]
+    MapFailed {
+        device: String,
+        size: u64,
+        /// The protection and flags passed to `mmap`, on platforms that have them.
+        protection: Option<i32>,
+        flags: Option<i32>,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Unknown peer: {0}")]
+    UnknownPeer(u16),
+    #[error("Invalid interrupt vector: {0}")]
+    InvalidVector(usize),
+    #[error("The backend does not support {0}")]
+    Unsupported(&'static str),
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+    #[error(transparent)]
+    Unix(UnixError),
+    #[error(transparent)]
+    Windows(WindowsError),
+}
+
+/// Describes the file behind a mapping, which is unknown for descriptors received from an ivshmem-server.
+fn describe(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!("{path:?}"),
+        None => "a received file descriptor".to_string(),
+    }
+}
+
+/// Describes the arguments of a failed `mmap`, if the platform has them.
+fn describe_mapping(protection: Option<i32>, flags: Option<i32>) -> String {
+    match (protection, flags) {
+        (Some(protection), Some(flags)) => format!(" with protection {protection:#x} and flags {flags:#x}"),
+        _ => String::new(),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum UnixError {
-    #[error("Failed to open mapped memory. Potential permission error.")]
-    OpenFailed,
-    #[error("Memory map failed")]
-    MapFailed
+    #[error("{call} of {path:?} with flags {flags:#o} and mode {mode:#o} failed: {source}")]
+    OpenFailed {
+        /// `open` or `shm_open`.
+        call: &'static str,
+        path: PathBuf,
+        flags: i32,
+        mode: u32,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Path {0:?} contains an interior NUL byte")]
+    InvalidPath(PathBuf),
+    #[error("ftruncate of {path:?} to {size} bytes failed: {source}")]
+    ResizeFailed {
+        path: PathBuf,
+        size: usize,
+        #[source]
+        source: io::Error,
+    },
+    #[error("fstat of {path:?} failed: {source}")]
+    StatFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("lseek of {} failed: {source}", describe(.path))]
+    SeekFailed {
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{} is empty and cannot be mapped", describe(.path))]
+    EmptyFile { path: Option<PathBuf> },
+    #[error("mmap of {size} bytes of {} with protection {protection:#x} and flags {flags:#x} failed: {source}", describe(.path))]
+    MapFailed {
+        path: Option<PathBuf>,
+        size: usize,
+        protection: i32,
+        flags: i32,
+        #[source]
+        source: io::Error,
+    },
+    #[error("munmap of {size} bytes failed: {source}")]
+    UnmapFailed {
+        size: usize,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Shared memory {path:?} has an unexpected size. Expected {expected} bytes but found {actual} bytes")]
+    SizeMismatch { path: PathBuf, expected: usize, actual: usize },
+    #[error("Shared memory {path:?} is too small. Requires at least {minimum} bytes but found {actual} bytes")]
+    TooSmall { path: PathBuf, minimum: usize, actual: usize },
+    #[error("{call} of {path:?} failed: {source}")]
+    UnlinkFailed {
+        /// `unlink` or `shm_unlink`.
+        call: &'static str,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to read {path:?}: {source}")]
+    ReadFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to write {path:?}: {source}")]
+    WriteFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Unexpected content in {path:?}: {content:?}")]
+    InvalidSysfsEntry { path: PathBuf, content: String },
+    #[error("{request} failed: {source}")]
+    IoctlFailed {
+        request: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Interrupts of PCI device {0} are unavailable. Bind it to uio_pci_generic, uio_ivshmem or vfio-pci")]
+    InterruptsUnavailable(String),
+    #[error("Unable to connect to the ivshmem-server socket {path:?}: {source}")]
+    ConnectFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Unable to listen on the ivshmem-server socket {path:?}: {source}")]
+    ListenFailed {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Unable to accept a peer on the ivshmem-server socket: {source}")]
+    AcceptFailed {
+        #[source]
+        source: io::Error,
+    },
+    #[error("Connection to the ivshmem-server was lost: {source}")]
+    Disconnected {
+        #[source]
+        source: io::Error,
+    },
+    #[error("poll failed: {source}")]
+    PollFailed {
+        #[source]
+        source: io::Error,
+    },
+    #[error("Unsupported ivshmem protocol version: {0}")]
+    UnsupportedProtocol(i64),
+    #[error("Invalid message from ivshmem-server: {0}")]
+    ProtocolViolation(&'static str),
+    #[error("Unknown peer: {0}")]
+    UnknownPeer(u16),
+    #[error("Invalid interrupt vector: {0}")]
+    InvalidVector(usize),
+    #[error("eventfd failed: {source}")]
+    EventFdFailed {
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to signal or read the doorbell of vector {vector}: {source}")]
+    DoorbellFailed {
+        vector: usize,
+        #[source]
+        source: io::Error,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum DeviceError {
+    #[error("Access of {length} bytes at offset {offset} is out of bounds for a memory buffer of {size} bytes")]
+    OutOfBounds { offset: usize, length: usize, size: usize },
+    #[error("Offset {offset} is not aligned to {alignment} bytes")]
+    Misaligned { offset: usize, alignment: usize },
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RingError {
+    #[error("Region of {0} bytes is too small to hold a ring buffer")]
+    RegionTooSmall(usize),
+    #[error("Region is not aligned to 8 bytes")]
+    Misaligned,
+    #[error("Region does not contain a ring buffer")]
+    NotFormatted,
+    #[error("Unsupported ring buffer version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Ring buffer header or framing is corrupted")]
+    Corrupted,
+    #[error("Ring buffer is full")]
+    Full,
+    #[error("Message of {length} bytes exceeds the maximum message size of {capacity} bytes")]
+    MessageTooLarge { length: usize, capacity: usize },
+    #[error("Buffer is too small. The message requires {needed} bytes")]
+    BufferTooSmall { needed: usize },
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum QueueError {
+    #[error("Region of {0} bytes is too small to hold a queue")]
+    RegionTooSmall(usize),
+    #[error("Region is not aligned to 8 bytes")]
+    Misaligned,
+    #[error("Region does not contain a queue")]
+    NotFormatted,
+    #[error("Unsupported queue version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Queue header or slot is corrupted")]
+    Corrupted,
+    #[error("Queue is full")]
+    Full,
+    #[error("Message of {length} bytes exceeds the slot size of {slot_size} bytes")]
+    MessageTooLarge { length: usize, slot_size: usize },
+    #[error("Buffer is too small. The message requires {needed} bytes")]
+    BufferTooSmall { needed: usize },
+    #[error("Message was not published within the abandon timeout and has been skipped by the consumers")]
+    Abandoned,
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Frame exchange requires {required} bytes, but the shared memory is {actual} bytes")]
+    RegionTooSmall { required: usize, actual: usize },
+    #[error("Invalid amount of frame slots: {0}. Requires at least 2")]
+    InvalidSlotCount(usize),
+    #[error("Shared memory does not contain a frame exchange")]
+    NotFormatted,
+    #[error("Unsupported frame exchange version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Frame exchange header is corrupted")]
+    Corrupted,
+    #[error("Every frame slot is in use by readers")]
+    Busy,
+    #[error("Frame of {length} bytes exceeds the slot size of {slot_size} bytes")]
+    FrameTooLarge { length: usize, slot_size: usize },
+    #[error("Buffer is too small. The frame requires {needed} bytes")]
+    BufferTooSmall { needed: usize },
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+#[derive(Error, Debug)]
+pub enum LgmpError {
+    #[error("Shared memory does not contain an LGMP session")]
+    NotFormatted,
+    #[error("Unsupported LGMP protocol version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("LGMP header or message is corrupted")]
+    Corrupted,
+    #[error("The LGMP host restarted with a new session")]
+    SessionChanged,
+    #[error("No LGMP queue with id {0}")]
+    NoSuchQueue(u32),
+    #[error("Every subscriber slot of the queue is taken")]
+    NoSubscriberSlots,
+    #[error("Not subscribed to the queue")]
+    NotSubscribed,
+    #[error("The host timed out this subscriber. Unsubscribe and subscribe again")]
+    TimedOut,
+    #[error("No message to acknowledge")]
+    NoMessage,
+    #[error("Timed out waiting for the lock of the queue")]
+    LockTimedOut,
+    #[error("Queue is full")]
+    QueueFull,
+    #[error("Too many queues")]
+    TooManyQueues,
+    #[error("Invalid amount of queue messages: {0}. Requires at least 2")]
+    InvalidMessageCount(u32),
+    #[error("Not enough shared memory left")]
+    OutOfMemory,
+    #[error("Access is out of bounds of the buffer")]
+    OutOfBounds,
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+#[derive(Error, Debug)]
+pub enum KvmfrError {
+    #[error("LGMP user data does not contain a KVMFR header")]
+    NotKvmfr,
+    #[error("Unsupported KVMFR version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("KVMFR message is truncated")]
+    Truncated,
+    #[error("KVMFR message is corrupted")]
+    Corrupted,
+    #[error("Data of {length} bytes exceeds the buffer capacity of {capacity} bytes")]
+    TooLarge { length: usize, capacity: usize },
+    #[error(transparent)]
+    Lgmp(#[from] LgmpError),
 }
 
+#[derive(Error, Debug)]
+pub enum RegionError {
+    #[error("A region table with {capacity} entries does not fit in {size} bytes")]
+    TableTooLarge { capacity: usize, size: usize },
+    #[error("Shared memory does not contain a region table")]
+    NotFormatted,
+    #[error("Unsupported region table version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("Region table is corrupted")]
+    Corrupted,
+    #[error("Invalid region name: {0:?}")]
+    InvalidName(String),
+    #[error("Region {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("Region {0:?} does not exist")]
+    NotFound(String),
+    #[error("Region {0:?} was requested more than once")]
+    Duplicate(String),
+    #[error("Region table is full")]
+    TableFull,
+    #[error("No free space for a region of {length} bytes")]
+    OutOfSpace { length: usize },
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+}
+
+/// Failures of the IVSHMEM driver of the virtio-win project.
 #[derive(Error, Debug)]
 pub enum WindowsError {
+    #[error("No IVSHMEM device found")]
+    DeviceNotFound,
+    #[error("{call} failed with error code {code:#x}")]
+    SetupApiFailed { call: &'static str, code: u32 },
+    #[error("Unable to open IVSHMEM device {device}: error code {code:#x}")]
+    OpenFailed { device: String, code: u32 },
+    #[error("IVSHMEM device {0} is already attached by another process")]
+    AlreadyAttached(String),
+    #[error("{request} on IVSHMEM device {device} failed with error code {code:#x}")]
+    IoctlFailed { device: String, request: &'static str, code: u32 },
+    #[error("IVSHMEM device {0} has no shared memory")]
+    EmptyDevice(String),
+    #[error("Failed to map {size} bytes of IVSHMEM device {device}: error code {code:#x}")]
+    MapFailed { device: String, size: u64, code: u32 },
+    #[error("IVSHMEM device {device} mapped {actual} bytes, but reported a size of {expected} bytes")]
+    SizeMismatch { device: String, expected: u64, actual: u64 },
+}
 
-}
\ No newline at end of file
+impl From<UnixError> for Error {
+    fn from(error: UnixError) -> Self {
+        match error {
+            UnixError::OpenFailed { ref path, ref source, .. } if source.kind() == io::ErrorKind::NotFound => {
+                Error::DeviceNotFound {
+                    device: Some(path.display().to_string()),
+                    source: Some(Box::new(error)),
+                }
+            }
+            UnixError::SizeMismatch { path, expected, actual } => Error::SizeMismatch {
+                device: format!("{path:?}"),
+                expected: expected as u64,
+                actual: actual as u64,
+            },
+            UnixError::MapFailed {
+                path,
+                size,
+                protection,
+                flags,
+                source,
+            } => Error::MapFailed {
+                device: describe(&path),
+                size: size as u64,
+                protection: Some(protection),
+                flags: Some(flags),
+                source,
+            },
+            UnixError::UnknownPeer(peer) => Error::UnknownPeer(peer),
+            UnixError::InvalidVector(vector) => Error::InvalidVector(vector),
+            error => Error::Unix(error),
+        }
+    }
+}
+
+impl From<WindowsError> for Error {
+    fn from(error: WindowsError) -> Self {
+        match error {
+            WindowsError::DeviceNotFound => Error::DeviceNotFound {
+                device: None,
+                source: None,
+            },
+            WindowsError::AlreadyAttached(device) => Error::AlreadyAttached(device),
+            WindowsError::SizeMismatch { device, expected, actual } => Error::SizeMismatch { device, expected, actual },
+            WindowsError::IoctlFailed { device, request, code } => Error::IoctlFailed { device, request, code },
+            WindowsError::MapFailed { device, size, code } => Error::MapFailed {
+                device,
+                size,
+                protection: None,
+                flags: None,
+                source: io::Error::from_raw_os_error(code as i32),
+            },
+            error => Error::Windows(error),
+        }
+    }
+}
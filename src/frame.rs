@@ -0,0 +1,591 @@
+//! Exchange of frames, such as video frames, between a single writer and any amount of readers.
+//!
+//! The shared memory is partitioned into a control header followed by `slot_count` page aligned frame slots.
+//! The writer fills a slot that is neither the latest frame nor being read, using the parallel copy engine of its
+//! `IvshmemDevice`, and then publishes it. Readers acquire the latest published frame. A slot is never written while
+//! a reader holds it, so frames are never torn. Use two slots for double buffering and three for triple buffering.
+//!
+//! | Offset              | Contents                                                                            |
+//! |---------------------|-------------------------------------------------------------------------------------|
+//! | 0                   | magic (`u64`), version (`u32`), slot count (`u32`), slot size (`u64`), data offset (`u64`) |
+//! | 64                  | latest frame (`AtomicU64`): sequence number << 16 \| slot index                    |
+//! | 128 + 64 * slot     | slot control (`AtomicU64`): writing flag \| reader count, followed by `FrameInfo`    |
+//! | data offset         | the frame slots, each `slot size` bytes                                             |
+//!
+//! # Examples
+//!
+//! ```
+//! use ivshmemmap::frame::{FrameInfo, FrameReader, FrameWriter};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-frame", 4 << 20).unwrap();
+//! let mut guest = shm.map(4).unwrap();
+//! let mut host = shm.map(4).unwrap();
+//!
+//! const WIDTH: u32 = 320;
+//! const HEIGHT: u32 = 240;
+//! let mut writer = FrameWriter::new(&mut guest, 3, (WIDTH * HEIGHT * 4) as usize).unwrap();
+//! let mut reader = FrameReader::attach(&mut host).unwrap();
+//!
+//! std::thread::scope(|scope| {
+//!     scope.spawn(move || {
+//!         for sequence in 1..=200u64 {
+//!             let pixels = vec![sequence as u8; (WIDTH * HEIGHT * 4) as usize];
+//!             let mut slot = writer.acquire().unwrap();
+//!             slot.write(&pixels).unwrap();
+//!             slot.publish(FrameInfo {
+//!                 width: WIDTH,
+//!                 height: HEIGHT,
+//!                 stride: WIDTH * 4,
+//!                 format: u32::from_le_bytes(*b"BGRA"),
+//!                 timestamp: sequence,
+//!             });
+//!         }
+//!     });
+//!
+//!     let mut pixels = vec![0u8; (WIDTH * HEIGHT * 4) as usize];
+//!     let mut last = 0;
+//!     while last < 200 {
+//!         if let Some(frame) = reader.acquire_new().unwrap() {
+//!             assert!(frame.sequence() > last);
+//!             last = frame.sequence();
+//!             frame.read_into(&mut pixels).unwrap();
+//!             assert!(pixels.iter().all(|&byte| byte == last as u8), "Torn frame");
+//!             assert_eq!(frame.info().timestamp, last);
+//!         }
+//!     }
+//! });
+//! ```
+
+use crate::device::IvshmemDevice;
+use crate::error::FrameError;
+use crate::header;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// "IVSHFRAM" in ASCII.
+pub const FRAME_MAGIC: u64 = u64::from_le_bytes(*b"IVSHFRAM");
+pub const FRAME_VERSION: u32 = 1;
+
+const PAGE_SIZE: usize = 4096;
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 8;
+const SLOT_COUNT_OFFSET: usize = 12;
+const SLOT_SIZE_OFFSET: usize = 16;
+const DATA_OFFSET_OFFSET: usize = 24;
+const LATEST_OFFSET: usize = 64;
+const SLOTS_OFFSET: usize = 128;
+const SLOT_CONTROL_SIZE: usize = 64;
+/// Set in the slot control while the writer fills the slot. The lower bits count the readers of the slot.
+const WRITING: u64 = 1 << 63;
+/// The latest frame before anything has been published.
+const NO_FRAME: u64 = 0;
+
+/// Describes the contents of a frame. The meaning of `format` is agreed upon by the writer and readers,
+/// for example a FourCC code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct FrameInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Amount of bytes between the start of two consecutive rows.
+    pub stride: u32,
+    pub format: u32,
+    pub timestamp: u64,
+}
+
+/// Pointers into the control header of the frame exchange.
+#[derive(Copy, Clone)]
+struct Header {
+    base: *mut u8,
+    slot_count: usize,
+    slot_size: usize,
+    data_offset: usize,
+}
+
+impl Header {
+    fn latest(&self) -> &AtomicU64 {
+        unsafe { &*(self.base.add(LATEST_OFFSET) as *const AtomicU64) }
+    }
+
+    fn control(&self, slot: usize) -> &AtomicU64 {
+        unsafe { &*(self.base.add(SLOTS_OFFSET + slot * SLOT_CONTROL_SIZE) as *const AtomicU64) }
+    }
+
+    /// Metadata of a slot. Only accessed by the writer while it holds the slot, or by readers of the slot.
+    fn field(&self, slot: usize, field: usize) -> *mut u64 {
+        unsafe { self.base.add(SLOTS_OFFSET + slot * SLOT_CONTROL_SIZE + 8 + field * 8) as *mut u64 }
+    }
+
+    unsafe fn read_metadata(&self, slot: usize) -> (u64, usize, FrameInfo) {
+        let sequence = std::ptr::read_volatile(self.field(slot, 0));
+        let length = std::ptr::read_volatile(self.field(slot, 1)) as usize;
+        let dimensions = std::ptr::read_volatile(self.field(slot, 2));
+        let layout = std::ptr::read_volatile(self.field(slot, 3));
+        let info = FrameInfo {
+            width: dimensions as u32,
+            height: (dimensions >> 32) as u32,
+            stride: layout as u32,
+            format: (layout >> 32) as u32,
+            timestamp: std::ptr::read_volatile(self.field(slot, 4)),
+        };
+        (sequence, length, info)
+    }
+
+    unsafe fn write_metadata(&self, slot: usize, sequence: u64, length: usize, info: &FrameInfo) {
+        std::ptr::write_volatile(self.field(slot, 0), sequence);
+        std::ptr::write_volatile(self.field(slot, 1), length as u64);
+        std::ptr::write_volatile(self.field(slot, 2), info.width as u64 | (info.height as u64) << 32);
+        std::ptr::write_volatile(self.field(slot, 3), info.stride as u64 | (info.format as u64) << 32);
+        std::ptr::write_volatile(self.field(slot, 4), info.timestamp);
+    }
+
+    fn slot_offset(&self, slot: usize) -> usize {
+        self.data_offset + slot * self.slot_size
+    }
+}
+
+fn decode_latest(latest: u64) -> (u64, usize) {
+    (latest >> 16, (latest & 0xFFFF) as usize)
+}
+
+/// Publishes frames into shared memory. There may only be a single writer per frame exchange.
+pub struct FrameWriter<'a> {
+    device: &'a mut IvshmemDevice,
+    header: Header,
+    sequence: u64,
+}
+
+// The header is only accessed atomically, or through slots this writer holds exclusively.
+unsafe impl Send for FrameWriter<'_> {}
+
+impl Debug for FrameWriter<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FrameWriter{{ slots: {:?} slot_size: {:?} sequence: {:?} }}",
+            self.header.slot_count, self.header.slot_size, self.sequence
+        )
+    }
+}
+
+impl<'a> FrameWriter<'a> {
+    /// Formats the shared memory of `device` as a frame exchange, discarding any previous content.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: The shared memory to partition.
+    /// * `slot_count`: Amount of frame slots. 2 for double buffering, 3 for triple buffering.
+    /// * `slot_size`: Maximum size of a frame in bytes. Rounded up to whole pages.
+    pub fn new(device: &'a mut IvshmemDevice, slot_count: usize, slot_size: usize) -> Result<Self, FrameError> {
+        if !(2..=u16::MAX as usize).contains(&slot_count) {
+            return Err(FrameError::InvalidSlotCount(slot_count));
+        }
+        let slot_size = slot_size.next_multiple_of(PAGE_SIZE);
+        let data_offset = (SLOTS_OFFSET + slot_count * SLOT_CONTROL_SIZE).next_multiple_of(PAGE_SIZE);
+        let required = slot_count
+            .checked_mul(slot_size)
+            .and_then(|slots| slots.checked_add(data_offset))
+            .unwrap_or(usize::MAX);
+        if required > device.len() {
+            return Err(FrameError::RegionTooSmall {
+                required,
+                actual: device.len(),
+            });
+        }
+
+        let base = device.as_mut_ptr();
+        let header = Header {
+            base,
+            slot_count,
+            slot_size,
+            data_offset,
+        };
+        unsafe {
+            std::ptr::write_bytes(base, 0, data_offset);
+            std::ptr::write_volatile(base.add(VERSION_OFFSET) as *mut u32, FRAME_VERSION);
+            std::ptr::write_volatile(base.add(SLOT_COUNT_OFFSET) as *mut u32, slot_count as u32);
+            std::ptr::write_volatile(base.add(SLOT_SIZE_OFFSET) as *mut u64, slot_size as u64);
+            std::ptr::write_volatile(base.add(DATA_OFFSET_OFFSET) as *mut u64, data_offset as u64);
+            header::publish(base.add(MAGIC_OFFSET), FRAME_MAGIC);
+        }
+        Ok(Self {
+            device,
+            header,
+            sequence: 0,
+        })
+    }
+
+    /// Maximum size of a frame in bytes.
+    pub fn slot_size(&self) -> usize {
+        self.header.slot_size
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.header.slot_count
+    }
+
+    /// Acquires a slot that is neither the latest frame nor being read.
+    ///
+    /// returns: `FrameError::Busy` if readers hold every other slot.
+    pub fn acquire(&mut self) -> Result<FrameSlot<'_, 'a>, FrameError> {
+        let latest = self.header.latest().load(Ordering::Relaxed);
+        let (_, latest_slot) = decode_latest(latest);
+        // Starting after the latest frame rotates through the slots, so readers of older frames are avoided.
+        let slot = (1..self.header.slot_count)
+            .map(|distance| (latest_slot + distance) % self.header.slot_count)
+            .chain((latest == NO_FRAME).then_some(latest_slot))
+            .find(|&slot| {
+                self.header
+                    .control(slot)
+                    .compare_exchange(0, WRITING, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .ok_or(FrameError::Busy)?;
+        Ok(FrameSlot {
+            writer: self,
+            slot,
+            length: 0,
+            published: false,
+        })
+    }
+}
+
+/// A slot held by the writer. Dropping it without publishing returns the slot without changing the latest frame.
+pub struct FrameSlot<'w, 'a> {
+    writer: &'w mut FrameWriter<'a>,
+    slot: usize,
+    length: usize,
+    published: bool,
+}
+
+impl Debug for FrameSlot<'_, '_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FrameSlot{{ slot: {:?} length: {:?} }}", self.slot, self.length)
+    }
+}
+
+impl FrameSlot<'_, '_> {
+    /// Copies `frame` into the slot using the worker threads of the device. Replaces any previous content.
+    pub fn write(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+        self.length = 0;
+        self.write_at(0, frame)
+    }
+
+    /// Copies `buf` into the slot at `offset`, using the worker threads of the device.
+    /// The frame length grows to include the written bytes.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), FrameError> {
+        let end = offset.saturating_add(buf.len());
+        if end > self.writer.header.slot_size {
+            return Err(FrameError::FrameTooLarge {
+                length: end,
+                slot_size: self.writer.header.slot_size,
+            });
+        }
+        let slot_offset = self.writer.header.slot_offset(self.slot);
+        self.writer.device.write_at(slot_offset + offset, buf)?;
+        self.length = self.length.max(end);
+        Ok(())
+    }
+
+    /// The slot memory, for producing a frame in place.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let slot_offset = self.writer.header.slot_offset(self.slot);
+        &mut self.writer.device[slot_offset..slot_offset + self.writer.header.slot_size]
+    }
+
+    /// Sets the frame length. Useful after filling the frame through `as_mut_slice`.
+    pub fn set_len(&mut self, length: usize) -> Result<(), FrameError> {
+        if length > self.writer.header.slot_size {
+            return Err(FrameError::FrameTooLarge {
+                length,
+                slot_size: self.writer.header.slot_size,
+            });
+        }
+        self.length = length;
+        Ok(())
+    }
+
+    /// Makes the slot the latest frame.
+    ///
+    /// returns: The sequence number of the frame, starting at 1.
+    pub fn publish(mut self, info: FrameInfo) -> u64 {
+        let header = self.writer.header;
+        self.writer.sequence += 1;
+        let sequence = self.writer.sequence;
+        unsafe {
+            header.write_metadata(self.slot, sequence, self.length, &info);
+        }
+        header.control(self.slot).store(0, Ordering::Release);
+        header.latest().store(sequence << 16 | self.slot as u64, Ordering::Release);
+        self.published = true;
+        sequence
+    }
+}
+
+impl Drop for FrameSlot<'_, '_> {
+    fn drop(&mut self) {
+        if !self.published {
+            self.writer.header.control(self.slot).store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Acquires the latest frame published by a `FrameWriter`, possibly in another process or VM.
+pub struct FrameReader<'a> {
+    device: &'a mut IvshmemDevice,
+    header: Header,
+    last_sequence: u64,
+}
+
+// The header is only accessed atomically, or through slots this reader holds.
+unsafe impl Send for FrameReader<'_> {}
+
+impl Debug for FrameReader<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "FrameReader{{ slots: {:?} slot_size: {:?} last_sequence: {:?} }}",
+            self.header.slot_count, self.header.slot_size, self.last_sequence
+        )
+    }
+}
+
+impl<'a> FrameReader<'a> {
+    /// Attaches to a frame exchange formatted by `FrameWriter::new`.
+    pub fn attach(device: &'a mut IvshmemDevice) -> Result<Self, FrameError> {
+        if device.len() < SLOTS_OFFSET {
+            return Err(FrameError::RegionTooSmall {
+                required: SLOTS_OFFSET,
+                actual: device.len(),
+            });
+        }
+        let base = device.as_mut_ptr();
+        let header = unsafe {
+            if !header::is_published(base.add(MAGIC_OFFSET), FRAME_MAGIC) {
+                return Err(FrameError::NotFormatted);
+            }
+            let version = std::ptr::read_volatile(base.add(VERSION_OFFSET) as *const u32);
+            if version != FRAME_VERSION {
+                return Err(FrameError::UnsupportedVersion(version));
+            }
+            Header {
+                base,
+                slot_count: std::ptr::read_volatile(base.add(SLOT_COUNT_OFFSET) as *const u32) as usize,
+                slot_size: std::ptr::read_volatile(base.add(SLOT_SIZE_OFFSET) as *const u64) as usize,
+                data_offset: std::ptr::read_volatile(base.add(DATA_OFFSET_OFFSET) as *const u64) as usize,
+            }
+        };
+        let fits = header
+            .slot_count
+            .checked_mul(header.slot_size)
+            .and_then(|slots| slots.checked_add(header.data_offset))
+            .is_some_and(|required| required <= device.len());
+        if !(2..=u16::MAX as usize).contains(&header.slot_count)
+            || header.data_offset < SLOTS_OFFSET + header.slot_count * SLOT_CONTROL_SIZE
+            || !fits
+        {
+            return Err(FrameError::Corrupted);
+        }
+        Ok(Self {
+            device,
+            header,
+            last_sequence: 0,
+        })
+    }
+
+    /// Sequence number of the latest published frame. 0 if nothing has been published yet.
+    pub fn latest_sequence(&self) -> u64 {
+        decode_latest(self.header.latest().load(Ordering::Acquire)).0
+    }
+
+    /// Acquires the latest frame, even if this reader has seen it before.
+    ///
+    /// returns: `None` if nothing has been published yet.
+    pub fn acquire_latest(&mut self) -> Result<Option<Frame<'_, 'a>>, FrameError> {
+        self.acquire(0)
+    }
+
+    /// Acquires the latest frame if it is newer than the last frame acquired by this reader.
+    pub fn acquire_new(&mut self) -> Result<Option<Frame<'_, 'a>>, FrameError> {
+        self.acquire(self.last_sequence)
+    }
+
+    fn acquire(&mut self, seen: u64) -> Result<Option<Frame<'_, 'a>>, FrameError> {
+        let header = self.header;
+        let (slot, sequence, length, info) = loop {
+            let (sequence, slot) = decode_latest(header.latest().load(Ordering::Acquire));
+            if sequence == 0 || sequence <= seen {
+                return Ok(None);
+            }
+            if slot >= header.slot_count {
+                return Err(FrameError::Corrupted);
+            }
+
+            let control = header.control(slot);
+            let mut current = control.load(Ordering::Relaxed);
+            let acquired = loop {
+                if current & WRITING != 0 {
+                    break false;
+                }
+                match control.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => break true,
+                    Err(actual) => current = actual,
+                }
+            };
+            if !acquired {
+                // The writer reused the slot, so a newer frame has been published in the meantime.
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let (slot_sequence, length, info) = unsafe { header.read_metadata(slot) };
+            if slot_sequence != sequence {
+                control.fetch_sub(1, Ordering::Release);
+                continue;
+            }
+            if length > header.slot_size {
+                control.fetch_sub(1, Ordering::Release);
+                return Err(FrameError::Corrupted);
+            }
+            break (slot, sequence, length, info);
+        };
+
+        self.last_sequence = sequence;
+        Ok(Some(Frame {
+            reader: self,
+            slot,
+            sequence,
+            length,
+            info,
+        }))
+    }
+}
+
+/// A frame held by a reader. The writer does not reuse the slot until the frame is dropped.
+pub struct Frame<'r, 'a> {
+    reader: &'r FrameReader<'a>,
+    slot: usize,
+    sequence: u64,
+    length: usize,
+    info: FrameInfo,
+}
+
+impl Debug for Frame<'_, '_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Frame{{ sequence: {:?} length: {:?} info: {:?} }}",
+            self.sequence, self.length, self.info
+        )
+    }
+}
+
+impl Frame<'_, '_> {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn info(&self) -> FrameInfo {
+        self.info
+    }
+
+    /// Length of the frame in bytes.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// The frame in shared memory, without copying it.
+    pub fn as_slice(&self) -> &[u8] {
+        let offset = self.reader.header.slot_offset(self.slot);
+        &self.reader.device[offset..offset + self.length]
+    }
+
+    /// Copies the frame into `buf` using the worker threads of the device.
+    ///
+    /// returns: The length of the frame.
+    pub fn read_into(&self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        if buf.len() < self.length {
+            return Err(FrameError::BufferTooSmall { needed: self.length });
+        }
+        let offset = self.reader.header.slot_offset(self.slot);
+        self.reader.device.read_at(offset, &mut buf[..self.length])?;
+        Ok(self.length)
+    }
+}
+
+impl Drop for Frame<'_, '_> {
+    fn drop(&mut self) {
+        self.reader.header.control(self.slot).fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_frames_are_never_reused() {
+        let shm = crate::SharedMemory::scratch("test-frame-held", 1 << 20).unwrap();
+        let mut guest = shm.map(1).unwrap();
+        let mut host = shm.map(1).unwrap();
+        let mut writer = FrameWriter::new(&mut guest, 2, 4096).unwrap();
+        let mut reader = FrameReader::attach(&mut host).unwrap();
+
+        let mut slot = writer.acquire().unwrap();
+        slot.write(&[1; 16]).unwrap();
+        assert_eq!(slot.publish(FrameInfo::default()), 1);
+        let first = reader.acquire_new().unwrap().unwrap();
+        assert_eq!(first.as_slice(), [1; 16]);
+
+        // With two slots, one holds the latest frame and the reader holds the other.
+        let mut slot = writer.acquire().unwrap();
+        slot.write(&[2; 16]).unwrap();
+        slot.publish(FrameInfo::default());
+        assert!(matches!(writer.acquire(), Err(FrameError::Busy)));
+        assert_eq!(first.as_slice(), [1; 16]);
+
+        drop(first);
+        let mut slot = writer.acquire().unwrap();
+        slot.write(&[3; 16]).unwrap();
+        assert_eq!(slot.publish(FrameInfo::default()), 3);
+        let latest = reader.acquire_new().unwrap().unwrap();
+        assert_eq!((latest.sequence(), latest.as_slice()), (3, &[3; 16][..]));
+    }
+
+    #[test]
+    fn dropping_a_slot_keeps_the_latest_frame() {
+        let shm = crate::SharedMemory::scratch("test-frame-drop", 1 << 20).unwrap();
+        let mut guest = shm.map(1).unwrap();
+        let mut host = shm.map(1).unwrap();
+        assert!(matches!(FrameReader::attach(&mut host), Err(FrameError::NotFormatted)));
+
+        let mut writer = FrameWriter::new(&mut guest, 3, 4096).unwrap();
+        let mut reader = FrameReader::attach(&mut host).unwrap();
+        assert!(reader.acquire_latest().unwrap().is_none());
+
+        let info = FrameInfo {
+            width: 4,
+            height: 1,
+            stride: 16,
+            format: u32::from_le_bytes(*b"BGRA"),
+            timestamp: 42,
+        };
+        let mut slot = writer.acquire().unwrap();
+        slot.as_mut_slice()[..16].fill(7);
+        slot.set_len(16).unwrap();
+        slot.publish(info);
+        let mut slot = writer.acquire().unwrap();
+        slot.write(&[8; 16]).unwrap();
+        drop(slot);
+
+        assert_eq!(reader.latest_sequence(), 1);
+        let frame = reader.acquire_new().unwrap().unwrap();
+        assert_eq!((frame.info(), frame.as_slice()), (info, &[7; 16][..]));
+        drop(frame);
+        assert!(reader.acquire_new().unwrap().is_none());
+        assert_eq!(reader.acquire_latest().unwrap().unwrap().sequence(), 1);
+    }
+}
@@ -0,0 +1,39 @@
+//! The header convention shared by the structures this crate formats into shared memory.
+//!
+//! Rings, queues, frame buffers and region tables all start with a header:
+//!
+//! | Offset | Contents                                          |
+//! |--------|---------------------------------------------------|
+//! | 0      | magic (`u64`), identifying the kind of structure  |
+//! | 8      | version (`u32`), followed by structure specific fields |
+//!
+//! Formatting writes every other field first and publishes the magic last, with release ordering. Attaching checks
+//! the magic before anything else, with acquire ordering. A peer in another process or VM that attaches while the
+//! header is being written therefore sees no magic at all, rather than a half written header.
+//!
+//! LGMP uses the layout of upstream, with a `u32` magic, but publishes it in the same order.
+
+use std::sync::atomic::{fence, Ordering};
+
+/// Writes `magic` at `base`, after every write made before the call.
+///
+/// # Safety
+///
+/// `base` must be valid for writing 8 bytes, and aligned to 8 bytes.
+pub(crate) unsafe fn publish(base: *mut u8, magic: u64) {
+    fence(Ordering::Release);
+    std::ptr::write_volatile(base as *mut u64, magic);
+}
+
+/// returns: Whether `magic` was published at `base`. Only then may the rest of the header be read.
+///
+/// # Safety
+///
+/// `base` must be valid for reading 8 bytes, and aligned to 8 bytes.
+pub(crate) unsafe fn is_published(base: *const u8, magic: u64) -> bool {
+    if std::ptr::read_volatile(base as *const u64) != magic {
+        return false;
+    }
+    fence(Ordering::Acquire);
+    true
+}
@@ -0,0 +1,156 @@
+/// The instruction sequence used to copy a single segment.
+///
+/// Write-combined memory, such as the BAR the Windows driver maps for us, performs poorly with ordinary stores.
+/// The non-temporal kernels bypass the cache and are finished with an `sfence`, so the data is globally visible
+/// once the copy completes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CopyKernel {
+    /// `std::ptr::copy_nonoverlapping`, the platform memcpy.
+    Standard,
+    /// A single `rep movsb`, which is fast on CPUs with ERMSB.
+    RepMovsb,
+    /// 16 byte non-temporal stores (`movntdq`), followed by `sfence`.
+    Sse2NonTemporal,
+    /// 32 byte non-temporal stores (`vmovntdq`), followed by `sfence`.
+    Avx2NonTemporal,
+}
+
+impl CopyKernel {
+    /// All kernels, including the ones that are not supported by this CPU.
+    pub const ALL: [CopyKernel; 4] = [
+        CopyKernel::Standard,
+        CopyKernel::RepMovsb,
+        CopyKernel::Sse2NonTemporal,
+        CopyKernel::Avx2NonTemporal,
+    ];
+
+    /// Picks the fastest kernel for write-combined memory that this CPU supports.
+    pub fn detect() -> Self {
+        [CopyKernel::Avx2NonTemporal, CopyKernel::Sse2NonTemporal]
+            .into_iter()
+            .find(|kernel| kernel.is_supported())
+            .unwrap_or(CopyKernel::Standard)
+    }
+
+    /// Returns true if this CPU can execute the kernel.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            CopyKernel::Standard => true,
+            #[cfg(target_arch = "x86_64")]
+            CopyKernel::RepMovsb => true,
+            #[cfg(target_arch = "x86_64")]
+            CopyKernel::Sse2NonTemporal => std::arch::is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            CopyKernel::Avx2NonTemporal => std::arch::is_x86_feature_detected!("avx2"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+
+    /// Copies `length` bytes. Falls back to `Standard` if the kernel is not supported by this CPU.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads and `dst` must be valid for writes of `length` bytes. The regions may not overlap.
+    pub unsafe fn copy(&self, src: *const u8, dst: *mut u8, length: usize) {
+        if !self.is_supported() {
+            std::ptr::copy_nonoverlapping(src, dst, length);
+            return;
+        }
+        match self {
+            CopyKernel::Standard => std::ptr::copy_nonoverlapping(src, dst, length),
+            #[cfg(target_arch = "x86_64")]
+            CopyKernel::RepMovsb => x86_64::rep_movsb(src, dst, length),
+            #[cfg(target_arch = "x86_64")]
+            CopyKernel::Sse2NonTemporal => x86_64::sse2_non_temporal(src, dst, length),
+            #[cfg(target_arch = "x86_64")]
+            CopyKernel::Avx2NonTemporal => x86_64::avx2_non_temporal(src, dst, length),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64 {
+    use std::arch::x86_64::*;
+
+    pub(super) unsafe fn rep_movsb(src: *const u8, dst: *mut u8, length: usize) {
+        std::arch::asm!(
+            "rep movsb",
+            inout("rcx") length => _,
+            inout("rsi") src => _,
+            inout("rdi") dst => _,
+            options(nostack, preserves_flags)
+        );
+    }
+
+    /// Copies with ordinary stores until `dst` is aligned to `alignment`.
+    ///
+    /// returns: The amount of bytes that were copied.
+    unsafe fn align_head(src: *const u8, dst: *mut u8, length: usize, alignment: usize) -> usize {
+        let head = dst.align_offset(alignment).min(length);
+        std::ptr::copy_nonoverlapping(src, dst, head);
+        head
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn sse2_non_temporal(mut src: *const u8, mut dst: *mut u8, mut length: usize) {
+        let head = align_head(src, dst, length, 16);
+        src = src.add(head);
+        dst = dst.add(head);
+        length -= head;
+
+        while length >= 64 {
+            let a = _mm_loadu_si128(src as *const __m128i);
+            let b = _mm_loadu_si128(src.add(16) as *const __m128i);
+            let c = _mm_loadu_si128(src.add(32) as *const __m128i);
+            let d = _mm_loadu_si128(src.add(48) as *const __m128i);
+            _mm_stream_si128(dst as *mut __m128i, a);
+            _mm_stream_si128(dst.add(16) as *mut __m128i, b);
+            _mm_stream_si128(dst.add(32) as *mut __m128i, c);
+            _mm_stream_si128(dst.add(48) as *mut __m128i, d);
+            src = src.add(64);
+            dst = dst.add(64);
+            length -= 64;
+        }
+        while length >= 16 {
+            _mm_stream_si128(dst as *mut __m128i, _mm_loadu_si128(src as *const __m128i));
+            src = src.add(16);
+            dst = dst.add(16);
+            length -= 16;
+        }
+        std::ptr::copy_nonoverlapping(src, dst, length);
+        _mm_sfence();
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn avx2_non_temporal(mut src: *const u8, mut dst: *mut u8, mut length: usize) {
+        let head = align_head(src, dst, length, 32);
+        src = src.add(head);
+        dst = dst.add(head);
+        length -= head;
+
+        while length >= 128 {
+            let a = _mm256_loadu_si256(src as *const __m256i);
+            let b = _mm256_loadu_si256(src.add(32) as *const __m256i);
+            let c = _mm256_loadu_si256(src.add(64) as *const __m256i);
+            let d = _mm256_loadu_si256(src.add(96) as *const __m256i);
+            _mm256_stream_si256(dst as *mut __m256i, a);
+            _mm256_stream_si256(dst.add(32) as *mut __m256i, b);
+            _mm256_stream_si256(dst.add(64) as *mut __m256i, c);
+            _mm256_stream_si256(dst.add(96) as *mut __m256i, d);
+            src = src.add(128);
+            dst = dst.add(128);
+            length -= 128;
+        }
+        while length >= 32 {
+            _mm256_stream_si256(dst as *mut __m256i, _mm256_loadu_si256(src as *const __m256i));
+            src = src.add(32);
+            dst = dst.add(32);
+            length -= 32;
+        }
+        std::ptr::copy_nonoverlapping(src, dst, length);
+        _mm_sfence();
+    }
+}
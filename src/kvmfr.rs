@@ -0,0 +1,584 @@
+//! KVM Frame Relay (KVMFR): the Looking Glass frame and cursor messages, transported over LGMP.
+//!
+//! The host stores a `KvmfrHeader` as LGMP user data and creates two queues: `LGMP_Q_POINTER` for cursor updates
+//! and `LGMP_Q_FRAME` for frames. Every frame message refers to a buffer starting with a `KvmfrFrame`, followed by
+//! the pixels at `KvmfrFrame::offset`. Every cursor message refers to a buffer starting with a `KvmfrCursor`,
+//! followed by the cursor shape. The user data of a cursor message holds the `CURSOR_FLAG_*` bits.
+//!
+//! # Examples
+//!
+//! ```
+//! use ivshmemmap::kvmfr::{FrameType, KvmfrClient, KvmfrCursor, KvmfrFrame, KvmfrHost, CURSOR_FLAG_POSITION};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-kvmfr", 32 << 20).unwrap();
+//! let mut guest = shm.map(4).unwrap();
+//! let mut viewer = shm.map(4).unwrap();
+//!
+//! let mut host = KvmfrHost::new(&mut guest, "ivshmemmap", 1920 * 1080 * 4, 64 * 64 * 4).unwrap();
+//! let mut client = KvmfrClient::attach(&mut viewer).unwrap();
+//! assert_eq!(client.header().host_version, "ivshmemmap");
+//!
+//! let frame = KvmfrFrame::new(FrameType::BGRA, 1920, 1080, 1920 * 4);
+//! host.post_frame(&frame, &vec![0x7F; 1920 * 1080 * 4]).unwrap();
+//! host.post_cursor(CURSOR_FLAG_POSITION, &KvmfrCursor { x: 10, y: 20, ..Default::default() }, &[]).unwrap();
+//!
+//! let mut pixels = Vec::new();
+//! let received = client.read_frame(&mut pixels).unwrap().unwrap();
+//! assert_eq!((received.frame_width, received.frame_height, received.frame_serial), (1920, 1080, 1));
+//! assert!(pixels.iter().all(|&byte| byte == 0x7F));
+//!
+//! let (flags, cursor, _) = client.read_cursor().unwrap().unwrap();
+//! assert_eq!((flags, cursor.x, cursor.y), (CURSOR_FLAG_POSITION, 10, 20));
+//! ```
+
+use crate::device::IvshmemDevice;
+use crate::error::KvmfrError;
+use crate::lgmp::{LgmpClient, LgmpHost, LgmpMemory, LgmpQueue, LgmpSubscription, BUFFER_ALIGNMENT};
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+
+pub const KVMFR_MAGIC: [u8; 8] = *b"KVMFR---";
+pub const KVMFR_VERSION: u32 = 20;
+
+pub const LGMP_Q_POINTER: u32 = 1;
+pub const LGMP_Q_FRAME: u32 = 2;
+pub const LGMP_Q_POINTER_LEN: u32 = 20;
+pub const LGMP_Q_FRAME_LEN: u32 = 2;
+pub const KVMFR_MAX_DAMAGE_RECTS: usize = 64;
+
+pub const CURSOR_FLAG_POSITION: u32 = 1;
+pub const CURSOR_FLAG_VISIBLE: u32 = 2;
+pub const CURSOR_FLAG_SHAPE: u32 = 4;
+
+pub const KVMFR_FEATURE_SETCURSORPOS: u32 = 1;
+
+/// Subscribers that do not acknowledge a frame within this time are timed out by the host.
+const FRAME_TIMEOUT: Duration = Duration::from_millis(1000);
+const POINTER_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const HOST_VERSION_SIZE: usize = 32;
+const HEADER_SIZE: usize = 8 + 4 + HOST_VERSION_SIZE + 4;
+const CURSOR_SIZE: usize = 24;
+const DAMAGE_RECT_SIZE: usize = 16;
+const FRAME_SIZE: usize = 14 * 4 + KVMFR_MAX_DAMAGE_RECTS * DAMAGE_RECT_SIZE + 4;
+
+fn get_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The LGMP user data written by a Looking Glass host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvmfrHeader {
+    pub version: u32,
+    /// Version string of the host application. At most 31 bytes.
+    pub host_version: String,
+    /// `KVMFR_FEATURE_*` bits.
+    pub features: u32,
+}
+
+impl KvmfrHeader {
+    pub const SIZE: usize = HEADER_SIZE;
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..8].copy_from_slice(&KVMFR_MAGIC);
+        put_u32(&mut bytes, 8, self.version);
+        let host_version = self.host_version.as_bytes();
+        let length = host_version.len().min(HOST_VERSION_SIZE - 1);
+        bytes[12..12 + length].copy_from_slice(&host_version[..length]);
+        put_u32(&mut bytes, 12 + HOST_VERSION_SIZE, self.features);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, KvmfrError> {
+        if bytes.len() < HEADER_SIZE || bytes[..8] != KVMFR_MAGIC {
+            return Err(KvmfrError::NotKvmfr);
+        }
+        let version = get_u32(bytes, 8);
+        if version != KVMFR_VERSION {
+            return Err(KvmfrError::UnsupportedVersion(version));
+        }
+        let host_version = &bytes[12..12 + HOST_VERSION_SIZE];
+        let length = host_version.iter().position(|&byte| byte == 0).unwrap_or(HOST_VERSION_SIZE);
+        Ok(Self {
+            version,
+            host_version: String::from_utf8_lossy(&host_version[..length]).into_owned(),
+            features: get_u32(bytes, 12 + HOST_VERSION_SIZE),
+        })
+    }
+}
+
+/// Pixel format of a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct FrameType(pub u32);
+
+impl FrameType {
+    pub const INVALID: FrameType = FrameType(0);
+    pub const BGRA: FrameType = FrameType(1);
+    pub const RGBA: FrameType = FrameType(2);
+    pub const RGBA10: FrameType = FrameType(3);
+    pub const RGBA16F: FrameType = FrameType(4);
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct DamageRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The header of a frame buffer.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct KvmfrFrame {
+    pub format_version: u32,
+    /// Incremented for every frame. Assigned by `KvmfrHost::post_frame`.
+    pub frame_serial: u32,
+    pub frame_type: FrameType,
+    pub screen_width: u32,
+    pub screen_height: u32,
+    pub data_width: u32,
+    pub data_height: u32,
+    pub frame_width: u32,
+    pub frame_height: u32,
+    pub rotation: u32,
+    /// Row length in pixels.
+    pub stride: u32,
+    /// Row length in bytes.
+    pub pitch: u32,
+    /// Offset of the pixels from the start of the frame buffer. Assigned by `KvmfrHost::post_frame`.
+    pub offset: u32,
+    /// Changed areas of the frame. Empty means the entire frame changed. At most `KVMFR_MAX_DAMAGE_RECTS`.
+    pub damage_rects: Vec<DamageRect>,
+    pub flags: u32,
+}
+
+impl KvmfrFrame {
+    pub const SIZE: usize = FRAME_SIZE;
+
+    /// A frame of which the screen, data and frame dimensions are all equal.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame_type`: The pixel format.
+    /// * `width`: Width in pixels.
+    /// * `height`: Height in pixels.
+    /// * `pitch`: Row length in bytes.
+    pub fn new(frame_type: FrameType, width: u32, height: u32, pitch: u32) -> Self {
+        let bytes_per_pixel = if frame_type == FrameType::RGBA16F { 8 } else { 4 };
+        Self {
+            frame_type,
+            screen_width: width,
+            screen_height: height,
+            data_width: width,
+            data_height: height,
+            frame_width: width,
+            frame_height: height,
+            stride: pitch / bytes_per_pixel,
+            pitch,
+            ..Default::default()
+        }
+    }
+
+    /// Size of the pixel data in bytes.
+    pub fn data_size(&self) -> usize {
+        self.data_height as usize * self.pitch as usize
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_SIZE] {
+        let mut bytes = [0u8; FRAME_SIZE];
+        let fields = [
+            self.format_version,
+            self.frame_serial,
+            self.frame_type.0,
+            self.screen_width,
+            self.screen_height,
+            self.data_width,
+            self.data_height,
+            self.frame_width,
+            self.frame_height,
+            self.rotation,
+            self.stride,
+            self.pitch,
+            self.offset,
+            self.damage_rects.len().min(KVMFR_MAX_DAMAGE_RECTS) as u32,
+        ];
+        for (index, value) in fields.into_iter().enumerate() {
+            put_u32(&mut bytes, index * 4, value);
+        }
+        for (index, rect) in self.damage_rects.iter().take(KVMFR_MAX_DAMAGE_RECTS).enumerate() {
+            let offset = fields.len() * 4 + index * DAMAGE_RECT_SIZE;
+            put_u32(&mut bytes, offset, rect.x);
+            put_u32(&mut bytes, offset + 4, rect.y);
+            put_u32(&mut bytes, offset + 8, rect.width);
+            put_u32(&mut bytes, offset + 12, rect.height);
+        }
+        put_u32(&mut bytes, FRAME_SIZE - 4, self.flags);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, KvmfrError> {
+        if bytes.len() < FRAME_SIZE {
+            return Err(KvmfrError::Truncated);
+        }
+        let field = |index: usize| get_u32(bytes, index * 4);
+        let damage_count = field(13) as usize;
+        if damage_count > KVMFR_MAX_DAMAGE_RECTS {
+            return Err(KvmfrError::Corrupted);
+        }
+        let damage_rects = (0..damage_count)
+            .map(|index| {
+                let offset = 14 * 4 + index * DAMAGE_RECT_SIZE;
+                DamageRect {
+                    x: get_u32(bytes, offset),
+                    y: get_u32(bytes, offset + 4),
+                    width: get_u32(bytes, offset + 8),
+                    height: get_u32(bytes, offset + 12),
+                }
+            })
+            .collect();
+        Ok(Self {
+            format_version: field(0),
+            frame_serial: field(1),
+            frame_type: FrameType(field(2)),
+            screen_width: field(3),
+            screen_height: field(4),
+            data_width: field(5),
+            data_height: field(6),
+            frame_width: field(7),
+            frame_height: field(8),
+            rotation: field(9),
+            stride: field(10),
+            pitch: field(11),
+            offset: field(12),
+            damage_rects,
+            flags: get_u32(bytes, FRAME_SIZE - 4),
+        })
+    }
+}
+
+/// Encoding of a cursor shape.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct CursorType(pub u32);
+
+impl CursorType {
+    pub const COLOR: CursorType = CursorType(0);
+    pub const MONOCHROME: CursorType = CursorType(1);
+    pub const MASKED_COLOR: CursorType = CursorType(2);
+}
+
+/// The header of a cursor buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct KvmfrCursor {
+    pub x: i16,
+    pub y: i16,
+    pub cursor_type: CursorType,
+    /// Hotspot of the cursor shape.
+    pub hx: i8,
+    pub hy: i8,
+    pub width: u32,
+    pub height: u32,
+    /// Row length of the shape in bytes.
+    pub pitch: u32,
+}
+
+impl KvmfrCursor {
+    pub const SIZE: usize = CURSOR_SIZE;
+
+    pub fn encode(&self) -> [u8; CURSOR_SIZE] {
+        let mut bytes = [0u8; CURSOR_SIZE];
+        bytes[0..2].copy_from_slice(&self.x.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.y.to_le_bytes());
+        put_u32(&mut bytes, 4, self.cursor_type.0);
+        bytes[8] = self.hx as u8;
+        bytes[9] = self.hy as u8;
+        put_u32(&mut bytes, 12, self.width);
+        put_u32(&mut bytes, 16, self.height);
+        put_u32(&mut bytes, 20, self.pitch);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, KvmfrError> {
+        if bytes.len() < CURSOR_SIZE {
+            return Err(KvmfrError::Truncated);
+        }
+        Ok(Self {
+            x: i16::from_le_bytes([bytes[0], bytes[1]]),
+            y: i16::from_le_bytes([bytes[2], bytes[3]]),
+            cursor_type: CursorType(get_u32(bytes, 4)),
+            hx: bytes[8] as i8,
+            hy: bytes[9] as i8,
+            width: get_u32(bytes, 12),
+            height: get_u32(bytes, 16),
+            pitch: get_u32(bytes, 20),
+        })
+    }
+
+    /// Size of the cursor shape in bytes.
+    pub fn shape_size(&self) -> usize {
+        self.height as usize * self.pitch as usize
+    }
+}
+
+/// Offset of the pixels in a frame buffer.
+fn frame_data_offset() -> usize {
+    FRAME_SIZE.next_multiple_of(BUFFER_ALIGNMENT)
+}
+
+/// A Looking Glass compatible host, which publishes frames and cursor updates.
+pub struct KvmfrHost<'a> {
+    lgmp: LgmpHost<'a>,
+    frame_queue: LgmpQueue,
+    pointer_queue: LgmpQueue,
+    frame_buffers: Vec<LgmpMemory>,
+    pointer_buffers: Vec<LgmpMemory>,
+    frame_serial: u32,
+    pointer_index: usize,
+}
+
+impl Debug for KvmfrHost<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KvmfrHost{{ lgmp: {:?} frame_serial: {:?} }}", self.lgmp, self.frame_serial)
+    }
+}
+
+impl<'a> KvmfrHost<'a> {
+    /// Formats the shared memory of `device` and allocates the frame and cursor buffers.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: The shared memory.
+    /// * `host_version`: Version string shown by clients.
+    /// * `max_frame_size`: Maximum size of the pixels of a frame in bytes.
+    /// * `max_cursor_size`: Maximum size of a cursor shape in bytes.
+    pub fn new(device: &'a mut IvshmemDevice, host_version: &str, max_frame_size: usize, max_cursor_size: usize) -> Result<Self, KvmfrError> {
+        let header = KvmfrHeader {
+            version: KVMFR_VERSION,
+            host_version: host_version.to_string(),
+            features: 0,
+        };
+        let mut lgmp = LgmpHost::init(device, &header.encode())?;
+        let pointer_queue = lgmp.add_queue(LGMP_Q_POINTER, LGMP_Q_POINTER_LEN, POINTER_TIMEOUT)?;
+        let frame_queue = lgmp.add_queue(LGMP_Q_FRAME, LGMP_Q_FRAME_LEN, FRAME_TIMEOUT)?;
+        // A queue of N messages holds at most N - 1 pending messages, so the buffer of the oldest one is always free.
+        let pointer_buffers = (0..LGMP_Q_POINTER_LEN)
+            .map(|_| lgmp.alloc(CURSOR_SIZE + max_cursor_size))
+            .collect::<Result<_, _>>()?;
+        let frame_buffers = (0..LGMP_Q_FRAME_LEN)
+            .map(|_| lgmp.alloc(frame_data_offset() + max_frame_size))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            lgmp,
+            frame_queue,
+            pointer_queue,
+            frame_buffers,
+            pointer_buffers,
+            frame_serial: 0,
+            pointer_index: 0,
+        })
+    }
+
+    /// The LGMP host, e.g. to call `process` regularly.
+    pub fn lgmp(&mut self) -> &mut LgmpHost<'a> {
+        &mut self.lgmp
+    }
+
+    /// Posts a frame. The pixels are copied with the worker threads of the device.
+    /// Assigns `frame_serial` and `offset` of `frame`.
+    ///
+    /// returns: The serial of the frame, or `LgmpError::QueueFull` if clients did not acknowledge the previous frame.
+    pub fn post_frame(&mut self, frame: &KvmfrFrame, pixels: &[u8]) -> Result<u32, KvmfrError> {
+        self.lgmp.process();
+        let index = self.frame_serial as usize % self.frame_buffers.len();
+        let buffer = self.frame_buffers[index];
+        if frame_data_offset() + pixels.len() > buffer.size {
+            return Err(KvmfrError::TooLarge {
+                length: pixels.len(),
+                capacity: buffer.size - frame_data_offset(),
+            });
+        }
+        let mut frame = frame.clone();
+        frame.frame_serial = self.frame_serial.wrapping_add(1);
+        frame.offset = frame_data_offset() as u32;
+
+        self.lgmp.write(&buffer, 0, &frame.encode())?;
+        self.lgmp.write(&buffer, frame_data_offset(), pixels)?;
+        self.lgmp.post(self.frame_queue, 0, &buffer)?;
+        self.frame_serial = frame.frame_serial;
+        Ok(frame.frame_serial)
+    }
+
+    /// Posts a cursor update.
+    ///
+    /// # Arguments
+    ///
+    /// * `flags`: `CURSOR_FLAG_*` bits describing what changed.
+    /// * `cursor`: Position and shape description.
+    /// * `shape`: The cursor shape. Only used with `CURSOR_FLAG_SHAPE`.
+    pub fn post_cursor(&mut self, flags: u32, cursor: &KvmfrCursor, shape: &[u8]) -> Result<(), KvmfrError> {
+        self.lgmp.process();
+        let buffer = self.pointer_buffers[self.pointer_index];
+        if CURSOR_SIZE + shape.len() > buffer.size {
+            return Err(KvmfrError::TooLarge {
+                length: shape.len(),
+                capacity: buffer.size - CURSOR_SIZE,
+            });
+        }
+        self.lgmp.write(&buffer, 0, &cursor.encode())?;
+        self.lgmp.write(&buffer, CURSOR_SIZE, shape)?;
+        self.lgmp.post(self.pointer_queue, flags, &buffer)?;
+        self.pointer_index = (self.pointer_index + 1) % self.pointer_buffers.len();
+        Ok(())
+    }
+}
+
+/// A Looking Glass compatible client, which receives frames and cursor updates.
+pub struct KvmfrClient<'a> {
+    lgmp: LgmpClient<'a>,
+    header: KvmfrHeader,
+    frame_subscription: LgmpSubscription,
+    pointer_subscription: LgmpSubscription,
+}
+
+impl Debug for KvmfrClient<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "KvmfrClient{{ header: {:?} }}", self.header)
+    }
+}
+
+impl<'a> KvmfrClient<'a> {
+    /// Attaches to a Looking Glass compatible host and subscribes to its frame and cursor queues.
+    pub fn attach(device: &'a mut IvshmemDevice) -> Result<Self, KvmfrError> {
+        let mut lgmp = LgmpClient::attach(device)?;
+        let header = KvmfrHeader::decode(lgmp.udata())?;
+        let frame_subscription = lgmp.subscribe(LGMP_Q_FRAME)?;
+        let pointer_subscription = lgmp.subscribe(LGMP_Q_POINTER)?;
+        Ok(Self {
+            lgmp,
+            header,
+            frame_subscription,
+            pointer_subscription,
+        })
+    }
+
+    pub fn header(&self) -> &KvmfrHeader {
+        &self.header
+    }
+
+    /// The underlying LGMP client.
+    pub fn lgmp(&mut self) -> &mut LgmpClient<'a> {
+        &mut self.lgmp
+    }
+
+    /// Receives the next frame and copies its pixels into `pixels`, using the worker threads of the device.
+    ///
+    /// returns: `None` if there is no new frame.
+    pub fn read_frame(&mut self, pixels: &mut Vec<u8>) -> Result<Option<KvmfrFrame>, KvmfrError> {
+        let Some(message) = self.lgmp.process(&mut self.frame_subscription)? else {
+            return Ok(None);
+        };
+        let result = self.copy_frame(&message, pixels);
+        self.lgmp.message_done(&mut self.frame_subscription)?;
+        result.map(Some)
+    }
+
+    fn copy_frame(&self, message: &crate::lgmp::LgmpMessage, pixels: &mut Vec<u8>) -> Result<KvmfrFrame, KvmfrError> {
+        let frame = KvmfrFrame::decode(self.lgmp.data(message)?)?;
+        let start = frame.offset as usize;
+        let length = frame.data_size();
+        if start.saturating_add(length) > message.size {
+            return Err(KvmfrError::Corrupted);
+        }
+        pixels.resize(length, 0);
+        self.lgmp.read(message, start, pixels)?;
+        Ok(frame)
+    }
+
+    /// Receives the next cursor update.
+    ///
+    /// returns: The `CURSOR_FLAG_*` bits, the cursor, and the shape if `CURSOR_FLAG_SHAPE` is set. `None` if there is no update.
+    pub fn read_cursor(&mut self) -> Result<Option<(u32, KvmfrCursor, Vec<u8>)>, KvmfrError> {
+        let Some(message) = self.lgmp.process(&mut self.pointer_subscription)? else {
+            return Ok(None);
+        };
+        let result = self.lgmp.data(&message).map_err(KvmfrError::from).and_then(|data| {
+            let cursor = KvmfrCursor::decode(data)?;
+            let mut shape = Vec::new();
+            if message.udata & CURSOR_FLAG_SHAPE != 0 {
+                shape = data
+                    .get(CURSOR_SIZE..CURSOR_SIZE + cursor.shape_size())
+                    .ok_or(KvmfrError::Corrupted)?
+                    .to_vec();
+            }
+            Ok((message.udata, cursor, shape))
+        });
+        self.lgmp.message_done(&mut self.pointer_subscription)?;
+        result.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_encoding() {
+        let header = KvmfrHeader {
+            version: KVMFR_VERSION,
+            host_version: "B7-rc1".to_string(),
+            features: KVMFR_FEATURE_SETCURSORPOS,
+        };
+        assert_eq!(KvmfrHeader::decode(&header.encode()).unwrap(), header);
+
+        let frame = KvmfrFrame {
+            frame_serial: 7,
+            rotation: 2,
+            offset: 4096,
+            damage_rects: vec![DamageRect { x: 1, y: 2, width: 3, height: 4 }; KVMFR_MAX_DAMAGE_RECTS],
+            flags: 1,
+            ..KvmfrFrame::new(FrameType::RGBA16F, 640, 480, 640 * 8)
+        };
+        assert_eq!(frame.stride, 640);
+        assert_eq!(KvmfrFrame::decode(&frame.encode()).unwrap(), frame);
+
+        let cursor = KvmfrCursor {
+            x: -5,
+            y: -1,
+            cursor_type: CursorType::MASKED_COLOR,
+            hx: -2,
+            hy: 3,
+            width: 32,
+            height: 32,
+            pitch: 128,
+        };
+        assert_eq!(KvmfrCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert_eq!(cursor.shape_size(), 32 * 128);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let header = KvmfrHeader {
+            version: KVMFR_VERSION,
+            host_version: "a version string longer than 31 bytes".to_string(),
+            features: 0,
+        };
+        let mut bytes = header.encode();
+        assert_eq!(KvmfrHeader::decode(&bytes).unwrap().host_version, "a version string longer than 31");
+        assert!(matches!(KvmfrHeader::decode(&bytes[..HEADER_SIZE - 1]), Err(KvmfrError::NotKvmfr)));
+        put_u32(&mut bytes, 8, KVMFR_VERSION - 1);
+        assert!(matches!(KvmfrHeader::decode(&bytes), Err(KvmfrError::UnsupportedVersion(19))));
+        bytes[0] = b'X';
+        assert!(matches!(KvmfrHeader::decode(&bytes), Err(KvmfrError::NotKvmfr)));
+
+        let mut bytes = KvmfrFrame::default().encode();
+        assert!(matches!(KvmfrFrame::decode(&bytes[..FRAME_SIZE - 1]), Err(KvmfrError::Truncated)));
+        put_u32(&mut bytes, 13 * 4, KVMFR_MAX_DAMAGE_RECTS as u32 + 1);
+        assert!(matches!(KvmfrFrame::decode(&bytes), Err(KvmfrError::Corrupted)));
+
+        assert!(matches!(KvmfrCursor::decode(&[0; CURSOR_SIZE - 1]), Err(KvmfrError::Truncated)));
+    }
+}
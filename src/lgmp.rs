@@ -0,0 +1,882 @@
+//! Looking Glass Memory Protocol (LGMP): message queues between a single host and multiple clients.
+//!
+//! The host formats the shared memory, creates queues, allocates buffers and posts messages that refer to them.
+//! Clients subscribe to a queue and acknowledge every message they received. The host reclaims a message once all
+//! subscribers acknowledged it, or marks subscribers that take longer than the queue's maximum time as timed out.
+//!
+//! The layout is that of `struct LGMPHeader` in `lgmp/src/headers.h` of LGMP, with natural C alignment:
+//!
+//! | Offset                  | Contents                                                                  |
+//! |-------------------------|---------------------------------------------------------------------------|
+//! | 0                       | magic (`u32`), version (`u32`), session id (`u32`), padding               |
+//! | 16                      | host heartbeat in milliseconds (`u64`)                                    |
+//! | 24                      | queue count (`u32`), padding                                              |
+//! | 32 + 320 * queue        | queue headers, see below                                                  |
+//! | 1632                    | user data size (`u32`)                                                    |
+//! | `HEADER_SIZE` (1636)    | user data, such as the KVMFR header                                       |
+//!
+//! Every queue header contains the queue id, message count, new subscriber count, maximum message time in
+//! milliseconds, a spin lock, the subscriber bits (subscribed in the upper, timed out in the lower 32 bits),
+//! the positions of the oldest and next message, the offset of its message array, and a per subscriber
+//! timeout table. A message is 16 bytes: user data, size, offset of its buffer, and the pending subscriber bits.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//! use ivshmemmap::lgmp::{LgmpClient, LgmpHost};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-lgmp", 1 << 20).unwrap();
+//! let mut host_side = shm.map(1).unwrap();
+//! let mut client_side = shm.map(1).unwrap();
+//!
+//! let mut host = LgmpHost::init(&mut host_side, b"hello").unwrap();
+//! let queue = host.add_queue(7, 4, Duration::from_millis(100)).unwrap();
+//! let buffer = host.alloc(4096).unwrap();
+//!
+//! let mut client = LgmpClient::attach(&mut client_side).unwrap();
+//! assert_eq!(client.udata(), b"hello");
+//! let mut subscription = client.subscribe(7).unwrap();
+//!
+//! host.write(&buffer, 0, b"message").unwrap();
+//! host.post(queue, 42, &buffer).unwrap();
+//!
+//! let message = client.process(&mut subscription).unwrap().unwrap();
+//! assert_eq!(message.udata, 42);
+//! assert_eq!(&client.data(&message).unwrap()[..7], b"message");
+//! client.message_done(&mut subscription).unwrap();
+//!
+//! host.process();
+//! assert_eq!(host.pending(queue), 0);
+//! ```
+//!
+//! The layout can also be assembled by hand, as a Looking Glass host would leave it. Offsets are those of
+//! `offsetof(struct LGMPHeader, ...)` and `offsetof(struct LGMPHeaderQueue, ...)` in upstream's `headers.h`:
+//!
+//! ```
+//! use ivshmemmap::lgmp::{LgmpClient, LGMP_PROTOCOL_MAGIC, LGMP_PROTOCOL_VERSION};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-lgmp-raw", 64 * 1024).unwrap();
+//! let mut recorded = shm.map(1).unwrap();
+//! let put = |memory: &mut [u8], offset: usize, bytes: &[u8]| memory[offset..offset + bytes.len()].copy_from_slice(bytes);
+//!
+//! put(&mut recorded, 0, &LGMP_PROTOCOL_MAGIC.to_le_bytes());
+//! put(&mut recorded, 4, &LGMP_PROTOCOL_VERSION.to_le_bytes());
+//! put(&mut recorded, 8, &0x1234u32.to_le_bytes()); // session id
+//! put(&mut recorded, 24, &1u32.to_le_bytes()); // numQueues
+//! put(&mut recorded, 32, &2u32.to_le_bytes()); // queues[0].queueID
+//! put(&mut recorded, 36, &2u32.to_le_bytes()); // queues[0].numMessages
+//! put(&mut recorded, 44, &1000u32.to_le_bytes()); // queues[0].maxTime
+//! put(&mut recorded, 80, &1u32.to_le_bytes()); // queues[0].position
+//! put(&mut recorded, 84, &8192u32.to_le_bytes()); // queues[0].messagesOffset
+//! put(&mut recorded, 1632, &4u32.to_le_bytes()); // udataSize
+//! put(&mut recorded, 1636, b"udat"); // udata
+//! // Message 0: user data 5, 3 bytes at offset 12288, pending for subscriber 0.
+//! put(&mut recorded, 8192, &[5, 0, 0, 0, 3, 0, 0, 0, 0, 0x30, 0, 0, 1, 0, 0, 0]);
+//! put(&mut recorded, 12288, b"abc");
+//!
+//! let mut client = LgmpClient::attach(&mut recorded).unwrap();
+//! assert_eq!(client.session_id(), 0x1234);
+//! assert_eq!(client.udata(), b"udat");
+//! let mut subscription = client.subscribe(2).unwrap();
+//! // Subscribers only receive messages posted after they subscribed, so rewind to the recorded message.
+//! subscription.rewind_to(0);
+//! let message = client.process(&mut subscription).unwrap().unwrap();
+//! assert_eq!((message.udata, message.size, message.offset), (5, 3, 12288));
+//! assert_eq!(client.data(&message).unwrap(), b"abc");
+//! ```
+
+use crate::device::IvshmemDevice;
+use crate::error::LgmpError;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// "LGMP" in ASCII.
+pub const LGMP_PROTOCOL_MAGIC: u32 = u32::from_le_bytes(*b"LGMP");
+pub const LGMP_PROTOCOL_VERSION: u32 = 10;
+pub const LGMP_MAX_QUEUES: usize = 5;
+pub const LGMP_MAX_QUEUE_SUBS: usize = 32;
+/// Size of the LGMP header. The user data starts here.
+pub const HEADER_SIZE: usize = UDATA_SIZE_OFFSET + 4;
+/// Buffers returned by `LgmpHost::alloc` are aligned to this amount of bytes.
+pub const BUFFER_ALIGNMENT: usize = 4096;
+
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 4;
+const SESSION_OFFSET: usize = 8;
+const TIMESTAMP_OFFSET: usize = 16;
+const NUM_QUEUES_OFFSET: usize = 24;
+const QUEUES_OFFSET: usize = 32;
+const UDATA_SIZE_OFFSET: usize = QUEUES_OFFSET + LGMP_MAX_QUEUES * QUEUE_SIZE;
+
+const QUEUE_SIZE: usize = 320;
+const QUEUE_ID: usize = 0;
+const QUEUE_NUM_MESSAGES: usize = 4;
+const QUEUE_NEW_SUB_COUNT: usize = 8;
+const QUEUE_MAX_TIME: usize = 12;
+const QUEUE_LOCK: usize = 16;
+const QUEUE_SUBS: usize = 24;
+const QUEUE_START: usize = 32;
+const QUEUE_MESSAGE_TIMEOUT: usize = 40;
+const QUEUE_POSITION: usize = 48;
+const QUEUE_MESSAGES_OFFSET: usize = 52;
+const QUEUE_TIMEOUTS: usize = 56;
+const QUEUE_COUNT: usize = 312;
+
+const MESSAGE_SIZE: usize = 16;
+const MESSAGE_UDATA: usize = 0;
+const MESSAGE_SIZE_FIELD: usize = 4;
+const MESSAGE_OFFSET: usize = 8;
+const MESSAGE_PENDING_SUBS: usize = 12;
+
+/// How long to wait for the spin lock of a queue. A holder that takes longer has most likely died.
+const LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn subs_on(subs: u64) -> u32 {
+    (subs >> 32) as u32
+}
+
+fn subs_bad(subs: u64) -> u32 {
+    subs as u32
+}
+
+/// Pointers into an LGMP header in shared memory.
+#[derive(Copy, Clone)]
+struct Layout {
+    base: *mut u8,
+    length: usize,
+}
+
+impl Layout {
+    fn u32_at(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.base.add(offset) as *const AtomicU32) }
+    }
+
+    fn u64_at(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+
+    fn queue(&self, index: usize) -> usize {
+        QUEUES_OFFSET + index * QUEUE_SIZE
+    }
+
+    fn queue_u32(&self, index: usize, field: usize) -> &AtomicU32 {
+        self.u32_at(self.queue(index) + field)
+    }
+
+    fn queue_u64(&self, index: usize, field: usize) -> &AtomicU64 {
+        self.u64_at(self.queue(index) + field)
+    }
+
+    /// Offset of message `position` of a queue, validated against the size of the shared memory.
+    fn message(&self, index: usize, position: u32) -> Result<usize, LgmpError> {
+        let offset = self.queue_u32(index, QUEUE_MESSAGES_OFFSET).load(Ordering::Relaxed) as usize
+            + position as usize * MESSAGE_SIZE;
+        if !offset.is_multiple_of(4) || offset + MESSAGE_SIZE > self.length {
+            return Err(LgmpError::Corrupted);
+        }
+        Ok(offset)
+    }
+
+    /// The message count of a queue, which indices are taken modulo of.
+    fn num_messages(&self, index: usize) -> Result<u32, LgmpError> {
+        match self.queue_u32(index, QUEUE_NUM_MESSAGES).load(Ordering::Relaxed) {
+            0 => Err(LgmpError::Corrupted),
+            num_messages => Ok(num_messages),
+        }
+    }
+
+    /// Reads the start or position field of a queue, validated against `num_messages`.
+    fn position(&self, index: usize, field: usize, num_messages: u32) -> Result<u32, LgmpError> {
+        let position = self.queue_u32(index, field).load(Ordering::Acquire);
+        if position >= num_messages {
+            return Err(LgmpError::Corrupted);
+        }
+        Ok(position)
+    }
+
+    fn num_queues(&self) -> usize {
+        (self.u32_at(NUM_QUEUES_OFFSET).load(Ordering::Acquire) as usize).min(LGMP_MAX_QUEUES)
+    }
+
+    /// Takes the spin lock of a queue.
+    ///
+    /// returns: `LgmpError::LockTimedOut` if it is still held after `LOCK_TIMEOUT`.
+    fn lock(&self, index: usize) -> Result<(), LgmpError> {
+        let lock = self.queue_u32(index, QUEUE_LOCK);
+        let start = Instant::now();
+        while lock
+            .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if start.elapsed() > LOCK_TIMEOUT {
+                return Err(LgmpError::LockTimedOut);
+            }
+            std::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, index: usize) {
+        self.queue_u32(index, QUEUE_LOCK).store(0, Ordering::Release);
+    }
+}
+
+/// Shared memory allocated by `LgmpHost::alloc`, which messages refer to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LgmpMemory {
+    /// Offset from the start of the shared memory.
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// A queue created by `LgmpHost::add_queue`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LgmpQueue(usize);
+
+/// A message received by a client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LgmpMessage {
+    /// Application defined value posted along with the message.
+    pub udata: u32,
+    pub size: usize,
+    /// Offset of the message buffer from the start of the shared memory.
+    pub offset: usize,
+}
+
+/// The host side of LGMP. There may only be a single host per shared memory.
+pub struct LgmpHost<'a> {
+    device: &'a mut IvshmemDevice,
+    layout: Layout,
+    started: Instant,
+    /// The next free byte for `alloc`.
+    allocated: usize,
+    /// Per queue, the time every message was posted at.
+    posted: Vec<Vec<Instant>>,
+}
+
+// The header is only accessed atomically. Clients only access buffers that the host has posted.
+unsafe impl Send for LgmpHost<'_> {}
+
+impl Debug for LgmpHost<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "LgmpHost{{ queues: {:?} allocated: {:?} }}",
+            self.posted.len(),
+            self.allocated
+        )
+    }
+}
+
+impl<'a> LgmpHost<'a> {
+    /// Formats the shared memory of `device` with a new session, discarding any previous content.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: The shared memory.
+    /// * `udata`: Application defined data that clients read when they attach, such as the KVMFR header.
+    pub fn init(device: &'a mut IvshmemDevice, udata: &[u8]) -> Result<Self, LgmpError> {
+        let allocated = (HEADER_SIZE + udata.len()).next_multiple_of(64);
+        if allocated > device.len() {
+            return Err(LgmpError::OutOfMemory);
+        }
+        let layout = Layout {
+            base: device.as_mut_ptr(),
+            length: device.len(),
+        };
+        let session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos() ^ time.as_secs() as u32)
+            ^ std::process::id();
+
+        unsafe {
+            std::ptr::write_bytes(layout.base, 0, HEADER_SIZE);
+            std::ptr::copy_nonoverlapping(udata.as_ptr(), layout.base.add(HEADER_SIZE), udata.len());
+        }
+        layout.u32_at(VERSION_OFFSET).store(LGMP_PROTOCOL_VERSION, Ordering::Relaxed);
+        layout.u32_at(SESSION_OFFSET).store(session, Ordering::Relaxed);
+        layout.u32_at(UDATA_SIZE_OFFSET).store(udata.len() as u32, Ordering::Relaxed);
+        // The magic goes last, as described in `crate::header`.
+        layout.u32_at(MAGIC_OFFSET).store(LGMP_PROTOCOL_MAGIC, Ordering::Release);
+
+        Ok(Self {
+            device,
+            layout,
+            started: Instant::now(),
+            allocated,
+            posted: Vec::new(),
+        })
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.layout.u32_at(SESSION_OFFSET).load(Ordering::Relaxed)
+    }
+
+    /// Creates a queue that clients can subscribe to with `queue_id`.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_id`: Identifier that clients subscribe with.
+    /// * `num_messages`: Size of the message ring. At most `num_messages - 1` messages can be pending.
+    /// * `max_time`: Subscribers that do not acknowledge a message within this time are marked as timed out.
+    pub fn add_queue(&mut self, queue_id: u32, num_messages: u32, max_time: Duration) -> Result<LgmpQueue, LgmpError> {
+        let index = self.posted.len();
+        if index == LGMP_MAX_QUEUES {
+            return Err(LgmpError::TooManyQueues);
+        }
+        if num_messages < 2 {
+            return Err(LgmpError::InvalidMessageCount(num_messages));
+        }
+        let messages = self.alloc(num_messages as usize * MESSAGE_SIZE)?;
+        unsafe {
+            std::ptr::write_bytes(self.layout.base.add(messages.offset), 0, messages.size);
+        }
+
+        let layout = self.layout;
+        layout.queue_u32(index, QUEUE_ID).store(queue_id, Ordering::Relaxed);
+        layout.queue_u32(index, QUEUE_NUM_MESSAGES).store(num_messages, Ordering::Relaxed);
+        layout.queue_u32(index, QUEUE_MAX_TIME).store(max_time.as_millis() as u32, Ordering::Relaxed);
+        layout.queue_u32(index, QUEUE_MESSAGES_OFFSET).store(messages.offset as u32, Ordering::Relaxed);
+        layout.u32_at(NUM_QUEUES_OFFSET).store(index as u32 + 1, Ordering::Release);
+
+        self.posted.push(vec![self.started; num_messages as usize]);
+        Ok(LgmpQueue(index))
+    }
+
+    /// Allocates a buffer in the shared memory. Buffers are never freed, but can be reused for later messages.
+    pub fn alloc(&mut self, size: usize) -> Result<LgmpMemory, LgmpError> {
+        let offset = self.allocated.next_multiple_of(BUFFER_ALIGNMENT);
+        let end = offset.checked_add(size).ok_or(LgmpError::OutOfMemory)?;
+        // Message offsets are 32 bit on the wire.
+        if end > self.device.len() || end > u32::MAX as usize {
+            return Err(LgmpError::OutOfMemory);
+        }
+        self.allocated = end;
+        Ok(LgmpMemory { offset, size })
+    }
+
+    /// Copies `data` into a buffer at `offset`, using the worker threads of the device.
+    pub fn write(&mut self, memory: &LgmpMemory, offset: usize, data: &[u8]) -> Result<(), LgmpError> {
+        if offset.saturating_add(data.len()) > memory.size {
+            return Err(LgmpError::OutOfBounds);
+        }
+        self.device.write_at(memory.offset + offset, data)?;
+        Ok(())
+    }
+
+    /// The contents of a buffer, for producing data in place.
+    pub fn buffer_mut(&mut self, memory: &LgmpMemory) -> &mut [u8] {
+        &mut self.device[memory.offset..memory.offset + memory.size]
+    }
+
+    /// Posts a message referring to `memory` to every current subscriber of `queue`.
+    ///
+    /// returns: `LgmpError::QueueFull` if the subscribers did not acknowledge enough messages yet.
+    pub fn post(&mut self, queue: LgmpQueue, udata: u32, memory: &LgmpMemory) -> Result<(), LgmpError> {
+        let layout = self.layout;
+        let index = queue.0;
+        let num_messages = layout.num_messages(index)?;
+        let position = layout.position(index, QUEUE_POSITION, num_messages)?;
+        if num_messages as usize != self.posted[index].len() {
+            return Err(LgmpError::Corrupted);
+        }
+        let next = (position + 1) % num_messages;
+        if next == layout.queue_u32(index, QUEUE_START).load(Ordering::Acquire) {
+            return Err(LgmpError::QueueFull);
+        }
+
+        let subs = layout.queue_u64(index, QUEUE_SUBS).load(Ordering::Acquire);
+        let message = layout.message(index, position)?;
+        layout.u32_at(message + MESSAGE_UDATA).store(udata, Ordering::Relaxed);
+        layout.u32_at(message + MESSAGE_SIZE_FIELD).store(memory.size as u32, Ordering::Relaxed);
+        layout.u32_at(message + MESSAGE_OFFSET).store(memory.offset as u32, Ordering::Relaxed);
+        layout
+            .u32_at(message + MESSAGE_PENDING_SUBS)
+            .store(subs_on(subs) & !subs_bad(subs), Ordering::Relaxed);
+        self.posted[index][position as usize] = Instant::now();
+        layout.queue_u32(index, QUEUE_COUNT).fetch_add(1, Ordering::Relaxed);
+        layout.queue_u32(index, QUEUE_POSITION).store(next, Ordering::Release);
+        Ok(())
+    }
+
+    /// Amount of messages in `queue` that have not been reclaimed yet.
+    pub fn pending(&self, queue: LgmpQueue) -> u32 {
+        self.layout.queue_u32(queue.0, QUEUE_COUNT).load(Ordering::Relaxed)
+    }
+
+    /// Amount of clients subscribed to `queue` that have not timed out.
+    pub fn subscriber_count(&self, queue: LgmpQueue) -> u32 {
+        let subs = self.layout.queue_u64(queue.0, QUEUE_SUBS).load(Ordering::Acquire);
+        (subs_on(subs) & !subs_bad(subs)).count_ones()
+    }
+
+    /// Updates the heartbeat, reclaims acknowledged messages and times out slow subscribers. Call this regularly.
+    pub fn process(&mut self) {
+        let layout = self.layout;
+        let now = Instant::now();
+        layout
+            .u64_at(TIMESTAMP_OFFSET)
+            .store(now.duration_since(self.started).as_millis() as u64, Ordering::Release);
+
+        for index in 0..self.posted.len() {
+            layout.queue_u32(index, QUEUE_NEW_SUB_COUNT).store(0, Ordering::Relaxed);
+            let max_time = Duration::from_millis(layout.queue_u32(index, QUEUE_MAX_TIME).load(Ordering::Relaxed) as u64);
+            // Only the host writes these fields, so a queue that fails validation has been overwritten. Leave it
+            // alone rather than reclaiming messages based on garbage.
+            let Ok((num_messages, position, mut start)) = layout.num_messages(index).and_then(|num_messages| {
+                if num_messages as usize != self.posted[index].len() {
+                    return Err(LgmpError::Corrupted);
+                }
+                Ok((
+                    num_messages,
+                    layout.position(index, QUEUE_POSITION, num_messages)?,
+                    layout.position(index, QUEUE_START, num_messages)?,
+                ))
+            }) else {
+                continue;
+            };
+
+            while start != position {
+                let Ok(message) = layout.message(index, start) else {
+                    break;
+                };
+                let subs = layout.queue_u64(index, QUEUE_SUBS).load(Ordering::Acquire);
+                let pending = layout.u32_at(message + MESSAGE_PENDING_SUBS).load(Ordering::Acquire)
+                    & subs_on(subs)
+                    & !subs_bad(subs);
+                if pending != 0 {
+                    if now.duration_since(self.posted[index][start as usize]) < max_time {
+                        break;
+                    }
+                    // The subscribers are too slow. They have to resubscribe. A client that died holding the lock
+                    // never releases it, so the host takes it over after the timeout.
+                    let _ = layout.lock(index);
+                    layout.queue_u64(index, QUEUE_SUBS).fetch_or(pending as u64, Ordering::AcqRel);
+                    layout.unlock(index);
+                }
+                start = (start + 1) % num_messages;
+                layout.queue_u32(index, QUEUE_COUNT).fetch_sub(1, Ordering::Relaxed);
+            }
+            layout.queue_u32(index, QUEUE_START).store(start, Ordering::Release);
+            let expires = self.posted[index][start as usize] + max_time;
+            layout.queue_u64(index, QUEUE_MESSAGE_TIMEOUT).store(
+                expires.saturating_duration_since(self.started).as_millis() as u64,
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+/// A subscription of a client to a queue.
+#[derive(Debug)]
+pub struct LgmpSubscription {
+    queue: usize,
+    bit: u32,
+    position: u32,
+}
+
+impl LgmpSubscription {
+    /// Index of this subscriber in the subscriber bits of the queue.
+    pub fn id(&self) -> u32 {
+        self.bit.trailing_zeros()
+    }
+
+    /// Moves the read position to an earlier message. Messages that are not pending for this subscriber are skipped.
+    pub fn rewind_to(&mut self, position: u32) {
+        self.position = position;
+    }
+}
+
+/// The client side of LGMP.
+pub struct LgmpClient<'a> {
+    device: &'a mut IvshmemDevice,
+    layout: Layout,
+    session_id: u32,
+}
+
+// The header is only accessed atomically, message buffers only while a message is pending for this client.
+unsafe impl Send for LgmpClient<'_> {}
+
+impl Debug for LgmpClient<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LgmpClient{{ session_id: {:?} }}", self.session_id)
+    }
+}
+
+impl<'a> LgmpClient<'a> {
+    /// Attaches to the session of the host that formatted `device`.
+    pub fn attach(device: &'a mut IvshmemDevice) -> Result<Self, LgmpError> {
+        if device.len() < HEADER_SIZE {
+            return Err(LgmpError::Corrupted);
+        }
+        let layout = Layout {
+            base: device.as_mut_ptr(),
+            length: device.len(),
+        };
+        if layout.u32_at(MAGIC_OFFSET).load(Ordering::Acquire) != LGMP_PROTOCOL_MAGIC {
+            return Err(LgmpError::NotFormatted);
+        }
+        let version = layout.u32_at(VERSION_OFFSET).load(Ordering::Relaxed);
+        if version != LGMP_PROTOCOL_VERSION {
+            return Err(LgmpError::UnsupportedVersion(version));
+        }
+        let udata_size = layout.u32_at(UDATA_SIZE_OFFSET).load(Ordering::Relaxed) as usize;
+        if HEADER_SIZE + udata_size > device.len() {
+            return Err(LgmpError::Corrupted);
+        }
+        Ok(Self {
+            session_id: layout.u32_at(SESSION_OFFSET).load(Ordering::Relaxed),
+            device,
+            layout,
+        })
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// The host heartbeat in milliseconds. A host that stopped calling `process` no longer advances it.
+    pub fn host_timestamp(&self) -> u64 {
+        self.layout.u64_at(TIMESTAMP_OFFSET).load(Ordering::Acquire)
+    }
+
+    /// The user data that the host provided to `LgmpHost::init`.
+    pub fn udata(&self) -> &[u8] {
+        let size = self.layout.u32_at(UDATA_SIZE_OFFSET).load(Ordering::Relaxed) as usize;
+        &self.device[HEADER_SIZE..HEADER_SIZE + size]
+    }
+
+    fn check_session(&self) -> Result<(), LgmpError> {
+        if self.layout.u32_at(SESSION_OFFSET).load(Ordering::Acquire) != self.session_id {
+            return Err(LgmpError::SessionChanged);
+        }
+        Ok(())
+    }
+
+    /// Subscribes to the queue with `queue_id`. Only messages posted after subscribing are received.
+    pub fn subscribe(&mut self, queue_id: u32) -> Result<LgmpSubscription, LgmpError> {
+        self.check_session()?;
+        let layout = self.layout;
+        let queue = (0..layout.num_queues())
+            .find(|&index| layout.queue_u32(index, QUEUE_ID).load(Ordering::Relaxed) == queue_id)
+            .ok_or(LgmpError::NoSuchQueue(queue_id))?;
+        let num_messages = layout.num_messages(queue)?;
+
+        layout.lock(queue)?;
+        let subs = layout.queue_u64(queue, QUEUE_SUBS).load(Ordering::Acquire);
+        let taken = subs_on(subs) | subs_bad(subs);
+        let position = match layout.position(queue, QUEUE_POSITION, num_messages) {
+            Ok(position) if taken != u32::MAX => position,
+            result => {
+                layout.unlock(queue);
+                return Err(result.err().unwrap_or(LgmpError::NoSubscriberSlots));
+            }
+        };
+        let bit = 1u32 << (!taken).trailing_zeros();
+        layout.queue_u64(queue, QUEUE_SUBS).fetch_or((bit as u64) << 32, Ordering::AcqRel);
+        layout.queue_u64(queue, QUEUE_TIMEOUTS + bit.trailing_zeros() as usize * 8).store(0, Ordering::Relaxed);
+        layout.queue_u32(queue, QUEUE_NEW_SUB_COUNT).fetch_add(1, Ordering::Relaxed);
+        layout.unlock(queue);
+
+        Ok(LgmpSubscription { queue, bit, position })
+    }
+
+    /// Ends a subscription, so the host no longer waits for it.
+    pub fn unsubscribe(&mut self, subscription: LgmpSubscription) -> Result<(), LgmpError> {
+        let layout = self.layout;
+        layout.lock(subscription.queue)?;
+        layout
+            .queue_u64(subscription.queue, QUEUE_SUBS)
+            .fetch_and(!((subscription.bit as u64) << 32 | subscription.bit as u64), Ordering::AcqRel);
+        layout.unlock(subscription.queue);
+        Ok(())
+    }
+
+    fn check_subscription(&self, subscription: &LgmpSubscription) -> Result<(), LgmpError> {
+        self.check_session()?;
+        let subs = self.layout.queue_u64(subscription.queue, QUEUE_SUBS).load(Ordering::Acquire);
+        if subs_bad(subs) & subscription.bit != 0 {
+            return Err(LgmpError::TimedOut);
+        }
+        if subs_on(subs) & subscription.bit == 0 {
+            return Err(LgmpError::NotSubscribed);
+        }
+        Ok(())
+    }
+
+    /// Returns the next message without acknowledging it. Call `message_done` once the message has been handled.
+    ///
+    /// returns: `None` if there is no new message, or `LgmpError::TimedOut` if the host gave up on this subscriber.
+    pub fn process(&mut self, subscription: &mut LgmpSubscription) -> Result<Option<LgmpMessage>, LgmpError> {
+        self.check_subscription(subscription)?;
+        let layout = self.layout;
+        let num_messages = layout.num_messages(subscription.queue)?;
+        loop {
+            if subscription.position >= num_messages {
+                return Err(LgmpError::Corrupted);
+            }
+            if subscription.position == layout.position(subscription.queue, QUEUE_POSITION, num_messages)? {
+                return Ok(None);
+            }
+            let message = layout.message(subscription.queue, subscription.position)?;
+            if layout.u32_at(message + MESSAGE_PENDING_SUBS).load(Ordering::Acquire) & subscription.bit == 0 {
+                // Posted before this client subscribed.
+                subscription.position = (subscription.position + 1) % num_messages;
+                continue;
+            }
+            let message = LgmpMessage {
+                udata: layout.u32_at(message + MESSAGE_UDATA).load(Ordering::Relaxed),
+                size: layout.u32_at(message + MESSAGE_SIZE_FIELD).load(Ordering::Relaxed) as usize,
+                offset: layout.u32_at(message + MESSAGE_OFFSET).load(Ordering::Relaxed) as usize,
+            };
+            if message.offset.saturating_add(message.size) > self.device.len() {
+                return Err(LgmpError::Corrupted);
+            }
+            return Ok(Some(message));
+        }
+    }
+
+    /// Acknowledges the message last returned by `process`, so the host can reclaim it.
+    pub fn message_done(&mut self, subscription: &mut LgmpSubscription) -> Result<(), LgmpError> {
+        self.check_subscription(subscription)?;
+        let layout = self.layout;
+        let num_messages = layout.num_messages(subscription.queue)?;
+        if subscription.position >= num_messages {
+            return Err(LgmpError::Corrupted);
+        }
+        if subscription.position == layout.position(subscription.queue, QUEUE_POSITION, num_messages)? {
+            return Err(LgmpError::NoMessage);
+        }
+        let message = layout.message(subscription.queue, subscription.position)?;
+        layout
+            .u32_at(message + MESSAGE_PENDING_SUBS)
+            .fetch_and(!subscription.bit, Ordering::Release);
+        subscription.position = (subscription.position + 1) % num_messages;
+        Ok(())
+    }
+
+    /// The buffer of a message. Valid until the message is acknowledged with `message_done`.
+    pub fn data(&self, message: &LgmpMessage) -> Result<&[u8], LgmpError> {
+        self.check_session()?;
+        Ok(&self.device[message.offset..message.offset + message.size])
+    }
+
+    /// Copies part of a message buffer into `buf`, using the worker threads of the device.
+    pub fn read(&self, message: &LgmpMessage, offset: usize, buf: &mut [u8]) -> Result<(), LgmpError> {
+        if offset.saturating_add(buf.len()) > message.size {
+            return Err(LgmpError::OutOfBounds);
+        }
+        self.check_session()?;
+        self.device.read_at(message.offset + offset, buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::{offset_of, size_of};
+
+    // Field for field transcriptions of the structs in lgmp/src/headers.h of LGMP (https://github.com/gnif/LGMP),
+    // protocol version 10. The C compiler lays them out like `repr(C)`, so `offset_of!` yields upstream's `offsetof`.
+
+    #[repr(C)]
+    struct LGMPHeaderMessage {
+        udata: u32,
+        size: u32,
+        offset: u32,
+        pending_subs: u32,
+    }
+
+    #[repr(C)]
+    struct LGMPHeaderQueue {
+        queue_id: u32,
+        num_messages: u32,
+        new_sub_count: u32,
+        max_time: u32,
+        lock: u32,
+        subs: u64,
+        start: u32,
+        msg_timeout: u64,
+        position: u32,
+        messages_offset: u32,
+        timeout: [u64; LGMP_MAX_QUEUE_SUBS],
+        count: u32,
+    }
+
+    #[repr(C)]
+    struct LGMPHeader {
+        magic: u32,
+        version: u32,
+        session_id: u32,
+        timestamp: u64,
+        num_queues: u32,
+        queues: [LGMPHeaderQueue; LGMP_MAX_QUEUES],
+        udata_size: u32,
+        udata: [u8; 0],
+    }
+
+    #[test]
+    fn header_matches_upstream() {
+        assert_eq!(offset_of!(LGMPHeader, magic), MAGIC_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, version), VERSION_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, session_id), SESSION_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, timestamp), TIMESTAMP_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, num_queues), NUM_QUEUES_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, queues), QUEUES_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, udata_size), UDATA_SIZE_OFFSET);
+        assert_eq!(offset_of!(LGMPHeader, udata), HEADER_SIZE);
+        assert_eq!((UDATA_SIZE_OFFSET, HEADER_SIZE), (1632, 1636));
+    }
+
+    #[test]
+    fn queue_matches_upstream() {
+        assert_eq!(size_of::<LGMPHeaderQueue>(), QUEUE_SIZE);
+        assert_eq!(offset_of!(LGMPHeaderQueue, queue_id), QUEUE_ID);
+        assert_eq!(offset_of!(LGMPHeaderQueue, num_messages), QUEUE_NUM_MESSAGES);
+        assert_eq!(offset_of!(LGMPHeaderQueue, new_sub_count), QUEUE_NEW_SUB_COUNT);
+        assert_eq!(offset_of!(LGMPHeaderQueue, max_time), QUEUE_MAX_TIME);
+        assert_eq!(offset_of!(LGMPHeaderQueue, lock), QUEUE_LOCK);
+        assert_eq!(offset_of!(LGMPHeaderQueue, subs), QUEUE_SUBS);
+        assert_eq!(offset_of!(LGMPHeaderQueue, start), QUEUE_START);
+        assert_eq!(offset_of!(LGMPHeaderQueue, msg_timeout), QUEUE_MESSAGE_TIMEOUT);
+        assert_eq!(offset_of!(LGMPHeaderQueue, position), QUEUE_POSITION);
+        assert_eq!(offset_of!(LGMPHeaderQueue, messages_offset), QUEUE_MESSAGES_OFFSET);
+        assert_eq!(offset_of!(LGMPHeaderQueue, timeout), QUEUE_TIMEOUTS);
+        assert_eq!(offset_of!(LGMPHeaderQueue, count), QUEUE_COUNT);
+    }
+
+    #[test]
+    fn message_matches_upstream() {
+        assert_eq!(size_of::<LGMPHeaderMessage>(), MESSAGE_SIZE);
+        assert_eq!(offset_of!(LGMPHeaderMessage, udata), MESSAGE_UDATA);
+        assert_eq!(offset_of!(LGMPHeaderMessage, size), MESSAGE_SIZE_FIELD);
+        assert_eq!(offset_of!(LGMPHeaderMessage, offset), MESSAGE_OFFSET);
+        assert_eq!(offset_of!(LGMPHeaderMessage, pending_subs), MESSAGE_PENDING_SUBS);
+    }
+
+    /// A header as upstream's `lgmpHostInit` and `lgmpClientSubscribe` leave it, with queue 3 holding one
+    /// message. Subscriber 0 is live, subscriber 1 has timed out. Upstream keeps the subscribed bits in the upper
+    /// and the timed out bits in the lower 32 bits of `subs` (`LGMP_SUBS_ON`, `LGMP_SUBS_BAD`).
+    #[cfg(target_os = "linux")]
+    fn recorded(name: &str) -> crate::SharedMemory {
+        let shm = crate::SharedMemory::scratch(&format!("test-lgmp-{name}"), 64 * 1024).unwrap();
+        let mut memory = shm.map(1).unwrap();
+        let mut put = |offset: usize, bytes: &[u8]| memory[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(VERSION_OFFSET, &LGMP_PROTOCOL_VERSION.to_le_bytes());
+        put(NUM_QUEUES_OFFSET, &1u32.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_ID, &3u32.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_NUM_MESSAGES, &4u32.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_MAX_TIME, &1000u32.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_SUBS, &0x0000_0003_0000_0002u64.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_POSITION, &1u32.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_MESSAGES_OFFSET, &8192u32.to_le_bytes());
+        put(QUEUES_OFFSET + QUEUE_COUNT, &1u32.to_le_bytes());
+        // Message 0: user data 9, 2 bytes at offset 12288, pending for subscriber 0.
+        put(8192, &[9, 0, 0, 0, 2, 0, 0, 0, 0, 0x30, 0, 0, 1, 0, 0, 0]);
+        put(MAGIC_OFFSET, &LGMP_PROTOCOL_MAGIC.to_le_bytes());
+        shm
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn recorded_subscribers_are_read_from_the_upper_half() {
+        let shm = recorded("subs");
+        let mut device = shm.map(1).unwrap();
+        let mut client = LgmpClient::attach(&mut device).unwrap();
+
+        let mut live = LgmpSubscription { queue: 0, bit: 1, position: 0 };
+        let mut timed_out = LgmpSubscription { queue: 0, bit: 2, position: 0 };
+        assert_eq!(client.process(&mut live).unwrap().map(|message| message.udata), Some(9));
+        assert!(matches!(client.process(&mut timed_out), Err(LgmpError::TimedOut)));
+
+        // Both slots are taken, so a new subscriber gets the third one.
+        let subscription = client.subscribe(3).unwrap();
+        assert_eq!(subscription.id(), 2);
+        let subs = client.layout.queue_u64(0, QUEUE_SUBS).load(Ordering::Relaxed);
+        assert_eq!(subs, 0x0000_0007_0000_0002);
+
+        client.unsubscribe(timed_out).unwrap();
+        let subs = client.layout.queue_u64(0, QUEUE_SUBS).load(Ordering::Relaxed);
+        assert_eq!(subs, 0x0000_0005_0000_0000);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn host_marks_timed_out_subscribers_in_the_lower_half() {
+        let shm = recorded("timeout");
+        let mut host_side = shm.map(1).unwrap();
+        let mut client_side = shm.map(1).unwrap();
+        let mut host = LgmpHost::init(&mut host_side, b"").unwrap();
+        let queue = host.add_queue(3, 4, Duration::ZERO).unwrap();
+        let buffer = host.alloc(16).unwrap();
+        let mut client = LgmpClient::attach(&mut client_side).unwrap();
+        let mut subscription = client.subscribe(3).unwrap();
+        let subs = host.layout.queue_u64(0, QUEUE_SUBS).load(Ordering::Relaxed);
+        assert_eq!(subs, 1 << 32);
+
+        host.post(queue, 1, &buffer).unwrap();
+        host.process();
+        let subs = host.layout.queue_u64(0, QUEUE_SUBS).load(Ordering::Relaxed);
+        assert_eq!(subs, 1 << 32 | 1);
+        assert_eq!(host.subscriber_count(queue), 0);
+        assert!(matches!(client.process(&mut subscription), Err(LgmpError::TimedOut)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn client_rejects_corrupted_positions() {
+        let shm = recorded("client-corrupted");
+        let mut device = shm.map(1).unwrap();
+        let mut client = LgmpClient::attach(&mut device).unwrap();
+        let mut subscription = LgmpSubscription { queue: 0, bit: 1, position: 0 };
+
+        client.layout.queue_u32(0, QUEUE_POSITION).store(4, Ordering::Relaxed);
+        assert!(matches!(client.process(&mut subscription), Err(LgmpError::Corrupted)));
+        assert!(matches!(client.message_done(&mut subscription), Err(LgmpError::Corrupted)));
+        assert!(matches!(client.subscribe(3), Err(LgmpError::Corrupted)));
+
+        client.layout.queue_u32(0, QUEUE_POSITION).store(1, Ordering::Relaxed);
+        client.layout.queue_u32(0, QUEUE_NUM_MESSAGES).store(0, Ordering::Relaxed);
+        assert!(matches!(client.process(&mut subscription), Err(LgmpError::Corrupted)));
+        assert!(matches!(client.message_done(&mut subscription), Err(LgmpError::Corrupted)));
+        assert!(matches!(client.subscribe(3), Err(LgmpError::Corrupted)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn host_rejects_corrupted_positions() {
+        let shm = recorded("host-corrupted");
+        let mut device = shm.map(1).unwrap();
+        let mut host = LgmpHost::init(&mut device, b"").unwrap();
+        let queue = host.add_queue(3, 4, Duration::ZERO).unwrap();
+        let buffer = host.alloc(16).unwrap();
+        host.post(queue, 1, &buffer).unwrap();
+
+        host.layout.queue_u32(0, QUEUE_START).store(9, Ordering::Relaxed);
+        host.process();
+        assert_eq!(host.pending(queue), 1);
+
+        host.layout.queue_u32(0, QUEUE_POSITION).store(9, Ordering::Relaxed);
+        assert!(matches!(host.post(queue, 1, &buffer), Err(LgmpError::Corrupted)));
+        host.layout.queue_u32(0, QUEUE_POSITION).store(1, Ordering::Relaxed);
+        host.layout.queue_u32(0, QUEUE_NUM_MESSAGES).store(0, Ordering::Relaxed);
+        assert!(matches!(host.post(queue, 1, &buffer), Err(LgmpError::Corrupted)));
+        host.process();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn lock_of_a_dead_client_times_out() {
+        let shm = recorded("lock");
+        let mut device = shm.map(1).unwrap();
+        let mut client = LgmpClient::attach(&mut device).unwrap();
+        client.layout.queue_u32(0, QUEUE_LOCK).store(1, Ordering::Relaxed);
+
+        let start = Instant::now();
+        assert!(matches!(client.subscribe(3), Err(LgmpError::LockTimedOut)));
+        assert!(start.elapsed() >= LOCK_TIMEOUT);
+    }
+}
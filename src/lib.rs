@@ -1,10 +1,23 @@
-use anyhow::Result;
 use device::IvshmemDevice;
-use crate::error::UnixError;
+use crate::error::Error;
 
+pub mod backend;
 pub mod device;
 pub mod error;
-#[cfg(unix)]
+pub mod frame;
+mod header;
+pub mod kernel;
+pub mod kvmfr;
+pub mod lgmp;
+pub mod pod;
+pub mod pool;
+pub mod queue;
+pub mod region;
+pub mod registers;
+pub mod ring;
+pub mod seqlock;
+pub mod table;
+#[cfg(target_os = "linux")]
 mod linux;
 #[cfg(windows)]
 mod windows;
@@ -20,18 +33,18 @@ mod windows;
 ///
 /// # Examples
 ///
-/// ```
-/// let mut device = pick_windows_ivshmem_device(|mut dev| {
+/// ```no_run
+/// let mut device = ivshmemmap::pick_windows_ivshmem_device(|mut dev| {
 ///     // Do your comparison logic here. In this instance, we simply return the second Ivshmem device found on this computer.
 ///     dev.remove(1)
-/// }).unwrap();
+/// }, 4).unwrap();
 /// ```
 #[cfg(windows)]
-pub fn pick_windows_ivshmem_device<F>(picker: F, worker_threads: usize) -> Result<IvshmemDevice>
+pub fn pick_windows_ivshmem_device<F>(picker: F, worker_threads: usize) -> Result<IvshmemDevice, Error>
 where
     F: FnOnce(Vec<windows::IvshmemDescriptor>) -> windows::IvshmemDescriptor,
 {
-    windows::pick_ivshmem_device(picker, worker_threads)
+    Ok(windows::pick_ivshmem_device(picker, worker_threads)?)
 }
 
 ///
@@ -45,13 +58,75 @@ where
 ///
 /// # Examples
 ///
-/// ```
+/// ```no_run
 /// use std::path::PathBuf;
 /// use std::str::FromStr;
 ///
 /// let mut device = ivshmemmap::linux_ivshmem_device(&PathBuf::from_str("/dev/shm/shm-portal").unwrap(), 4).unwrap();
 /// ```
-#[cfg(unix)]
-pub fn linux_ivshmem_device(path: &std::path::Path, worker_threads: usize) -> Result<IvshmemDevice, UnixError> {
-    linux::ivshmem_device(path, worker_threads)
+///
+/// Errors tell why the device could not be opened, and never panic on unusual paths:
+///
+/// ```
+/// use ivshmemmap::error::{Error, UnixError};
+/// use std::ffi::OsStr;
+/// use std::os::unix::ffi::OsStrExt;
+/// use std::path::Path;
+///
+/// let not_utf8 = Path::new(OsStr::from_bytes(b"/nonexistent/\xff"));
+/// let error = ivshmemmap::linux_ivshmem_device(not_utf8, 1).unwrap_err();
+/// let Error::DeviceNotFound { device: Some(_), source: Some(source) } = error else { panic!("{error:?}") };
+/// assert!(matches!(source.downcast_ref(), Some(UnixError::OpenFailed { call: "open", .. })));
+///
+/// let error = ivshmemmap::linux_ivshmem_device(Path::new("/dev/shm/a\0b"), 1).unwrap_err();
+/// assert!(matches!(error, Error::Unix(UnixError::InvalidPath(_))));
+///
+/// let empty = std::env::temp_dir().join(format!("ivshmemmap-doc-empty-{}", std::process::id()));
+/// std::fs::File::create(&empty).unwrap();
+/// let error = ivshmemmap::linux_ivshmem_device(&empty, 1).unwrap_err();
+/// assert!(matches!(&error, Error::Unix(UnixError::EmptyFile { path: Some(path) }) if *path == empty));
+/// # std::fs::remove_file(&empty).unwrap();
+/// ```
+#[cfg(target_os = "linux")]
+pub fn linux_ivshmem_device(path: &std::path::Path, worker_threads: usize) -> Result<IvshmemDevice, Error> {
+    Ok(linux::ivshmem_device(path, worker_threads)?)
+}
+
+#[cfg(windows)]
+pub use windows::{IvshmemDescriptor, WindowsBackend};
+
+#[cfg(target_os = "linux")]
+pub use linux::client::{IvshmemClient, Peer, PeerEvent};
+#[cfg(target_os = "linux")]
+pub use linux::futex::{ShmCondvar, ShmMutex, ShmMutexGuard};
+#[cfg(target_os = "linux")]
+pub use linux::pci::{PciBackend, PciIvshmem, IVSHMEM_DEVICE_ID, IVSHMEM_VENDOR_ID};
+#[cfg(target_os = "linux")]
+pub use linux::server::IvshmemServer;
+#[cfg(target_os = "linux")]
+pub use linux::shm::{SharedMemory, SharedMemoryBuilder};
+
+///
+///
+/// # Arguments
+///
+/// * `socket_path`: Path to the UNIX socket of a running ivshmem-server.
+/// * `vectors`: Amount of interrupt vectors per peer. Must match the server configuration.
+/// * `worker_threads`: Amount of worker threads for copy operations.
+///
+/// returns: An initialized and usable IvshmemDevice, together with the client used to ring and wait on peers.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+///
+/// let (_device, client) = ivshmemmap::linux_ivshmem_client(Path::new("/tmp/ivshmem_socket"), 1, 4).unwrap();
+/// for peer in client.peers() {
+///     client.ring(peer.id(), 0).unwrap();
+/// }
+/// ```
+#[cfg(target_os = "linux")]
+pub fn linux_ivshmem_client(socket_path: &std::path::Path, vectors: usize, worker_threads: usize) -> Result<(IvshmemDevice, IvshmemClient), Error> {
+    Ok(IvshmemClient::connect(socket_path, vectors, worker_threads)?)
 }
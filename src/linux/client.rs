@@ -0,0 +1,435 @@
+use crate::device::IvshmemDevice;
+use crate::error::UnixError;
+use crate::linux::protocol::{recv_message, IVSHMEM_PROTOCOL_VERSION};
+use crate::linux::UnixMemoryMap;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// Change in the peer table, as announced by the ivshmem-server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// A vector of a (possibly new) peer became available.
+    Joined { peer: u16, vector: usize },
+    /// The peer disconnected from the server. All of its vectors are closed.
+    Left { peer: u16 },
+}
+
+/// Another client of the same ivshmem-server, and the eventfds used to ring its doorbell.
+pub struct Peer {
+    id: u16,
+    vectors: Vec<OwnedFd>,
+}
+
+impl Peer {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Amount of interrupt vectors this peer announced so far.
+    pub fn vector_count(&self) -> usize {
+        self.vectors.len()
+    }
+}
+
+impl Debug for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer{{ id: {:?} vectors: {:?} }}", self.id, self.vectors.len())
+    }
+}
+
+/// Connection to a QEMU compatible ivshmem-server.
+///
+/// Holds our own peer ID and interrupt vectors, as well as the doorbells of all other peers.
+/// The peer table is only updated when calling `process_events` or `wait_event`.
+pub struct IvshmemClient {
+    socket: UnixStream,
+    id: u16,
+    vectors: Vec<OwnedFd>,
+    peers: BTreeMap<u16, Peer>,
+    /// An error that followed events which were already applied, reported by the next call.
+    deferred_error: Option<UnixError>,
+}
+
+impl Debug for IvshmemClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "IvshmemClient{{ id: {:?} vectors: {:?} peers: {:?} }}",
+            self.id,
+            self.vectors.len(),
+            self.peers.values().collect::<Vec<_>>()
+        )
+    }
+}
+
+impl IvshmemClient {
+    /// Connects to the ivshmem-server listening on `path`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: Path of the UNIX socket of the server.
+    /// * `vectors`: Amount of interrupt vectors the server hands out per peer. Must match the `-n` option of the server.
+    /// * `worker_threads`: Amount of worker threads for copy operations.
+    ///
+    /// returns: The shared memory and the connection used for doorbells.
+    pub fn connect(path: &Path, vectors: usize, worker_threads: usize) -> Result<(IvshmemDevice, Self), UnixError> {
+        let socket = UnixStream::connect(path).map_err(|source| UnixError::ConnectFailed {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::handshake(socket, vectors, worker_threads)
+    }
+
+    /// Performs the handshake over an already connected socket.
+    ///
+    /// The server first sends the protocol version, then our own peer ID and the shared memory file descriptor.
+    /// Afterwards it announces the vectors of every existing peer, followed by our own vectors.
+    pub fn handshake(socket: UnixStream, vectors: usize, worker_threads: usize) -> Result<(IvshmemDevice, Self), UnixError> {
+        let fd = socket.as_raw_fd();
+
+        let (version, _) = Self::expect_message(fd)?;
+        if version != IVSHMEM_PROTOCOL_VERSION {
+            return Err(UnixError::UnsupportedProtocol(version));
+        }
+
+        let (id, _) = Self::expect_message(fd)?;
+        let id = u16::try_from(id).map_err(|_| UnixError::ProtocolViolation("peer ID out of range"))?;
+
+        let shm = match Self::expect_message(fd)? {
+            (-1, Some(shm)) => shm,
+            _ => return Err(UnixError::ProtocolViolation("expected shared memory file descriptor")),
+        };
+        let memory_map = UnixMemoryMap::from_fd(shm.as_raw_fd(), None)?;
+        drop(shm);
+
+        let mut zelf = Self {
+            socket,
+            id,
+            vectors: Vec::with_capacity(vectors),
+            peers: BTreeMap::new(),
+            deferred_error: None,
+        };
+
+        while zelf.vectors.len() < vectors {
+            let (peer, fd) = Self::expect_message(fd)?;
+            zelf.handle_message(peer, fd)?;
+        }
+
+        Ok((memory_map.into_device(worker_threads), zelf))
+    }
+
+    fn expect_message(socket: RawFd) -> Result<(i64, Option<OwnedFd>), UnixError> {
+        Self::receive(socket, true)?.ok_or(UnixError::ProtocolViolation("no message received"))
+    }
+
+    fn receive(socket: RawFd, blocking: bool) -> Result<Option<(i64, Option<OwnedFd>)>, UnixError> {
+        recv_message(socket, blocking).map_err(|source| match source.kind() {
+            std::io::ErrorKind::InvalidData => UnixError::ProtocolViolation("truncated message"),
+            _ => UnixError::Disconnected { source },
+        })
+    }
+
+    fn handle_message(&mut self, peer: i64, fd: Option<OwnedFd>) -> Result<PeerEvent, UnixError> {
+        let peer = u16::try_from(peer).map_err(|_| UnixError::ProtocolViolation("peer ID out of range"))?;
+        match fd {
+            Some(fd) if peer == self.id => {
+                // QEMU's server creates non-blocking eventfds, but other servers might not. Enforce it, so reads never hang.
+                unsafe {
+                    let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+                    libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+                }
+                self.vectors.push(fd);
+                Ok(PeerEvent::Joined { peer, vector: self.vectors.len() - 1 })
+            }
+            Some(fd) => {
+                let entry = self.peers.entry(peer).or_insert_with(|| Peer { id: peer, vectors: Vec::new() });
+                entry.vectors.push(fd);
+                Ok(PeerEvent::Joined { peer, vector: entry.vectors.len() - 1 })
+            }
+            None if peer == self.id => Err(UnixError::ProtocolViolation("own vector without file descriptor")),
+            None => {
+                self.peers.remove(&peer);
+                Ok(PeerEvent::Left { peer })
+            }
+        }
+    }
+
+    /// Applies all pending peer notifications without blocking.
+    ///
+    /// returns: The changes that were applied to the peer table, in the order the server sent them. If a message
+    /// fails after others were applied, those are returned and the error is returned by the next call.
+    pub fn process_events(&mut self) -> Result<Vec<PeerEvent>, UnixError> {
+        if let Some(error) = self.deferred_error.take() {
+            return Err(error);
+        }
+        let mut events = Vec::new();
+        loop {
+            let event = Self::receive(self.socket.as_raw_fd(), false)
+                .and_then(|message| message.map(|(peer, fd)| self.handle_message(peer, fd)).transpose());
+            match event {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => return Ok(events),
+                Err(error) if events.is_empty() => return Err(error),
+                Err(error) => {
+                    self.deferred_error = Some(error);
+                    return Ok(events);
+                }
+            }
+        }
+    }
+
+    /// Blocks until the server sends the next peer notification and applies it.
+    pub fn wait_event(&mut self) -> Result<PeerEvent, UnixError> {
+        if let Some(error) = self.deferred_error.take() {
+            return Err(error);
+        }
+        let (peer, fd) = Self::expect_message(self.socket.as_raw_fd())?;
+        self.handle_message(peer, fd)
+    }
+
+    /// Our own peer ID. This is the value a guest reads from the IVPosition register.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Amount of interrupt vectors we received from the server.
+    pub fn vector_count(&self) -> usize {
+        self.vectors.len()
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.values()
+    }
+
+    pub fn peer(&self, id: u16) -> Option<&Peer> {
+        self.peers.get(&id)
+    }
+
+    /// Rings the doorbell of `peer`, raising interrupt `vector` on its side.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer`: The ID of the peer. Using our own ID interrupts ourselves.
+    /// * `vector`: The interrupt vector to trigger.
+    pub fn ring(&self, peer: u16, vector: usize) -> Result<(), UnixError> {
+        let vectors = if peer == self.id {
+            &self.vectors
+        } else {
+            &self.peers.get(&peer).ok_or(UnixError::UnknownPeer(peer))?.vectors
+        };
+        let fd = vectors.get(vector).ok_or(UnixError::InvalidVector(vector))?;
+        let value = 1u64;
+        let written = unsafe {
+            libc::write(fd.as_raw_fd(), &value as *const u64 as *const libc::c_void, std::mem::size_of::<u64>())
+        };
+        if written != std::mem::size_of::<u64>() as isize {
+            return Err(UnixError::DoorbellFailed {
+                vector,
+                source: std::io::Error::last_os_error(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Blocks until interrupt `vector` is raised by any peer.
+    ///
+    /// returns: The amount of times the doorbell was rung since the last wait.
+    pub fn wait(&self, vector: usize) -> Result<u64, UnixError> {
+        loop {
+            if let Some(count) = self.try_wait(vector)? {
+                return Ok(count);
+            }
+            let mut poll_fd = libc::pollfd {
+                fd: self.vectors[vector].as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut poll_fd, 1, -1) } == -1 {
+                let source = std::io::Error::last_os_error();
+                if source.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(UnixError::PollFailed { source });
+                }
+            }
+        }
+    }
+
+    /// Consumes a pending interrupt on `vector` without blocking.
+    ///
+    /// returns: The amount of times the doorbell was rung since the last wait, or `None` if it was not rung.
+    pub fn try_wait(&self, vector: usize) -> Result<Option<u64>, UnixError> {
+        let fd = self.vectors.get(vector).ok_or(UnixError::InvalidVector(vector))?;
+        let mut value = 0u64;
+        let read = unsafe {
+            libc::read(fd.as_raw_fd(), &mut value as *mut u64 as *mut libc::c_void, std::mem::size_of::<u64>())
+        };
+        if read == std::mem::size_of::<u64>() as isize {
+            return Ok(Some(value));
+        }
+        let source = std::io::Error::last_os_error();
+        match source.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => Ok(None),
+            _ => Err(UnixError::DoorbellFailed { vector, source }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linux::protocol::send_message;
+    use std::io::Write;
+    use std::os::fd::AsFd;
+    use std::time::Duration;
+
+    /// A connected pair of sockets, the client side of which never blocks for long, so a hanging client fails the test.
+    fn connection() -> (UnixStream, UnixStream) {
+        let (client, server) = UnixStream::pair().unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    fn send(server: &UnixStream, value: i64, fd: Option<&std::fs::File>) {
+        send_message(server.as_raw_fd(), value, fd.map(|fd| fd.as_fd().as_raw_fd())).unwrap();
+    }
+
+    fn shared_memory(name: &str) -> std::fs::File {
+        let path = std::env::temp_dir().join(format!("ivshmemmap-test-{name}-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(4096).unwrap();
+        file
+    }
+
+    fn handshake(client: UnixStream, vectors: usize) -> Result<IvshmemClient, UnixError> {
+        IvshmemClient::handshake(client, vectors, 1).map(|(_, client)| client)
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let (client, server) = connection();
+        send(&server, 1, None);
+        assert!(matches!(handshake(client, 1), Err(UnixError::UnsupportedProtocol(1))));
+    }
+
+    #[test]
+    fn rejects_peer_id_out_of_range() {
+        let (client, server) = connection();
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        send(&server, -2, None);
+        assert!(matches!(handshake(client, 1), Err(UnixError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_message() {
+        let (client, mut server) = connection();
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        server.write_all(&[0; 4]).unwrap();
+        drop(server);
+        assert!(matches!(handshake(client, 1), Err(UnixError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn rejects_missing_shared_memory_descriptor() {
+        let (client, server) = connection();
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        send(&server, 3, None);
+        send(&server, -1, None);
+        assert!(matches!(handshake(client, 1), Err(UnixError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn rejects_own_vector_without_descriptor() {
+        let (client, server) = connection();
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        send(&server, 3, None);
+        send(&server, -1, Some(&shared_memory("own-vector")));
+        send(&server, 3, None);
+        assert!(matches!(handshake(client, 1), Err(UnixError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn reports_server_hanging_up_mid_handshake() {
+        let (client, server) = connection();
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        drop(server);
+        assert!(matches!(handshake(client, 1), Err(UnixError::Disconnected { .. })));
+    }
+
+    #[test]
+    fn process_events_rejects_malformed_messages() {
+        let (client, mut server) = connection();
+        let vector = shared_memory("vector");
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        send(&server, 3, None);
+        send(&server, -1, Some(&shared_memory("events")));
+        send(&server, 3, Some(&vector));
+        let mut client = handshake(client, 1).unwrap();
+
+        send(&server, 4, Some(&vector));
+        assert_eq!(client.process_events().unwrap(), vec![PeerEvent::Joined { peer: 4, vector: 0 }]);
+        assert_eq!(client.process_events().unwrap(), vec![]);
+
+        send(&server, 70000, Some(&vector));
+        assert!(matches!(client.process_events(), Err(UnixError::ProtocolViolation(_))));
+        send(&server, 3, None);
+        assert!(matches!(client.process_events(), Err(UnixError::ProtocolViolation(_))));
+
+        // Events applied before a malformed message are not lost. The error follows on the next call.
+        send(&server, 5, Some(&vector));
+        send(&server, 4, None);
+        send(&server, 3, None);
+        send(&server, 6, Some(&vector));
+        let events = client.process_events().unwrap();
+        assert_eq!(events, vec![PeerEvent::Joined { peer: 5, vector: 0 }, PeerEvent::Left { peer: 4 }]);
+        assert_eq!(client.peers().map(Peer::id).collect::<Vec<_>>(), vec![5]);
+        assert!(matches!(client.process_events(), Err(UnixError::ProtocolViolation(_))));
+        assert_eq!(client.process_events().unwrap(), vec![PeerEvent::Joined { peer: 6, vector: 0 }]);
+
+        server.write_all(&[0; 4]).unwrap();
+        drop(server);
+        assert!(matches!(client.process_events(), Err(UnixError::ProtocolViolation(_))));
+    }
+
+    fn eventfd() -> std::fs::File {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert_ne!(fd, -1);
+        unsafe { std::os::fd::FromRawFd::from_raw_fd(fd) }
+    }
+
+    #[test]
+    fn rings_and_waits_on_eventfds() {
+        let (client, server) = connection();
+        let own = [eventfd(), eventfd()];
+        let peer = [eventfd(), eventfd()];
+        send(&server, IVSHMEM_PROTOCOL_VERSION, None);
+        send(&server, 3, None);
+        send(&server, -1, Some(&shared_memory("doorbells")));
+        send(&server, 4, Some(&peer[0]));
+        send(&server, 4, Some(&peer[1]));
+        send(&server, 3, Some(&own[0]));
+        send(&server, 3, Some(&own[1]));
+        let client = handshake(client, 2).unwrap();
+
+        // Our own vectors, as rung by another peer, or by ourselves.
+        assert_eq!(client.try_wait(1).unwrap(), None);
+        (&own[1]).write_all(&2u64.to_ne_bytes()).unwrap();
+        client.ring(3, 1).unwrap();
+        assert_eq!(client.wait(1).unwrap(), 3);
+        assert_eq!(client.try_wait(1).unwrap(), None);
+        assert_eq!(client.try_wait(0).unwrap(), None);
+
+        // The vectors of another peer.
+        client.ring(4, 0).unwrap();
+        client.ring(4, 0).unwrap();
+        let mut count = [0u8; 8];
+        std::io::Read::read_exact(&mut &peer[0], &mut count).unwrap();
+        assert_eq!(u64::from_ne_bytes(count), 2);
+
+        assert!(matches!(client.ring(4, 2), Err(UnixError::InvalidVector(2))));
+        assert!(matches!(client.ring(5, 0), Err(UnixError::UnknownPeer(5))));
+        assert!(matches!(client.wait(2), Err(UnixError::InvalidVector(2))));
+    }
+}
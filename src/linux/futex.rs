@@ -0,0 +1,365 @@
+use crate::device::check_bounds;
+use crate::error::DeviceError;
+use crate::region::Region;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{LockResult, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The lower bits of a robust futex hold the thread id of the owner. (`FUTEX_TID_MASK`)
+const TID_MASK: u32 = 0x3FFF_FFFF;
+/// Values of the word in front of the `pthread_mutex_t`, which tracks who initializes it.
+const UNINITIALIZED: u32 = 0;
+const INITIALIZING: u32 = 1;
+const INITIALIZED: u32 = 2;
+/// Offset of the `pthread_mutex_t` in the memory of a `ShmMutex`.
+const MUTEX_OFFSET: usize = 8;
+
+const _: () = assert!(MUTEX_OFFSET + std::mem::size_of::<libc::pthread_mutex_t>() <= ShmMutex::SIZE);
+
+/// Sleeps while `word` contains `expected`, at most for `timeout`. Wakeups may be spurious.
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timespec = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    unsafe {
+        // Not FUTEX_PRIVATE_FLAG: the waiters may live in other processes that map the same memory.
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            timespec.as_ref().map_or(std::ptr::null(), |timespec| timespec as *const libc::timespec),
+        );
+    }
+}
+
+fn futex_wake(word: &AtomicU32, count: i32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, count);
+    }
+}
+
+/// Panics if a pthread call that cannot fail for a process shared robust mutex did.
+fn check(call: &str, result: libc::c_int) {
+    if result != 0 {
+        panic!("{call} failed: {}", std::io::Error::from_raw_os_error(result));
+    }
+}
+
+/// A mutex in shared memory that synchronizes processes mapping the same memory on one Linux host.
+///
+/// The mutex is a process shared, robust `pthread_mutex_t`. Its futex word holds the thread id of the owner, and
+/// glibc registers the locks a thread holds with the kernel through `set_robust_list`. When the owner dies, the
+/// kernel marks the word with `FUTEX_OWNER_DIED` and wakes a waiter, which takes over the lock and reports this
+/// through a `PoisonError`, as the protected data may be inconsistent.
+///
+/// # Examples
+///
+/// ```
+/// use ivshmemmap::ShmMutex;
+///
+/// let shm = ivshmemmap::SharedMemory::scratch("doc-mutex", 4096).unwrap();
+/// let mut devices: Vec<_> = (0..4).map(|_| shm.map(1).unwrap()).collect();
+///
+/// std::thread::scope(|scope| {
+///     for device in &mut devices {
+///         scope.spawn(move || {
+///             let (header, mut data) = device.as_region_mut().split_at_mut(ShmMutex::SIZE).unwrap();
+///             let mutex = ShmMutex::new(&header.as_region()).unwrap();
+///             for _ in 0..1000 {
+///                 let _guard = mutex.lock().unwrap();
+///                 // A non-atomic read-modify-write, protected by the mutex.
+///                 let counter = u32::from_ne_bytes(data[..4].try_into().unwrap());
+///                 data[..4].copy_from_slice(&(counter + 1).to_ne_bytes());
+///             }
+///         });
+///     }
+/// });
+/// assert_eq!(devices[0].read_volatile::<u32>(ShmMutex::SIZE).unwrap(), 4000);
+///
+/// // A thread that exits while holding the lock does not block the others forever.
+/// let device = &devices[0];
+/// std::thread::scope(|scope| {
+///     scope.spawn(|| std::mem::forget(ShmMutex::new(&device.as_region()).unwrap().lock()));
+/// });
+/// assert!(ShmMutex::new(&device.as_region()).unwrap().lock().is_err());
+/// ```
+pub struct ShmMutex<'a> {
+    mutex: *mut libc::pthread_mutex_t,
+    _memory: PhantomData<&'a AtomicU32>,
+}
+
+// A pthread_mutex_t may be locked and unlocked from any thread.
+unsafe impl Send for ShmMutex<'_> {}
+unsafe impl Sync for ShmMutex<'_> {}
+
+impl Debug for ShmMutex<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShmMutex{{ owner: {:?} }}", self.owner())
+    }
+}
+
+impl<'a> ShmMutex<'a> {
+    /// Bytes of shared memory occupied by a mutex.
+    pub const SIZE: usize = 64;
+
+    /// Uses the first `SIZE` bytes of `memory` as the mutex. Zeroed memory is an unlocked mutex, which the first
+    /// user initializes.
+    ///
+    /// returns: An error if `memory` is smaller than `SIZE` bytes or not aligned to 8 bytes.
+    pub fn new(memory: &Region<'a>) -> Result<Self, DeviceError> {
+        check_bounds(0, Self::SIZE, memory.len())?;
+        let state = memory.atomic_u32(0)?;
+        if memory.as_ptr().align_offset(8) != 0 {
+            return Err(DeviceError::Misaligned {
+                offset: memory.offset(),
+                alignment: 8,
+            });
+        }
+        let mutex = unsafe { memory.as_ptr().add(MUTEX_OFFSET) } as *mut libc::pthread_mutex_t;
+
+        loop {
+            match state.compare_exchange(UNINITIALIZED, INITIALIZING, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => unsafe {
+                    let mut attributes = std::mem::zeroed::<libc::pthread_mutexattr_t>();
+                    check("pthread_mutexattr_init", libc::pthread_mutexattr_init(&mut attributes));
+                    check(
+                        "pthread_mutexattr_setpshared",
+                        libc::pthread_mutexattr_setpshared(&mut attributes, libc::PTHREAD_PROCESS_SHARED),
+                    );
+                    check(
+                        "pthread_mutexattr_setrobust",
+                        libc::pthread_mutexattr_setrobust(&mut attributes, libc::PTHREAD_MUTEX_ROBUST),
+                    );
+                    // Relocking from the owning thread fails instead of deadlocking.
+                    check(
+                        "pthread_mutexattr_settype",
+                        libc::pthread_mutexattr_settype(&mut attributes, libc::PTHREAD_MUTEX_ERRORCHECK),
+                    );
+                    check("pthread_mutex_init", libc::pthread_mutex_init(mutex, &attributes));
+                    libc::pthread_mutexattr_destroy(&mut attributes);
+                    state.store(INITIALIZED, Ordering::Release);
+                    break;
+                },
+                Err(INITIALIZED) => break,
+                Err(_) => std::thread::yield_now(),
+            }
+        }
+        Ok(Self {
+            mutex,
+            _memory: PhantomData,
+        })
+    }
+
+    /// The thread id of the thread holding the lock.
+    pub fn owner(&self) -> Option<u32> {
+        // The futex word is the first member of a pthread_mutex_t, as the kernel's robust list handling requires.
+        let word = unsafe { AtomicU32::from_ptr(self.mutex as *mut u32) };
+        match word.load(Ordering::Relaxed) & TID_MASK {
+            0 => None,
+            tid => Some(tid),
+        }
+    }
+
+    /// Blocks until the lock is acquired.
+    ///
+    /// returns: A `PoisonError` containing the guard if the previous owner died while holding the lock.
+    pub fn lock(&self) -> LockResult<ShmMutexGuard<'_>> {
+        self.lock_until(None).expect("Locking without a deadline cannot time out")
+    }
+
+    /// Acquires the lock if it is not held.
+    ///
+    /// returns: `None` if another thread holds the lock.
+    pub fn try_lock(&self) -> Option<LockResult<ShmMutexGuard<'_>>> {
+        self.lock_until(Some(Instant::now()))
+    }
+
+    /// Blocks until the lock is acquired, or until `timeout` elapsed.
+    ///
+    /// returns: `None` if the timeout elapsed.
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<LockResult<ShmMutexGuard<'_>>> {
+        self.lock_until(Some(Instant::now() + timeout))
+    }
+
+    fn lock_until(&self, deadline: Option<Instant>) -> Option<LockResult<ShmMutexGuard<'_>>> {
+        let result = match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+            None => unsafe { libc::pthread_mutex_lock(self.mutex) },
+            Some(Duration::ZERO) => unsafe { libc::pthread_mutex_trylock(self.mutex) },
+            Some(remaining) => {
+                // pthread_mutex_timedlock takes a CLOCK_REALTIME deadline.
+                let deadline = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() + remaining;
+                let timespec = libc::timespec {
+                    tv_sec: deadline.as_secs() as libc::time_t,
+                    tv_nsec: deadline.subsec_nanos() as libc::c_long,
+                };
+                unsafe { libc::pthread_mutex_timedlock(self.mutex, &timespec) }
+            }
+        };
+        match result {
+            0 => Some(Ok(self.guard())),
+            libc::EOWNERDEAD => {
+                // The lock itself remains usable. Only this caller learns that the data may be inconsistent.
+                check("pthread_mutex_consistent", unsafe { libc::pthread_mutex_consistent(self.mutex) });
+                Some(Err(PoisonError::new(self.guard())))
+            }
+            libc::EBUSY | libc::ETIMEDOUT => None,
+            error => panic!("Unable to lock ShmMutex: {}", std::io::Error::from_raw_os_error(error)),
+        }
+    }
+
+    fn guard(&self) -> ShmMutexGuard<'_> {
+        ShmMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    fn unlock(&self) {
+        unsafe { libc::pthread_mutex_unlock(self.mutex) };
+    }
+}
+
+/// Releases the `ShmMutex` when dropped.
+pub struct ShmMutexGuard<'a> {
+    mutex: &'a ShmMutex<'a>,
+    /// The lock is owned by the locking thread, so the guard has to stay on it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Debug for ShmMutexGuard<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShmMutexGuard{{ mutex: {:?} }}", self.mutex)
+    }
+}
+
+impl Drop for ShmMutexGuard<'_> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable in shared memory, used together with a `ShmMutex`.
+///
+/// The condition variable is a single `u32` counting notifications. Zeroed memory is a valid condition variable.
+///
+/// # Examples
+///
+/// ```
+/// use ivshmemmap::{ShmCondvar, ShmMutex};
+///
+/// let shm = ivshmemmap::SharedMemory::scratch("doc-condvar", 4096).unwrap();
+/// let waiter = shm.map(1).unwrap();
+/// let notifier = shm.map(1).unwrap();
+///
+/// std::thread::scope(|scope| {
+///     scope.spawn(|| {
+///         let mutex = ShmMutex::new(&notifier.as_region()).unwrap();
+///         let _guard = mutex.lock().unwrap();
+///         notifier.atomic_u32(ShmMutex::SIZE + 4).unwrap().store(1, std::sync::atomic::Ordering::Relaxed);
+///         ShmCondvar::new(notifier.atomic_u32(ShmMutex::SIZE).unwrap()).notify_all();
+///     });
+///
+///     let mutex = ShmMutex::new(&waiter.as_region()).unwrap();
+///     let condvar = ShmCondvar::new(waiter.atomic_u32(ShmMutex::SIZE).unwrap());
+///     let ready = waiter.atomic_u32(ShmMutex::SIZE + 4).unwrap();
+///     let mut guard = mutex.lock().unwrap();
+///     while ready.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+///         guard = condvar.wait(guard).unwrap();
+///     }
+/// });
+/// ```
+pub struct ShmCondvar<'a> {
+    sequence: &'a AtomicU32,
+}
+
+impl Debug for ShmCondvar<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShmCondvar{{ sequence: {:?} }}", self.sequence.load(Ordering::Relaxed))
+    }
+}
+
+impl<'a> ShmCondvar<'a> {
+    /// Uses `sequence` as the condition variable.
+    pub fn new(sequence: &'a AtomicU32) -> Self {
+        Self { sequence }
+    }
+
+    /// Releases the lock of `guard`, blocks until notified, and locks it again. Wakeups may be spurious.
+    pub fn wait<'m>(&self, guard: ShmMutexGuard<'m>) -> LockResult<ShmMutexGuard<'m>> {
+        self.wait_timeout(guard, None).map(|(guard, _)| guard).map_err(|error| {
+            let (guard, _) = error.into_inner();
+            PoisonError::new(guard)
+        })
+    }
+
+    /// Like `wait`, but gives up after `timeout`.
+    ///
+    /// returns: The guard, and whether the timeout elapsed.
+    pub fn wait_timeout<'m>(&self, guard: ShmMutexGuard<'m>, timeout: Option<Duration>) -> LockResult<(ShmMutexGuard<'m>, bool)> {
+        let mutex = guard.mutex;
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        drop(guard);
+
+        let start = Instant::now();
+        futex_wait(self.sequence, sequence, timeout);
+        let timed_out = timeout.is_some_and(|timeout| start.elapsed() >= timeout);
+        match mutex.lock() {
+            Ok(guard) => Ok((guard, timed_out)),
+            Err(error) => Err(PoisonError::new((error.into_inner(), timed_out))),
+        }
+    }
+
+    /// Wakes up one waiting thread, possibly in another process.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        futex_wake(self.sequence, 1);
+    }
+
+    /// Wakes up all waiting threads, possibly in other processes.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        futex_wake(self.sequence, i32::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owner_killed_in_another_process_is_detected() {
+        let shm = crate::SharedMemory::scratch("test-mutex", 4096).unwrap();
+        let device = shm.map(1).unwrap();
+        let region = device.as_region();
+        let mutex = ShmMutex::new(&region).unwrap();
+
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            // The child dies holding the lock, without unwinding or unlocking anything.
+            std::mem::forget(mutex.lock());
+            std::thread::sleep(Duration::from_millis(100));
+            unsafe { libc::kill(libc::getpid(), libc::SIGKILL) };
+        }
+        assert!(child > 0);
+        let start = Instant::now();
+        while mutex.owner() != Some(child as u32) {
+            assert!(start.elapsed() < Duration::from_secs(5), "the child never took the lock");
+            std::thread::yield_now();
+        }
+
+        // Blocks until the kernel releases the lock of the dead child.
+        let guard = mutex.lock().unwrap_err().into_inner();
+        assert_eq!(mutex.owner(), Some(unsafe { libc::syscall(libc::SYS_gettid) } as u32));
+        drop(guard);
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFSIGNALED(status));
+
+        assert!(mutex.lock().is_ok());
+        assert!(mutex.try_lock().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,361 @@
+use crate::error::UnixError;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// `_IO(VFIO_TYPE, VFIO_BASE + nr)` from linux/vfio.h.
+const fn vfio_request(nr: u64) -> u64 {
+    ((b';' as u64) << 8) | (100 + nr)
+}
+
+const VFIO_GET_API_VERSION: u64 = vfio_request(0);
+const VFIO_CHECK_EXTENSION: u64 = vfio_request(1);
+const VFIO_SET_IOMMU: u64 = vfio_request(2);
+const VFIO_GROUP_GET_STATUS: u64 = vfio_request(3);
+const VFIO_GROUP_SET_CONTAINER: u64 = vfio_request(4);
+const VFIO_GROUP_GET_DEVICE_FD: u64 = vfio_request(6);
+const VFIO_DEVICE_GET_IRQ_INFO: u64 = vfio_request(9);
+const VFIO_DEVICE_SET_IRQS: u64 = vfio_request(10);
+
+const VFIO_API_VERSION: libc::c_int = 0;
+const VFIO_TYPE1_IOMMU: libc::c_ulong = 1;
+const VFIO_TYPE1V2_IOMMU: libc::c_ulong = 3;
+const VFIO_NOIOMMU_IOMMU: libc::c_ulong = 8;
+const VFIO_GROUP_FLAGS_VIABLE: u32 = 1 << 0;
+const VFIO_PCI_MSIX_IRQ_INDEX: u32 = 2;
+const VFIO_IRQ_SET_DATA_EVENTFD: u32 = 1 << 2;
+const VFIO_IRQ_SET_ACTION_TRIGGER: u32 = 1 << 5;
+
+/// Offset of the command register in the PCI configuration space, and its bus master bit.
+const PCI_COMMAND: u64 = 0x04;
+const PCI_COMMAND_MASTER: u16 = 0x04;
+
+#[repr(C)]
+struct VfioGroupStatus {
+    argsz: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct VfioIrqInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    count: u32,
+}
+
+fn open(path: &Path, flags: libc::c_int) -> Result<OwnedFd, UnixError> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| UnixError::InvalidPath(path.to_path_buf()))?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), flags) };
+    if fd == -1 {
+        return Err(UnixError::OpenFailed {
+            call: "open",
+            path: path.to_path_buf(),
+            flags,
+            mode: 0,
+            source: io::Error::last_os_error(),
+        });
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Issues an ioctl and turns a negative result into an error.
+fn ioctl(fd: RawFd, request: u64, name: &'static str, argument: *mut libc::c_void) -> Result<libc::c_int, UnixError> {
+    let result = unsafe { libc::ioctl(fd, request as _, argument) };
+    if result < 0 {
+        return Err(UnixError::IoctlFailed {
+            request: name,
+            source: io::Error::last_os_error(),
+        });
+    }
+    Ok(result)
+}
+
+/// Interrupts of a PCI device, delivered through whichever userspace driver it is bound to.
+pub(crate) enum Interrupts {
+    /// No supported driver is bound.
+    None,
+    /// `uio_pci_generic` or `uio_ivshmem`, which deliver a single interrupt through `/dev/uioN`.
+    Uio {
+        device: File,
+        /// Whether the driver masks the interrupt after it fired, so it has to be re-enabled before waiting.
+        irq_control: bool,
+        /// The interrupt count at the previous wakeup.
+        last_count: Mutex<Option<u32>>,
+    },
+    /// `vfio-pci`, which signals every MSI-X vector through its own eventfd.
+    Vfio {
+        vectors: Vec<OwnedFd>,
+        // Closing any of these tears down the interrupt routing, so they are kept until the eventfds are dropped.
+        _device: OwnedFd,
+        _group: OwnedFd,
+        _container: OwnedFd,
+    },
+}
+
+impl Interrupts {
+    /// Sets up the interrupts of the device at `path` according to its driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The sysfs directory of the device.
+    /// * `address`: The PCI address of the device.
+    pub fn open(path: &Path, address: &str) -> Result<Self, UnixError> {
+        let driver = std::fs::read_link(path.join("driver"))
+            .ok()
+            .and_then(|driver| driver.file_name().map(|name| name.to_string_lossy().into_owned()));
+        match driver.as_deref() {
+            Some("uio_pci_generic" | "uio_ivshmem") => Self::open_uio(path),
+            Some("vfio-pci") => Self::open_vfio(path, address),
+            _ => Ok(Interrupts::None),
+        }
+    }
+
+    fn open_uio(path: &Path) -> Result<Self, UnixError> {
+        let uio_path = path.join("uio");
+        let name = std::fs::read_dir(&uio_path)
+            .and_then(|mut entries| entries.next().unwrap_or_else(|| Err(io::ErrorKind::NotFound.into())))
+            .map_err(|source| UnixError::ReadFailed { path: uio_path, source })?
+            .file_name();
+        let node = Path::new("/dev").join(name);
+        let mut device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&node)
+            .map_err(|source| UnixError::OpenFailed {
+                call: "open",
+                path: node,
+                flags: libc::O_RDWR,
+                mode: 0,
+                source,
+            })?;
+        // Drivers without interrupt control reject the write. Their interrupts never need to be re-enabled.
+        let irq_control = device.write_all(&1u32.to_ne_bytes()).is_ok();
+        Ok(Interrupts::Uio {
+            device,
+            irq_control,
+            last_count: Mutex::new(None),
+        })
+    }
+
+    fn open_vfio(path: &Path, address: &str) -> Result<Self, UnixError> {
+        let group_link = path.join("iommu_group");
+        let group_id = std::fs::read_link(&group_link)
+            .map_err(|source| UnixError::ReadFailed {
+                path: group_link,
+                source,
+            })?
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let container = open(Path::new("/dev/vfio/vfio"), libc::O_RDWR | libc::O_CLOEXEC)?;
+        let version = ioctl(container.as_raw_fd(), VFIO_GET_API_VERSION, "VFIO_GET_API_VERSION", std::ptr::null_mut())?;
+        if version != VFIO_API_VERSION {
+            return Err(UnixError::UnsupportedProtocol(version as i64));
+        }
+
+        // Guests without a virtual IOMMU can only use VFIO in no-IOMMU mode, which names the groups differently.
+        let (group, iommu) = match open(&PathBuf::from(format!("/dev/vfio/{group_id}")), libc::O_RDWR | libc::O_CLOEXEC) {
+            Ok(group) => (group, None),
+            Err(error) => match open(
+                &PathBuf::from(format!("/dev/vfio/noiommu-{group_id}")),
+                libc::O_RDWR | libc::O_CLOEXEC,
+            ) {
+                Ok(group) => (group, Some(VFIO_NOIOMMU_IOMMU)),
+                Err(_) => return Err(error),
+            },
+        };
+
+        let mut status = VfioGroupStatus {
+            argsz: std::mem::size_of::<VfioGroupStatus>() as u32,
+            flags: 0,
+        };
+        ioctl(group.as_raw_fd(), VFIO_GROUP_GET_STATUS, "VFIO_GROUP_GET_STATUS", &mut status as *mut _ as *mut _)?;
+        if status.flags & VFIO_GROUP_FLAGS_VIABLE == 0 {
+            return Err(UnixError::InterruptsUnavailable(address.to_string()));
+        }
+        let mut container_fd = container.as_raw_fd();
+        ioctl(
+            group.as_raw_fd(),
+            VFIO_GROUP_SET_CONTAINER,
+            "VFIO_GROUP_SET_CONTAINER",
+            &mut container_fd as *mut _ as *mut _,
+        )?;
+
+        let iommu = match iommu {
+            Some(iommu) => iommu,
+            None => [VFIO_TYPE1V2_IOMMU, VFIO_TYPE1_IOMMU]
+                .into_iter()
+                .find(|&iommu| {
+                    ioctl(container.as_raw_fd(), VFIO_CHECK_EXTENSION, "VFIO_CHECK_EXTENSION", iommu as *mut _)
+                        .is_ok_and(|supported| supported > 0)
+                })
+                .ok_or(UnixError::InterruptsUnavailable(address.to_string()))?,
+        };
+        ioctl(container.as_raw_fd(), VFIO_SET_IOMMU, "VFIO_SET_IOMMU", iommu as *mut _)?;
+
+        let c_address = CString::new(address).map_err(|_| UnixError::InvalidPath(path.to_path_buf()))?;
+        let device = ioctl(
+            group.as_raw_fd(),
+            VFIO_GROUP_GET_DEVICE_FD,
+            "VFIO_GROUP_GET_DEVICE_FD",
+            c_address.as_ptr() as *mut _,
+        )?;
+        let device = unsafe { OwnedFd::from_raw_fd(device) };
+
+        let mut info = VfioIrqInfo {
+            argsz: std::mem::size_of::<VfioIrqInfo>() as u32,
+            flags: 0,
+            index: VFIO_PCI_MSIX_IRQ_INDEX,
+            count: 0,
+        };
+        ioctl(device.as_raw_fd(), VFIO_DEVICE_GET_IRQ_INFO, "VFIO_DEVICE_GET_IRQ_INFO", &mut info as *mut _ as *mut _)?;
+
+        let mut vectors = Vec::with_capacity(info.count as usize);
+        for _ in 0..info.count {
+            let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+            if fd == -1 {
+                return Err(UnixError::EventFdFailed {
+                    source: io::Error::last_os_error(),
+                });
+            }
+            vectors.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        // struct vfio_irq_set, followed by one eventfd per vector.
+        let mut irq_set = vec![
+            (5 + vectors.len() as u32) * 4,
+            VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            VFIO_PCI_MSIX_IRQ_INDEX,
+            0,
+            vectors.len() as u32,
+        ];
+        irq_set.extend(vectors.iter().map(|fd| fd.as_raw_fd() as u32));
+        if !vectors.is_empty() {
+            ioctl(device.as_raw_fd(), VFIO_DEVICE_SET_IRQS, "VFIO_DEVICE_SET_IRQS", irq_set.as_mut_ptr() as *mut _)?;
+        }
+
+        // MSI-X messages are memory writes by the device, which are dropped unless it may master the bus.
+        enable_bus_master(&path.join("config"))?;
+
+        Ok(Interrupts::Vfio {
+            vectors,
+            _device: device,
+            _group: group,
+            _container: container,
+        })
+    }
+
+    /// Amount of vectors that can be waited on.
+    ///
+    /// UIO only exposes the pin based interrupt, which every doorbell raises. It counts as a single vector, so a peer
+    /// ringing vector N>0 wakes `wait(0)` as well.
+    pub fn vectors(&self) -> usize {
+        match self {
+            Interrupts::None => 0,
+            Interrupts::Uio { .. } => 1,
+            Interrupts::Vfio { vectors, .. } => vectors.len(),
+        }
+    }
+
+    /// Blocks until interrupt `vector` fires.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector`: The interrupt vector to wait for.
+    /// * `acknowledge`: Called after a pin based interrupt fired, to clear it in the device.
+    ///
+    /// returns: The amount of interrupts since the last wait, as far as the driver can tell.
+    pub fn wait(&self, vector: usize, acknowledge: impl FnOnce()) -> Result<u64, UnixError> {
+        if vector >= self.vectors() {
+            return Err(UnixError::InvalidVector(vector));
+        }
+        let doorbell_failed = |source| UnixError::DoorbellFailed { vector, source };
+        match self {
+            Interrupts::None => unreachable!("Devices without interrupts have no vectors"),
+            Interrupts::Uio {
+                device,
+                irq_control,
+                last_count,
+            } => {
+                if *irq_control {
+                    (&*device).write_all(&1u32.to_ne_bytes()).map_err(doorbell_failed)?;
+                }
+                let mut count = [0u8; 4];
+                (&*device).read_exact(&mut count).map_err(doorbell_failed)?;
+                acknowledge();
+                let count = u32::from_ne_bytes(count);
+                let previous = last_count.lock().unwrap().replace(count);
+                Ok(previous.map_or(1, |previous| count.wrapping_sub(previous) as u64))
+            }
+            Interrupts::Vfio { vectors, .. } => loop {
+                let mut count = 0u64;
+                let read = unsafe {
+                    libc::read(
+                        vectors[vector].as_raw_fd(),
+                        &mut count as *mut u64 as *mut libc::c_void,
+                        std::mem::size_of::<u64>(),
+                    )
+                };
+                if read == std::mem::size_of::<u64>() as isize {
+                    return Ok(count);
+                }
+                let source = io::Error::last_os_error();
+                if source.kind() != io::ErrorKind::Interrupted {
+                    return Err(doorbell_failed(source));
+                }
+            },
+        }
+    }
+}
+
+fn enable_bus_master(config_path: &Path) -> Result<(), UnixError> {
+    let read_failed = |source| UnixError::ReadFailed {
+        path: config_path.to_path_buf(),
+        source,
+    };
+    let config = OpenOptions::new().read(true).write(true).open(config_path).map_err(read_failed)?;
+    let mut command = [0u8; 2];
+    config.read_exact_at(&mut command, PCI_COMMAND).map_err(read_failed)?;
+    let command = u16::from_le_bytes(command);
+    if command & PCI_COMMAND_MASTER == 0 {
+        config
+            .write_all_at(&(command | PCI_COMMAND_MASTER).to_le_bytes(), PCI_COMMAND)
+            .map_err(|source| UnixError::WriteFailed {
+                path: config_path.to_path_buf(),
+                source,
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bus_mastering_is_enabled_in_the_command_register() {
+        let path = std::env::temp_dir().join(format!("ivshmemmap-test-config-{}", std::process::id()));
+        let mut config = [0u8; 64];
+        config[PCI_COMMAND as usize..PCI_COMMAND as usize + 2].copy_from_slice(&0x0403u16.to_le_bytes());
+        std::fs::write(&path, config).unwrap();
+
+        enable_bus_master(&path).unwrap();
+        config[PCI_COMMAND as usize] |= PCI_COMMAND_MASTER as u8;
+        assert_eq!(std::fs::read(&path).unwrap(), config);
+        // Enabling it again leaves the register as it is.
+        enable_bus_master(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), config);
+
+        std::fs::write(&path, [0u8; 4]).unwrap();
+        assert!(matches!(enable_bus_master(&path), Err(UnixError::ReadFailed { .. })));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(enable_bus_master(&path), Err(UnixError::ReadFailed { .. })));
+    }
+}
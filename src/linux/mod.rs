@@ -1,51 +1,131 @@
 extern crate libc;
 
 use crate::device::IvshmemDevice;
-use anyhow::Result;
+use crate::pool::CopyPool;
 use std::ffi::CString;
 use std::fmt::{Debug, Formatter};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::sync::Arc;
 use crate::error::UnixError;
 
+pub(crate) mod client;
+pub(crate) mod futex;
+mod interrupts;
+pub(crate) mod pci;
+mod protocol;
+pub(crate) mod server;
+pub(crate) mod shm;
+
+/// A shared mapping that is unmapped, and whose file descriptor is closed, when dropped.
 pub(crate) struct UnixMemoryMap {
-    memory: &'static mut [u8],
+    memory: *mut u8,
+    length: usize,
+    file_descriptor: Option<OwnedFd>,
 }
 
+// The mapping is shared memory. Access is synchronized by the IvshmemDevice that owns it.
+unsafe impl Send for UnixMemoryMap {}
+unsafe impl Sync for UnixMemoryMap {}
+
 impl UnixMemoryMap {
     pub fn new(path: &Path) -> Result<Self, UnixError> {
-        let path = CString::new(path.to_str().expect("Unable to convert path to CString")).expect("Invalid path given");
-        unsafe {
-            let file_descriptor = libc::open(path.as_ptr(), libc::O_RDWR, 0o000);
-            if file_descriptor == -1 {
-                return Err(UnixError::OpenFailed);
-            }
-            let size = libc::lseek(file_descriptor, 0, libc::SEEK_END) as usize;
-            libc::lseek(file_descriptor, 0, libc::SEEK_SET);
-            let ptr = libc::mmap(
-                std::ptr::null_mut(),
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| UnixError::InvalidPath(path.to_path_buf()))?;
+        let flags = libc::O_RDWR | libc::O_CLOEXEC;
+        let file_descriptor = unsafe { libc::open(c_path.as_ptr(), flags, 0o000) };
+        if file_descriptor == -1 {
+            return Err(UnixError::OpenFailed {
+                call: "open",
+                path: path.to_path_buf(),
+                flags,
+                mode: 0,
+                source: io::Error::last_os_error(),
+            });
+        }
+        let file_descriptor = unsafe { OwnedFd::from_raw_fd(file_descriptor) };
+        let mut memory_map = Self::from_fd(file_descriptor.as_raw_fd(), Some(path))?;
+        memory_map.file_descriptor = Some(file_descriptor);
+        Ok(memory_map)
+    }
+
+    /// Maps the entire file behind `file_descriptor`. The descriptor is not owned and may be closed afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_descriptor`: The file to map.
+    /// * `path`: The path the descriptor was opened from, if known. Only used for error reporting.
+    pub fn from_fd(file_descriptor: RawFd, path: Option<&Path>) -> Result<Self, UnixError> {
+        let seek_failed = || UnixError::SeekFailed {
+            path: path.map(Path::to_path_buf),
+            source: io::Error::last_os_error(),
+        };
+        let size = unsafe { libc::lseek(file_descriptor, 0, libc::SEEK_END) };
+        if size == -1 {
+            return Err(seek_failed());
+        }
+        if unsafe { libc::lseek(file_descriptor, 0, libc::SEEK_SET) } == -1 {
+            return Err(seek_failed());
+        }
+        if size == 0 {
+            return Err(UnixError::EmptyFile {
+                path: path.map(Path::to_path_buf),
+            });
+        }
+
+        let size = size as usize;
+        let protection = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_SHARED;
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), size, protection, flags, file_descriptor, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(UnixError::MapFailed {
+                path: path.map(Path::to_path_buf),
                 size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                file_descriptor,
-                0,
-            );
-            if ptr == libc::MAP_FAILED {
-                return Err(UnixError::MapFailed);
-            }
-            let slice: &mut [u8] = std::slice::from_raw_parts_mut(ptr as *mut u8, size);
-            Ok(Self { memory: slice })
+                protection,
+                flags,
+                source: io::Error::last_os_error(),
+            });
         }
+        Ok(Self {
+            memory: ptr as *mut u8,
+            length: size,
+            file_descriptor: None,
+        })
+    }
+
+    /// Hands the mapping over to a new IvshmemDevice, which releases it when dropped.
+    pub fn into_device(self, worker_threads: usize) -> IvshmemDevice {
+        let pool = Arc::new(CopyPool::new(worker_threads));
+        IvshmemDevice::with_mapping(self.memory, self.length, Some(Box::new(self)), pool)
+    }
+
+    fn unmap(&mut self) -> Result<(), UnixError> {
+        if unsafe { libc::munmap(self.memory as *mut libc::c_void, self.length) } == -1 {
+            return Err(UnixError::UnmapFailed {
+                size: self.length,
+                source: io::Error::last_os_error(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UnixMemoryMap {
+    fn drop(&mut self) {
+        // munmap only rejects arguments that mmap already accepted. There is no one to report it to from here.
+        let _ = self.unmap();
     }
 }
 
 impl Debug for UnixMemoryMap {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Memory{{ size: {:?} }}", self.memory.len())?;
+        write!(f, "Memory{{ size: {:?} }}", self.length)?;
         Ok(())
     }
 }
 
-pub fn ivshmem_device<'a>(path: &Path, worker_threads: usize) -> Result<IvshmemDevice, UnixError> {
+pub fn ivshmem_device(path: &Path, worker_threads: usize) -> Result<IvshmemDevice, UnixError> {
     let memory_map = UnixMemoryMap::new(path)?;
-    Ok(IvshmemDevice::with_memory(memory_map.memory, worker_threads))
+    Ok(memory_map.into_device(worker_threads))
 }
@@ -0,0 +1,383 @@
+use crate::backend::Backend;
+use crate::device::IvshmemDevice;
+use crate::error::{Error, UnixError};
+use crate::linux::interrupts::Interrupts;
+use crate::linux::UnixMemoryMap;
+use crate::registers::{Registers, REGISTERS_SIZE};
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// PCI vendor ID of Red Hat, Inc., used by QEMU's ivshmem devices.
+pub const IVSHMEM_VENDOR_ID: u16 = 0x1af4;
+/// PCI device ID of `ivshmem-plain`, `ivshmem-doorbell` and the legacy `ivshmem` device.
+pub const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+
+/// Where the kernel lists all PCI devices.
+const SYSFS_PCI_DEVICES: &str = "/sys/bus/pci/devices";
+/// BAR0 holds the registers, BAR1 the MSI-X table of `ivshmem-doorbell` and BAR2 the shared memory.
+const BAR_COUNT: usize = 6;
+
+/// An ivshmem PCI device, as seen from inside a Linux guest.
+///
+/// Mapping the BARs through sysfs requires root, or write access to the `resource` files of the device.
+///
+/// # Examples
+///
+/// ```no_run
+/// let devices = ivshmemmap::PciIvshmem::discover().unwrap();
+/// let mut device = devices[0].map_shared_memory(4).unwrap();
+/// ```
+///
+/// Discovery and mapping work on any directory laid out like `/sys/bus/pci/devices`:
+///
+/// ```
+/// use ivshmemmap::PciIvshmem;
+///
+/// let root = std::env::temp_dir().join(format!("ivshmemmap-doc-sysfs-{}", std::process::id()));
+/// let write = |device: &str, file: &str, content: &[u8]| {
+///     std::fs::create_dir_all(root.join(device)).unwrap();
+///     std::fs::write(root.join(device).join(file), content).unwrap();
+/// };
+/// write("0000:00:02.0", "vendor", b"0x8086\n");
+/// write("0000:00:02.0", "device", b"0x100e\n");
+/// write("0000:00:05.0", "vendor", b"0x1af4\n");
+/// write("0000:00:05.0", "device", b"0x1110\n");
+/// write("0000:00:05.0", "revision", b"0x01\n");
+/// write("0000:00:05.0", "resource", concat!(
+///     "0x00000000fea00000 0x00000000fea000ff 0x0000000000040200\n",
+///     "0x0000000000000000 0x0000000000000000 0x0000000000000000\n",
+///     "0x0000000800000000 0x00000008000fffff 0x000000000014220c\n",
+/// ).as_bytes());
+/// write("0000:00:05.0", "resource0", &[0; 256]);
+/// write("0000:00:05.0", "resource2", &vec![7; 1 << 20]);
+/// // Odd entries are skipped: no vendor, garbage in device, and an ivshmem device with a malformed revision.
+/// write("0000:00:03.0", "device", b"0x1110\n");
+/// write("0000:00:04.0", "vendor", b"0x1af4\n");
+/// write("0000:00:04.0", "device", b"garbage\n");
+/// write("0000:00:06.0", "vendor", b"0x1af4\n");
+/// write("0000:00:06.0", "device", b"0x1110\n");
+/// write("0000:00:06.0", "revision", b"0x100\n");
+///
+/// let devices = PciIvshmem::discover_in(&root).unwrap();
+/// assert_eq!(devices.len(), 1);
+/// let ivshmem = &devices[0];
+/// assert_eq!(ivshmem.address(), "0000:00:05.0");
+/// assert_eq!((ivshmem.revision(), ivshmem.has_doorbells()), (1, false));
+/// assert_eq!((ivshmem.registers_size(), ivshmem.shared_memory_size()), (256, 1 << 20));
+///
+/// let device = ivshmem.map_shared_memory(2).unwrap();
+/// assert_eq!((device.len(), device[0]), (1 << 20, 7));
+/// assert_eq!(ivshmem.map_registers().unwrap().len(), 256);
+/// # std::fs::remove_dir_all(&root).unwrap();
+/// ```
+#[derive(Clone, PartialEq, Eq)]
+pub struct PciIvshmem {
+    path: PathBuf,
+    address: String,
+    revision: u8,
+    bar_sizes: [u64; BAR_COUNT],
+}
+
+impl Debug for PciIvshmem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PciIvshmem{{ address: {:?} revision: {:?} size: {:?} }}",
+            self.address,
+            self.revision,
+            self.shared_memory_size()
+        )
+    }
+}
+
+impl PciIvshmem {
+    /// Finds all ivshmem devices of this machine.
+    pub fn discover() -> Result<Vec<Self>, UnixError> {
+        Self::discover_in(Path::new(SYSFS_PCI_DEVICES))
+    }
+
+    /// Finds all ivshmem devices in a directory laid out like `/sys/bus/pci/devices`.
+    ///
+    /// Entries with unreadable or malformed attributes are skipped, so a single odd device does not hide the others.
+    ///
+    /// returns: The devices, sorted by PCI address. An error only if `root` itself cannot be read.
+    pub fn discover_in(root: &Path) -> Result<Vec<Self>, UnixError> {
+        let entries = std::fs::read_dir(root).map_err(|source| UnixError::ReadFailed {
+            path: root.to_path_buf(),
+            source,
+        })?;
+        let is_ivshmem = |path: &Path| {
+            matches!(
+                (read_hex(&path.join("vendor")), read_hex(&path.join("device"))),
+                (Ok(vendor), Ok(device)) if vendor == IVSHMEM_VENDOR_ID as u64 && device == IVSHMEM_DEVICE_ID as u64
+            )
+        };
+        let mut devices: Vec<_> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| is_ivshmem(path))
+            .filter_map(|path| Self::load(path).ok())
+            .collect();
+        devices.sort_by(|a, b| a.address.cmp(&b.address));
+        Ok(devices)
+    }
+
+    fn load(path: PathBuf) -> Result<Self, UnixError> {
+        let revision_path = path.join("revision");
+        let revision = read_hex(&revision_path)?;
+        let revision = u8::try_from(revision).map_err(|_| UnixError::InvalidSysfsEntry {
+            path: revision_path,
+            content: format!("{revision:#x}"),
+        })?;
+
+        // Every line of `resource` holds the start and end address and the flags of one BAR. Unused BARs are all 0.
+        let resource_path = path.join("resource");
+        let resource = read_file(&resource_path)?;
+        let mut bar_sizes = [0; BAR_COUNT];
+        for (size, line) in bar_sizes.iter_mut().zip(resource.lines()) {
+            let invalid = || UnixError::InvalidSysfsEntry {
+                path: resource_path.clone(),
+                content: line.to_string(),
+            };
+            let mut fields = line.split_whitespace().map(parse_hex);
+            let (Some(Some(start)), Some(Some(end))) = (fields.next(), fields.next()) else {
+                return Err(invalid());
+            };
+            if end != 0 {
+                *size = end.checked_sub(start).and_then(|size| size.checked_add(1)).ok_or_else(invalid)?;
+            }
+        }
+
+        Ok(Self {
+            address: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+            path,
+            revision,
+            bar_sizes,
+        })
+    }
+
+    /// The sysfs directory of the device.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The PCI address of the device, e.g. `0000:00:05.0`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// PCI revision. QEMU reports 1 for `ivshmem-plain` and `ivshmem-doorbell`, and 0 for the legacy `ivshmem`.
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// Size of BAR0, which holds the interrupt and doorbell registers.
+    pub fn registers_size(&self) -> u64 {
+        self.bar_sizes[0]
+    }
+
+    /// Whether the device has an MSI-X table in BAR1, which only `ivshmem-doorbell` has.
+    pub fn has_doorbells(&self) -> bool {
+        self.bar_sizes[1] != 0
+    }
+
+    /// Size of BAR2, which is the shared memory.
+    pub fn shared_memory_size(&self) -> u64 {
+        self.bar_sizes[2]
+    }
+
+    /// Maps the shared memory in BAR2.
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_threads`: Amount of worker threads for copy operations.
+    pub fn map_shared_memory(&self, worker_threads: usize) -> Result<IvshmemDevice, UnixError> {
+        Ok(self.map_bar(2)?.into_device(worker_threads))
+    }
+
+    /// Maps the registers in BAR0. Access them with `read_volatile` and `write_volatile` only.
+    ///
+    /// The mapping has no worker threads, as there is nothing to copy in parallel. A pool of one thread only uses
+    /// the caller.
+    pub fn map_registers(&self) -> Result<IvshmemDevice, UnixError> {
+        Ok(self.map_bar(0)?.into_device(1))
+    }
+
+    fn map_bar(&self, bar: usize) -> Result<UnixMemoryMap, UnixError> {
+        let path = self.path.join(format!("resource{bar}"));
+        let memory_map = UnixMemoryMap::new(&path)?;
+        if memory_map.length as u64 != self.bar_sizes[bar] {
+            return Err(UnixError::SizeMismatch {
+                path,
+                expected: self.bar_sizes[bar] as usize,
+                actual: memory_map.length,
+            });
+        }
+        Ok(memory_map)
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, UnixError> {
+    std::fs::read_to_string(path).map_err(|source| UnixError::ReadFailed {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.trim().strip_prefix("0x")?, 16).ok()
+}
+
+/// Reads a sysfs attribute holding a single hexadecimal number, like `vendor`.
+fn read_hex(path: &Path) -> Result<u64, UnixError> {
+    let content = read_file(path)?;
+    parse_hex(&content).ok_or_else(|| UnixError::InvalidSysfsEntry {
+        path: path.to_path_buf(),
+        content: content.trim().to_string(),
+    })
+}
+
+/// An ivshmem PCI device together with its registers and interrupts, as seen from inside a Linux guest.
+///
+/// Interrupts arrive through the userspace driver the device is bound to. `uio_pci_generic` and `uio_ivshmem` provide
+/// a single vector, `vfio-pci` one per MSI-X vector of `ivshmem-doorbell`. Ringing doorbells works with any driver.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ivshmemmap::backend::Backend;
+/// use ivshmemmap::{PciBackend, PciIvshmem};
+///
+/// let ivshmem = PciIvshmem::discover().unwrap().remove(0);
+/// let backend = PciBackend::open(ivshmem).unwrap();
+/// let peer = backend.peer_id().expect("Not connected to an ivshmem-server");
+/// backend.ring(peer ^ 1, 0).unwrap();
+/// backend.wait(0).unwrap();
+/// ```
+///
+/// With a file as BAR0, doorbells end up in the file:
+///
+/// ```
+/// use ivshmemmap::{PciBackend, PciIvshmem};
+/// use ivshmemmap::error::UnixError;
+///
+/// let root = std::env::temp_dir().join(format!("ivshmemmap-doc-doorbell-{}", std::process::id()));
+/// let device = root.join("0000:00:05.0");
+/// std::fs::create_dir_all(&device).unwrap();
+/// std::fs::write(device.join("vendor"), "0x1af4\n").unwrap();
+/// std::fs::write(device.join("device"), "0x1110\n").unwrap();
+/// std::fs::write(device.join("revision"), "0x01\n").unwrap();
+/// std::fs::write(device.join("resource"), "0x00000000fea00000 0x00000000fea000ff 0x0000000000040200\n").unwrap();
+/// let mut bar = [0u8; 256];
+/// bar[8..12].copy_from_slice(&2u32.to_ne_bytes());
+/// std::fs::write(device.join("resource0"), bar).unwrap();
+///
+/// let backend = PciBackend::new(PciIvshmem::discover_in(&root).unwrap().remove(0)).unwrap();
+/// assert_eq!(backend.registers().position(), Some(2));
+/// backend.ring(5, 3).unwrap();
+/// assert_eq!(&std::fs::read(device.join("resource0")).unwrap()[12..16], &0x0005_0003u32.to_ne_bytes());
+///
+/// // Without a UIO or VFIO driver, there is nothing to wait on.
+/// assert!(matches!(backend.wait(0), Err(UnixError::InterruptsUnavailable(_))));
+/// # std::fs::remove_dir_all(&root).unwrap();
+/// ```
+pub struct PciBackend {
+    ivshmem: PciIvshmem,
+    registers: Mutex<Registers>,
+    interrupts: Interrupts,
+}
+
+impl Debug for PciBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "PciBackend{{ ivshmem: {:?} vectors: {:?} }}",
+            self.ivshmem,
+            self.interrupts.vectors()
+        )
+    }
+}
+
+impl PciBackend {
+    /// Maps the registers of the device and sets up its interrupts.
+    pub fn new(ivshmem: PciIvshmem) -> Result<Self, UnixError> {
+        let mut registers = Registers::new(ivshmem.map_registers()?).map_err(|_| UnixError::TooSmall {
+            path: ivshmem.path.join("resource0"),
+            minimum: REGISTERS_SIZE,
+            actual: ivshmem.registers_size() as usize,
+        })?;
+        let interrupts = Interrupts::open(&ivshmem.path, &ivshmem.address)?;
+        if matches!(interrupts, Interrupts::Uio { .. }) {
+            // Pin based interrupts are only raised for the vectors enabled in the mask.
+            registers.take_interrupt_status();
+            registers.set_interrupt_mask(u32::MAX);
+        }
+        Ok(Self {
+            ivshmem,
+            registers: Mutex::new(registers),
+            interrupts,
+        })
+    }
+
+    pub fn ivshmem(&self) -> &PciIvshmem {
+        &self.ivshmem
+    }
+
+    /// Direct access to the registers in BAR0.
+    pub fn registers(&self) -> MutexGuard<'_, Registers> {
+        self.registers.lock().unwrap()
+    }
+
+    /// Rings the doorbell of `peer`, raising interrupt `vector` on its side.
+    pub fn ring(&self, peer: u16, vector: usize) -> Result<(), UnixError> {
+        let vector = u16::try_from(vector).map_err(|_| UnixError::InvalidVector(vector))?;
+        self.registers().ring(peer, vector);
+        Ok(())
+    }
+
+    /// Blocks until interrupt `vector` is raised by any peer.
+    ///
+    /// returns: The amount of times the doorbell was rung since the last wait, as far as the driver can tell.
+    pub fn wait(&self, vector: usize) -> Result<u64, UnixError> {
+        if let Interrupts::None = self.interrupts {
+            return Err(UnixError::InterruptsUnavailable(self.ivshmem.address.clone()));
+        }
+        self.interrupts.wait(vector, || {
+            self.registers().take_interrupt_status();
+        })
+    }
+}
+
+impl Backend for PciBackend {
+    type Options = PciIvshmem;
+
+    fn open(ivshmem: PciIvshmem) -> Result<Self, Error> {
+        Ok(PciBackend::new(ivshmem)?)
+    }
+
+    fn size(&self) -> usize {
+        self.ivshmem.shared_memory_size() as usize
+    }
+
+    fn map(&self, worker_threads: usize) -> Result<IvshmemDevice, Error> {
+        Ok(self.ivshmem.map_shared_memory(worker_threads)?)
+    }
+
+    fn peer_id(&self) -> Option<u16> {
+        self.registers().position()
+    }
+
+    /// Under UIO this is 1, whatever the amount of vectors the device was given: all of them raise the same pin
+    /// based interrupt, so a peer ringing vector N>0 also wakes `wait(0)`. VFIO has a separate interrupt per vector.
+    fn vectors(&self) -> usize {
+        self.interrupts.vectors()
+    }
+
+    fn ring(&self, peer: u16, vector: usize) -> Result<(), Error> {
+        Ok(PciBackend::ring(self, peer, vector)?)
+    }
+
+    fn wait(&self, vector: usize) -> Result<u64, Error> {
+        Ok(PciBackend::wait(self, vector)?)
+    }
+}
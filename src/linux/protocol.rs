@@ -0,0 +1,126 @@
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+/// Version of the ivshmem-server UNIX socket protocol as implemented by QEMU.
+pub(crate) const IVSHMEM_PROTOCOL_VERSION: i64 = 0;
+
+/// Every message exchanged over the ivshmem-server socket is a single native endian `int64`,
+/// optionally accompanied by one file descriptor passed through `SCM_RIGHTS`.
+pub(crate) const MESSAGE_SIZE: usize = std::mem::size_of::<i64>();
+
+/// Receives a single protocol message.
+///
+/// # Arguments
+///
+/// * `socket`: The connected UNIX stream socket.
+/// * `blocking`: If false, returns `Ok(None)` when no message is available yet.
+///
+/// returns: The value and the file descriptor that came along with it, or `Ok(None)` if no message is pending
+///          in non-blocking mode. A closed connection is reported as `ErrorKind::UnexpectedEof`.
+pub(crate) fn recv_message(socket: RawFd, blocking: bool) -> io::Result<Option<(i64, Option<OwnedFd>)>> {
+    let mut payload = [0u8; MESSAGE_SIZE];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let control_len = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) } as usize;
+    // Backed by u64 so the control buffer is suitably aligned for `cmsghdr`.
+    let mut control = vec![0u64; control_len.div_ceil(std::mem::size_of::<u64>())];
+
+    let mut header: libc::msghdr = unsafe { MaybeUninit::zeroed().assume_init() };
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    header.msg_controllen = control_len as _;
+
+    let flags = if blocking {
+        libc::MSG_CMSG_CLOEXEC
+    } else {
+        libc::MSG_CMSG_CLOEXEC | libc::MSG_DONTWAIT
+    };
+
+    let received = loop {
+        let received = unsafe { libc::recvmsg(socket, &mut header, flags) };
+        if received >= 0 {
+            break received as usize;
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::Interrupted => continue,
+            io::ErrorKind::WouldBlock if !blocking => return Ok(None),
+            _ => return Err(error),
+        }
+    };
+
+    // Take ownership of any descriptor first, so that it is closed if the message turns out to be invalid.
+    let mut fd = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let raw = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                fd = Some(OwnedFd::from_raw_fd(raw));
+            }
+            cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+        }
+    }
+
+    if received == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "ivshmem socket was closed"));
+    }
+    if received != MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Received truncated ivshmem message of {received} bytes"),
+        ));
+    }
+    Ok(Some((i64::from_ne_bytes(payload), fd)))
+}
+
+/// Sends a single protocol message.
+///
+/// # Arguments
+///
+/// * `socket`: The connected UNIX stream socket.
+/// * `value`: The payload of the message.
+/// * `fd`: Optional file descriptor to pass along through `SCM_RIGHTS`.
+pub(crate) fn send_message(socket: RawFd, value: i64, fd: Option<RawFd>) -> io::Result<()> {
+    let payload = value.to_ne_bytes();
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let control_len = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) } as usize;
+    let mut control = vec![0u64; control_len.div_ceil(std::mem::size_of::<u64>())];
+
+    let mut header: libc::msghdr = unsafe { MaybeUninit::zeroed().assume_init() };
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+
+    if let Some(fd) = fd {
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = control_len as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, fd);
+        }
+    }
+
+    loop {
+        let sent = unsafe { libc::sendmsg(socket, &header, libc::MSG_NOSIGNAL) };
+        if sent == MESSAGE_SIZE as isize {
+            return Ok(());
+        }
+        if sent >= 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Failed to send complete ivshmem message"));
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::error::UnixError;
+use crate::linux::protocol::{send_message, IVSHMEM_PROTOCOL_VERSION};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use crate::linux::shm::{SharedMemory, SharedMemoryBuilder};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A client connected to the server, together with the eventfds that other peers use to interrupt it.
+struct ServerPeer {
+    socket: UnixStream,
+    vectors: Vec<OwnedFd>,
+}
+
+/// Pure-Rust replacement for QEMU's `ivshmem-server`.
+///
+/// Creates the shared memory object, accepts peers on a UNIX socket and hands out peer IDs and eventfds.
+/// Both QEMU (`-chardev socket,path=...` with `ivshmem-doorbell`) and `IvshmemClient` can connect to it.
+pub struct IvshmemServer {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    shm: SharedMemory,
+    size: usize,
+    vectors: usize,
+    next_id: u16,
+    peers: BTreeMap<u16, ServerPeer>,
+}
+
+impl Debug for IvshmemServer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "IvshmemServer{{ socket: {:?} size: {:?} vectors: {:?} peers: {:?} }}",
+            self.socket_path,
+            self.size,
+            self.vectors,
+            self.peers.keys().collect::<Vec<_>>()
+        )
+    }
+}
+
+impl IvshmemServer {
+    /// Creates the shared memory and starts listening for peers.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_path`: Path of the UNIX socket to create. An existing socket is replaced, any other file is kept.
+    /// * `shm_path`: Path of the shared memory file, usually in /dev/shm/*. It is created if it does not exist.
+    /// * `size`: Size of the shared memory in bytes. QEMU requires this to be a power of two.
+    /// * `vectors`: Amount of interrupt vectors per peer.
+    ///
+    /// returns: A server that does not accept peers until `serve` or `process` is called.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ivshmemmap::{IvshmemClient, IvshmemServer, PeerEvent};
+    ///
+    /// let dir = std::env::temp_dir();
+    /// let socket = dir.join(format!("ivshmemmap-doc-{}.sock", std::process::id()));
+    /// let shm = dir.join(format!("ivshmemmap-doc-{}.shm", std::process::id()));
+    ///
+    /// let mut server = IvshmemServer::new(&socket, &shm, 1 << 20, 2).unwrap();
+    /// std::thread::spawn(move || server.serve());
+    ///
+    /// let (mut first, mut first_client) = IvshmemClient::connect(&socket, 2, 1).unwrap();
+    /// let (second, second_client) = IvshmemClient::connect(&socket, 2, 1).unwrap();
+    /// assert_eq!(first_client.wait_event().unwrap(), PeerEvent::Joined { peer: second_client.id(), vector: 0 });
+    /// assert_eq!(first_client.wait_event().unwrap(), PeerEvent::Joined { peer: second_client.id(), vector: 1 });
+    ///
+    /// first[0] = 42;
+    /// first_client.ring(second_client.id(), 1).unwrap();
+    /// assert_eq!(second_client.wait(1).unwrap(), 1);
+    /// assert_eq!(second[0], 42);
+    ///
+    /// let second_id = second_client.id();
+    /// drop(second_client);
+    /// assert_eq!(first_client.wait_event().unwrap(), PeerEvent::Left { peer: second_id });
+    /// # std::fs::remove_file(&shm).unwrap();
+    /// # std::fs::remove_file(&socket).unwrap();
+    /// ```
+    pub fn new(socket_path: &Path, shm_path: &Path, size: usize, vectors: usize) -> Result<Self, UnixError> {
+        let shm = SharedMemoryBuilder::file(shm_path).create(true).size(size).open()?;
+        Self::with_shared_memory(socket_path, shm, vectors)
+    }
+
+    /// Starts listening for peers of already opened shared memory, such as a POSIX shared memory object.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket_path`: Path of the UNIX socket to create. An existing socket is replaced, any other file is kept.
+    /// * `shm`: The shared memory handed to every peer.
+    /// * `vectors`: Amount of interrupt vectors per peer.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ivshmemmap::IvshmemServer;
+    ///
+    /// let socket = std::env::temp_dir().join(format!("ivshmemmap-doc-shm-{}.sock", std::process::id()));
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-server", 1 << 20).unwrap();
+    ///
+    /// let server = IvshmemServer::with_shared_memory(&socket, shm, 1).unwrap();
+    /// assert_eq!(server.size(), 1 << 20);
+    /// ```
+    pub fn with_shared_memory(socket_path: &Path, shm: SharedMemory, vectors: usize) -> Result<Self, UnixError> {
+        // Only a stale socket of a previous server is removed. Anything else makes bind fail below.
+        if let Ok(metadata) = std::fs::symlink_metadata(socket_path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(socket_path).map_err(|source| UnixError::ListenFailed {
+                    path: socket_path.to_path_buf(),
+                    source,
+                })?;
+            }
+        }
+        let listener = UnixListener::bind(socket_path).map_err(|source| UnixError::ListenFailed {
+            path: socket_path.to_path_buf(),
+            source,
+        })?;
+
+        Ok(Self {
+            listener,
+            socket_path: socket_path.to_path_buf(),
+            size: shm.size(),
+            shm,
+            vectors,
+            next_id: 0,
+            peers: BTreeMap::new(),
+        })
+    }
+
+    /// Accepts peers and tracks disconnects until an unrecoverable error occurs.
+    pub fn serve(&mut self) -> Result<(), UnixError> {
+        loop {
+            self.process(None)?;
+        }
+    }
+
+    /// Waits for a single round of socket activity and handles it.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout`: Maximum time to wait for activity. Waits indefinitely if `None`.
+    pub fn process(&mut self, timeout: Option<Duration>) -> Result<(), UnixError> {
+        let mut poll_fds = vec![libc::pollfd {
+            fd: self.listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let ids: Vec<u16> = self.peers.keys().copied().collect();
+        for id in &ids {
+            poll_fds.push(libc::pollfd {
+                fd: self.peers[id].socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+        }
+
+        let timeout = timeout.map_or(-1, |timeout| timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int);
+        if unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout) } == -1 {
+            let source = std::io::Error::last_os_error();
+            return match source.kind() {
+                std::io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(UnixError::PollFailed { source }),
+            };
+        }
+
+        // Peers never send anything. Any activity on their socket means they hung up.
+        for (id, poll_fd) in ids.iter().zip(&poll_fds[1..]) {
+            if poll_fd.revents != 0 {
+                self.remove_peer(*id);
+            }
+        }
+
+        if poll_fds[0].revents & libc::POLLIN != 0 {
+            let (socket, _) = self.listener.accept().map_err(|source| UnixError::AcceptFailed { source })?;
+            // A peer that fails the handshake is simply dropped. It must not take the server down.
+            let _ = self.add_peer(socket);
+        }
+        Ok(())
+    }
+
+    fn allocate_id(&mut self) -> Option<u16> {
+        for _ in 0..=u16::MAX as u32 {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.peers.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    fn add_peer(&mut self, socket: UnixStream) -> Result<(), UnixError> {
+        let id = self.allocate_id().ok_or(UnixError::ProtocolViolation("no peer IDs left"))?;
+
+        let mut vectors = Vec::with_capacity(self.vectors);
+        for _ in 0..self.vectors {
+            let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+            if fd == -1 {
+                return Err(UnixError::EventFdFailed {
+                    source: std::io::Error::last_os_error(),
+                });
+            }
+            vectors.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+
+        let fd = socket.as_raw_fd();
+        let send = |value: i64, attached: Option<&BorrowedFd>| {
+            send_message(fd, value, attached.map(|attached| attached.as_raw_fd())).map_err(|source| UnixError::Disconnected { source })
+        };
+        // The newcomer receives everything before existing peers learn about it. If it hangs up halfway, it is
+        // dropped without anybody else holding its eventfds.
+        send(IVSHMEM_PROTOCOL_VERSION, None)?;
+        send(id as i64, None)?;
+        send(-1, Some(&self.shm.as_fd()))?;
+        for (other_id, other) in &self.peers {
+            for vector in &other.vectors {
+                send(*other_id as i64, Some(&vector.as_fd()))?;
+            }
+        }
+        for vector in &vectors {
+            send(id as i64, Some(&vector.as_fd()))?;
+        }
+
+        let mut gone = Vec::new();
+        for (other_id, other) in &self.peers {
+            for vector in &vectors {
+                if send_message(other.socket.as_raw_fd(), id as i64, Some(vector.as_raw_fd())).is_err() {
+                    gone.push(*other_id);
+                    break;
+                }
+            }
+        }
+
+        self.peers.insert(id, ServerPeer { socket, vectors });
+        for other_id in gone {
+            self.remove_peer(other_id);
+        }
+        Ok(())
+    }
+
+    fn remove_peer(&mut self, id: u16) {
+        if self.peers.remove(&id).is_none() {
+            return;
+        }
+        for other in self.peers.values() {
+            let _ = send_message(other.socket.as_raw_fd(), id as i64, None);
+        }
+    }
+
+    /// Size of the shared memory in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Amount of interrupt vectors handed out to every peer.
+    pub fn vector_count(&self) -> usize {
+        self.vectors
+    }
+
+    /// IDs of all currently connected peers.
+    pub fn peer_ids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.peers.keys().copied()
+    }
+}
+
+impl Drop for IvshmemServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_stale_socket_is_replaced() {
+        let path = std::env::temp_dir().join(format!("ivshmemmap-test-server-{}.sock", std::process::id()));
+
+        std::fs::write(&path, b"not a socket").unwrap();
+        let shm = SharedMemory::scratch("test-server-file", 4096).unwrap();
+        assert!(IvshmemServer::with_shared_memory(&path, shm, 1).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+        let shm = SharedMemory::scratch("test-server-socket", 4096).unwrap();
+        let server = IvshmemServer::with_shared_memory(&path, shm, 1).unwrap();
+        assert_eq!(server.size(), 4096);
+        drop(server);
+        assert!(!path.exists());
+    }
+}
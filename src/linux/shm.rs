@@ -0,0 +1,382 @@
+use crate::backend::Backend;
+use crate::device::IvshmemDevice;
+use crate::error::{Error, UnixError};
+use crate::linux::UnixMemoryMap;
+use std::ffi::CString;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+enum Location {
+    /// A POSIX shared memory object name, opened with `shm_open`. Lives in /dev/shm/* on Linux.
+    Shm(String),
+    /// Any file on the filesystem.
+    File(PathBuf),
+}
+
+impl Location {
+    fn path(&self) -> PathBuf {
+        match self {
+            Location::Shm(name) => Path::new("/dev/shm").join(name.trim_start_matches('/')),
+            Location::File(path) => path.clone(),
+        }
+    }
+}
+
+/// Opens or creates the object backing an ivshmem region.
+///
+/// # Examples
+///
+/// ```
+/// use ivshmemmap::SharedMemoryBuilder;
+///
+/// let name = format!("/ivshmemmap-doc-{}", std::process::id());
+/// let shm = SharedMemoryBuilder::shm(&name)
+///     .create(true)
+///     .exclusive(true)
+///     .size(1 << 20)
+///     .mode(0o660)
+///     .unlink_on_drop(true)
+///     .open()
+///     .unwrap();
+///
+/// // QEMU can now use `-object memory-backend-file,mem-path=/dev/shm/<name>,size=1M,share=on`.
+/// let device = shm.map(1).unwrap();
+/// assert_eq!(device.len(), 1 << 20);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SharedMemoryBuilder {
+    location: Location,
+    create: bool,
+    exclusive: bool,
+    size: Option<usize>,
+    min_size: Option<usize>,
+    exact_size: Option<usize>,
+    mode: u32,
+    unlink_on_drop: bool,
+}
+
+impl SharedMemoryBuilder {
+    fn with_location(location: Location) -> Self {
+        Self {
+            location,
+            create: false,
+            exclusive: false,
+            size: None,
+            min_size: None,
+            exact_size: None,
+            mode: 0o600,
+            unlink_on_drop: false,
+        }
+    }
+
+    /// Uses a POSIX shared memory object. `name` should start with a `/`, e.g. `/shm-portal` for /dev/shm/shm-portal.
+    pub fn shm(name: &str) -> Self {
+        Self::with_location(Location::Shm(name.to_string()))
+    }
+
+    /// Uses a regular file, such as a file on a hugetlbfs mount.
+    pub fn file(path: &Path) -> Self {
+        Self::with_location(Location::File(path.to_path_buf()))
+    }
+
+    /// Creates the object if it does not exist yet. (`O_CREAT`)
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Fails if the object already exists. Only has effect in combination with `create`. (`O_EXCL`)
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Resizes the object to `size` bytes with `ftruncate` after opening it.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Fails if the object is smaller than `size` bytes.
+    pub fn min_size(mut self, size: usize) -> Self {
+        self.min_size = Some(size);
+        self
+    }
+
+    /// Fails if the object is not exactly `size` bytes.
+    pub fn exact_size(mut self, size: usize) -> Self {
+        self.exact_size = Some(size);
+        self
+    }
+
+    /// Permission bits used when the object is created. Defaults to `0o600`.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Removes the object from the filesystem when the returned `SharedMemory` is dropped.
+    /// Existing mappings remain valid until they are unmapped.
+    pub fn unlink_on_drop(mut self, unlink: bool) -> Self {
+        self.unlink_on_drop = unlink;
+        self
+    }
+
+    /// Opens the object, creating and resizing it as configured.
+    ///
+    /// returns: An error if any step fails. An object created by this call is removed again in that case.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ivshmemmap::SharedMemoryBuilder;
+    ///
+    /// let name = format!("/ivshmemmap-doc-open-{}", std::process::id());
+    /// assert!(SharedMemoryBuilder::shm(&name).create(true).size(4096).exact_size(8192).open().is_err());
+    /// assert!(!std::path::Path::new("/dev/shm").join(&name[1..]).exists());
+    ///
+    /// assert!(SharedMemoryBuilder::shm(&name).create(true).size(usize::MAX).open().is_err());
+    /// assert!(!std::path::Path::new("/dev/shm").join(&name[1..]).exists());
+    /// ```
+    pub fn open(self) -> Result<SharedMemory, UnixError> {
+        let path = self.location.path();
+        let (raw, created) = if self.create && !self.exclusive {
+            // Creating exclusively first, to know whether the object is ours to remove if a later step fails.
+            loop {
+                match self.open_with(libc::O_CREAT | libc::O_EXCL) {
+                    Ok(raw) => break (raw, true),
+                    Err(UnixError::OpenFailed { source, .. }) if source.kind() == io::ErrorKind::AlreadyExists => {}
+                    Err(error) => return Err(error),
+                }
+                match self.open_with(0) {
+                    Ok(raw) => break (raw, false),
+                    // Removed between both calls. Try creating it again.
+                    Err(UnixError::OpenFailed { source, .. }) if source.kind() == io::ErrorKind::NotFound => {}
+                    Err(error) => return Err(error),
+                }
+            }
+        } else if self.create {
+            (self.open_with(libc::O_CREAT | libc::O_EXCL)?, true)
+        } else {
+            (self.open_with(0)?, false)
+        };
+
+        let mut shm = SharedMemory {
+            fd: unsafe { OwnedFd::from_raw_fd(raw) },
+            location: self.location.clone(),
+            size: 0,
+            // Until every check passed, dropping removes an object we created.
+            unlink_on_drop: self.unlink_on_drop || created,
+        };
+
+        if let Some(size) = self.size {
+            let length = libc::off_t::try_from(size).map_err(|_| UnixError::ResizeFailed {
+                path: path.clone(),
+                size,
+                source: io::ErrorKind::InvalidInput.into(),
+            })?;
+            if unsafe { libc::ftruncate(shm.fd.as_raw_fd(), length) } == -1 {
+                return Err(UnixError::ResizeFailed {
+                    path,
+                    size,
+                    source: io::Error::last_os_error(),
+                });
+            }
+        }
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(shm.fd.as_raw_fd(), &mut stat) } == -1 {
+            return Err(UnixError::StatFailed {
+                path,
+                source: io::Error::last_os_error(),
+            });
+        }
+        shm.size = stat.st_size as usize;
+
+        if let Some(expected) = self.exact_size {
+            if shm.size != expected {
+                return Err(UnixError::SizeMismatch {
+                    path,
+                    expected,
+                    actual: shm.size,
+                });
+            }
+        }
+        if let Some(minimum) = self.min_size {
+            if shm.size < minimum {
+                return Err(UnixError::TooSmall {
+                    path,
+                    minimum,
+                    actual: shm.size,
+                });
+            }
+        }
+        shm.unlink_on_drop = self.unlink_on_drop;
+        Ok(shm)
+    }
+
+    /// Opens the object read-write with `extra_flags`, such as `O_CREAT`.
+    fn open_with(&self, extra_flags: i32) -> Result<RawFd, UnixError> {
+        let flags = libc::O_RDWR | libc::O_CLOEXEC | extra_flags;
+        let path = self.location.path();
+        let (call, raw) = match &self.location {
+            Location::Shm(name) => {
+                let name = CString::new(name.as_bytes()).map_err(|_| UnixError::InvalidPath(path.clone()))?;
+                ("shm_open", unsafe { libc::shm_open(name.as_ptr(), flags, self.mode as libc::mode_t) })
+            }
+            Location::File(file) => {
+                let file = CString::new(file.as_os_str().as_bytes()).map_err(|_| UnixError::InvalidPath(path.clone()))?;
+                ("open", unsafe { libc::open(file.as_ptr(), flags, self.mode as libc::c_uint) })
+            }
+        };
+        if raw == -1 {
+            return Err(UnixError::OpenFailed {
+                call,
+                path,
+                flags,
+                mode: self.mode,
+                source: io::Error::last_os_error(),
+            });
+        }
+        Ok(raw)
+    }
+}
+
+/// An opened shared memory object. Map it with `map` to obtain an `IvshmemDevice`.
+pub struct SharedMemory {
+    fd: OwnedFd,
+    location: Location,
+    size: usize,
+    unlink_on_drop: bool,
+}
+
+impl Debug for SharedMemory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SharedMemory{{ location: {:?} size: {:?} }}", self.location, self.size)
+    }
+}
+
+impl SharedMemory {
+    /// Creates a POSIX shared memory object that is unlinked when dropped, as used by the examples and tests.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Distinguishes the object from others of this process. The process id is appended to it.
+    /// * `size`: Size of the object in bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-scratch", 4096).unwrap();
+    /// assert_eq!(shm.path().to_str(), Some(&*format!("/dev/shm/ivshmemmap-doc-scratch-{}", std::process::id())));
+    /// assert_eq!(shm.map(1).unwrap().len(), 4096);
+    /// ```
+    pub fn scratch(name: &str, size: usize) -> Result<Self, UnixError> {
+        SharedMemoryBuilder::shm(&format!("/ivshmemmap-{}-{}", name, std::process::id()))
+            .create(true)
+            .size(size)
+            .unlink_on_drop(true)
+            .open()
+    }
+
+    /// Size of the object in bytes, as reported by `fstat` when it was opened.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The path of the object on the filesystem. QEMU's `memory-backend-file` should point here.
+    pub fn path(&self) -> PathBuf {
+        self.location.path()
+    }
+
+    /// Maps the entire object.
+    ///
+    /// # Arguments
+    ///
+    /// * `worker_threads`: Amount of worker threads for copy operations.
+    pub fn map(&self, worker_threads: usize) -> Result<IvshmemDevice, UnixError> {
+        let memory_map = UnixMemoryMap::from_fd(self.fd.as_raw_fd(), Some(&self.path()))?;
+        Ok(memory_map.into_device(worker_threads))
+    }
+
+    /// Removes the object from the filesystem. Existing mappings and descriptors remain valid.
+    pub fn unlink(&mut self) -> Result<(), UnixError> {
+        self.unlink_on_drop = false;
+        let path = self.path();
+        let (call, result) = match &self.location {
+            Location::Shm(name) => {
+                let name = CString::new(name.as_bytes()).map_err(|_| UnixError::InvalidPath(path.clone()))?;
+                ("shm_unlink", unsafe { libc::shm_unlink(name.as_ptr()) })
+            }
+            Location::File(file) => {
+                let file = CString::new(file.as_os_str().as_bytes()).map_err(|_| UnixError::InvalidPath(path.clone()))?;
+                ("unlink", unsafe { libc::unlink(file.as_ptr()) })
+            }
+        };
+        if result == -1 {
+            return Err(UnixError::UnlinkFailed {
+                call,
+                path,
+                source: io::Error::last_os_error(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl AsFd for SharedMemory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for SharedMemory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// The Linux file backend. It has no doorbells; use an `IvshmemClient` to reach the peers of an ivshmem-server.
+impl Backend for SharedMemory {
+    type Options = SharedMemoryBuilder;
+
+    fn open(options: SharedMemoryBuilder) -> Result<Self, Error> {
+        Ok(options.open()?)
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn map(&self, worker_threads: usize) -> Result<IvshmemDevice, Error> {
+        Ok(SharedMemory::map(self, worker_threads)?)
+    }
+
+    fn peer_id(&self) -> Option<u16> {
+        None
+    }
+
+    fn vectors(&self) -> usize {
+        0
+    }
+
+    fn ring(&self, _peer: u16, _vector: usize) -> Result<(), Error> {
+        Err(Error::Unsupported("doorbells"))
+    }
+
+    fn wait(&self, _vector: usize) -> Result<u64, Error> {
+        Err(Error::Unsupported("doorbells"))
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        if self.unlink_on_drop {
+            let _ = self.unlink();
+        }
+    }
+}
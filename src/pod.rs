@@ -0,0 +1,69 @@
+/// Plain old data: a type that can be copied in and out of shared memory as raw bytes.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, and the type may not contain padding, pointers or references.
+/// Another peer can write arbitrary bytes into the shared memory. Prefer the `pod!` macro, which verifies this for structs.
+pub unsafe trait Pod: Copy + Send + Sync + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// Declares a `#[repr(C)]` struct and implements `Pod` for it.
+///
+/// Fails to compile if a field is not `Pod`, or if the struct contains padding. The struct derives `Copy` and
+/// `Clone`, so do not derive those yourself. Other attributes, such as `#[derive(Debug)]`, are passed through.
+///
+/// # Examples
+///
+/// ```
+/// ivshmemmap::pod! {
+///     #[derive(Debug, PartialEq)]
+///     pub struct Header {
+///         pub magic: u64,
+///         pub version: u32,
+///         pub flags: u32,
+///         pub checksums: [u8; 16],
+///     }
+/// }
+/// ```
+///
+/// Padding is rejected, as it would expose uninitialized bytes to the other side:
+///
+/// ```compile_fail
+/// ivshmemmap::pod! {
+///     pub struct Padded {
+///         pub small: u8,
+///         pub large: u64,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! pod {
+    ($(#[$attribute:meta])* $visibility:vis struct $name:ident { $($(#[$field_attribute:meta])* $field_visibility:vis $field:ident: $ty:ty),* $(,)? }) => {
+        $(#[$attribute])*
+        #[repr(C)]
+        #[derive(Copy, Clone)]
+        $visibility struct $name {
+            $($(#[$field_attribute])* $field_visibility $field: $ty),*
+        }
+
+        const _: () = {
+            const fn assert_pod<T: $crate::pod::Pod>() {}
+            $(assert_pod::<$ty>();)*
+            assert!(
+                ::std::mem::size_of::<$name>() == 0 $(+ ::std::mem::size_of::<$ty>())*,
+                concat!(stringify!($name), " contains padding")
+            );
+        };
+
+        unsafe impl $crate::pod::Pod for $name {}
+    };
+}
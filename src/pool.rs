@@ -0,0 +1,418 @@
+use crate::kernel::CopyKernel;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+const CACHE_LINE_SIZE: usize = 64;
+const PAGE_SIZE: usize = 4096;
+
+/// Copies smaller than this amount of bytes are performed by the caller, as waking workers would take longer.
+pub const DEFAULT_INLINE_THRESHOLD: usize = 64 * 1024;
+
+/// A segment of a copy, executed by a single worker thread.
+struct Task {
+    src: *const u8,
+    dst: *mut u8,
+    length: usize,
+    kernel: CopyKernel,
+    completion: Arc<Completion>,
+}
+
+// Tasks are only created from buffers that are guaranteed to outlive their completion.
+unsafe impl Send for Task {}
+
+/// Tracks the amount of unfinished segments of a single copy.
+struct Completion {
+    remaining: AtomicUsize,
+    /// Waker of the task awaiting the copy, if it is awaited as a future.
+    waker: Mutex<Option<Waker>>,
+    done: Condvar,
+}
+
+impl Completion {
+    fn new(segments: usize) -> Arc<Self> {
+        Arc::new(Self {
+            remaining: AtomicUsize::new(segments),
+            waker: Mutex::new(None),
+            done: Condvar::new(),
+        })
+    }
+
+    fn finish_segment(&self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Taking the lock guarantees a waiter is either not yet checking, or already parked on the condvar.
+            let waker = self.waker.lock().unwrap().take();
+            self.done.notify_all();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.remaining.load(Ordering::Acquire) == 0
+    }
+
+    fn wait(&self) {
+        let mut guard = self.waker.lock().unwrap();
+        while !self.is_complete() {
+            guard = self.done.wait(guard).unwrap();
+        }
+    }
+}
+
+/// Handle to a copy that was submitted to a `CopyPool`.
+///
+/// The copy can be polled with `is_complete`, waited on with `wait`, or awaited as a `Future`.
+/// Dropping the handle does not cancel the copy.
+pub struct CopyHandle {
+    completion: Arc<Completion>,
+}
+
+impl CopyHandle {
+    pub(crate) fn completed() -> Self {
+        Self {
+            completion: Completion::new(0),
+        }
+    }
+
+    /// Creates a second handle to the same copy.
+    pub(crate) fn share(&self) -> Self {
+        Self {
+            completion: Arc::clone(&self.completion),
+        }
+    }
+
+    /// Returns true once every byte of the copy has been written.
+    pub fn is_complete(&self) -> bool {
+        self.completion.is_complete()
+    }
+
+    /// Blocks until every byte of the copy has been written.
+    pub fn wait(self) {
+        self.completion.wait();
+    }
+}
+
+impl Future for CopyHandle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_complete() {
+            return Poll::Ready(());
+        }
+        let mut waker = self.completion.waker.lock().unwrap();
+        // The last segment may have finished before the lock was taken. It takes the waker under the same lock.
+        if self.is_complete() {
+            return Poll::Ready(());
+        }
+        *waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Debug for CopyHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CopyHandle{{ complete: {:?} }}", self.is_complete())
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    tasks: VecDeque<Task>,
+    shutdown: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+/// A pool of worker threads that performs large copies in parallel.
+///
+/// A single pool can be shared between several `IvshmemDevice`s through `IvshmemDevice::set_copy_pool`.
+/// Copies are split into one segment per thread. The caller of a blocking copy executes one of the segments itself,
+/// and copies below the inline threshold are executed entirely by the caller.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use ivshmemmap::pool::CopyPool;
+///
+/// let pool = Arc::new(CopyPool::new(4));
+/// let src = vec![3u8; 1 << 20];
+/// let mut dst = vec![0u8; 1 << 20];
+/// pool.copy(&src, &mut dst);
+/// assert_eq!(src, dst);
+/// ```
+pub struct CopyPool {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    inline_threshold: usize,
+    kernel: CopyKernel,
+}
+
+impl Debug for CopyPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CopyPool{{ threads: {:?} inline_threshold: {:?} kernel: {:?} }}",
+            self.thread_count(),
+            self.inline_threshold,
+            self.kernel
+        )
+    }
+}
+
+impl CopyPool {
+    /// Creates a pool with the default inline threshold.
+    ///
+    /// # Arguments
+    ///
+    /// * `num_threads`: Total amount of threads copying in parallel, including the caller. Spawns `num_threads - 1` workers.
+    pub fn new(num_threads: usize) -> Self {
+        Self::with_inline_threshold(num_threads, DEFAULT_INLINE_THRESHOLD)
+    }
+
+    /// # Arguments
+    ///
+    /// * `num_threads`: Total amount of threads copying in parallel, including the caller. Spawns `num_threads - 1` workers.
+    /// * `inline_threshold`: Copies smaller than this amount of bytes are performed by the caller alone.
+    pub fn with_inline_threshold(num_threads: usize, inline_threshold: usize) -> Self {
+        assert!(num_threads > 0, "Tried to create a copy pool without threads. Requires at least 1.");
+
+        let shared = Arc::new(Shared::default());
+        let workers = (1..num_threads)
+            .map(|thread_id| {
+                let shared = Arc::clone(&shared);
+                std::thread::Builder::new()
+                    .name(format!("CopyWorker{thread_id}"))
+                    .spawn(move || Self::work(&shared))
+                    .expect("Unable to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            shared,
+            workers,
+            inline_threshold,
+            kernel: CopyKernel::Standard,
+        }
+    }
+
+    /// Uses `kernel` for all copies of this pool. Defaults to `CopyKernel::Standard`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ivshmemmap::kernel::CopyKernel;
+    /// use ivshmemmap::pool::CopyPool;
+    ///
+    /// // Streaming stores are the fastest way to fill write-combined memory.
+    /// let pool = CopyPool::new(4).with_kernel(CopyKernel::detect());
+    /// ```
+    pub fn with_kernel(mut self, kernel: CopyKernel) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    fn work(shared: &Shared) {
+        loop {
+            let task = {
+                let mut queue = shared.queue.lock().unwrap();
+                loop {
+                    if let Some(task) = queue.tasks.pop_front() {
+                        break task;
+                    }
+                    if queue.shutdown {
+                        return;
+                    }
+                    queue = shared.available.wait(queue).unwrap();
+                }
+            };
+            unsafe {
+                Self::execute(&task);
+            }
+        }
+    }
+
+    unsafe fn execute(task: &Task) {
+        task.kernel.copy(task.src, task.dst, task.length);
+        task.completion.finish_segment();
+    }
+
+    /// Total amount of threads copying in parallel, including the caller.
+    pub fn thread_count(&self) -> usize {
+        self.workers.len() + 1
+    }
+
+    pub fn inline_threshold(&self) -> usize {
+        self.inline_threshold
+    }
+
+    pub fn kernel(&self) -> CopyKernel {
+        self.kernel
+    }
+
+    /// Copies `src` into `dst` in parallel and blocks until the copy is complete.
+    /// Panics if the lengths of `src` and `dst` differ.
+    pub fn copy(&self, src: &[u8], dst: &mut [u8]) {
+        assert_eq!(src.len(), dst.len(), "Source and destination of a copy should be equal in size.");
+        unsafe {
+            self.copy_raw(src.as_ptr(), dst.as_mut_ptr(), src.len());
+        }
+    }
+
+    /// Copies `length` bytes in parallel and blocks until the copy is complete.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads and `dst` must be valid for writes of `length` bytes. The regions may not overlap.
+    pub unsafe fn copy_raw(&self, src: *const u8, dst: *mut u8, length: usize) {
+        if length < self.inline_threshold || self.workers.is_empty() {
+            self.kernel.copy(src, dst, length);
+            return;
+        }
+
+        // The caller takes the first segment, so it does not sit idle while the workers copy.
+        let mut tasks = self.split(src, dst, length);
+        let own = tasks.remove(0);
+        let completion = Arc::clone(&own.completion);
+        self.enqueue(tasks);
+        Self::execute(&own);
+        completion.wait();
+    }
+
+    /// Submits a copy to the workers and returns immediately.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads and `dst` must be valid for writes of `length` bytes, until the returned handle
+    /// reports completion. The regions may not overlap.
+    pub unsafe fn submit(&self, src: *const u8, dst: *mut u8, length: usize) -> CopyHandle {
+        if length < self.inline_threshold || self.workers.is_empty() {
+            self.kernel.copy(src, dst, length);
+            return CopyHandle::completed();
+        }
+
+        let tasks = self.split(src, dst, length);
+        let completion = Arc::clone(&tasks[0].completion);
+        self.enqueue(tasks);
+        CopyHandle { completion }
+    }
+
+    fn split(&self, src: *const u8, dst: *mut u8, length: usize) -> Vec<Task> {
+        let num_threads = self.thread_count();
+        let completion = Completion::new(num_threads);
+        (0..num_threads)
+            .map(|thread_num| {
+                let segment = segment(dst as usize, length, thread_num, num_threads);
+                Task {
+                    src: src.wrapping_add(segment.start),
+                    dst: dst.wrapping_add(segment.start),
+                    length: segment.len(),
+                    kernel: self.kernel,
+                    completion: Arc::clone(&completion),
+                }
+            })
+            .collect()
+    }
+
+    fn enqueue(&self, tasks: Vec<Task>) {
+        let count = tasks.len();
+        self.shared.queue.lock().unwrap().tasks.extend(tasks);
+        if count == 1 {
+            self.shared.available.notify_one();
+        } else {
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+impl Drop for CopyPool {
+    /// Lets the workers finish all queued copies, then joins them.
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Determines which part of a copy is handled by a single thread.
+///
+/// The boundaries between threads are aligned to the destination address, at page granularity for large copies
+/// and cache line granularity otherwise, so two threads never write to the same cache line.
+/// Together, the segments of all threads cover `0..length` exactly once. Some segments may be empty.
+///
+/// # Arguments
+///
+/// * `dst`: Address of the destination of the copy.
+/// * `length`: Total amount of bytes to copy.
+/// * `thread_num`: The thread to compute the segment for.
+/// * `num_threads`: Total amount of threads
+///
+/// returns: The byte range relative to the start of the copy.
+fn segment(dst: usize, length: usize, thread_num: usize, num_threads: usize) -> Range<usize> {
+    let alignment = if length / num_threads >= PAGE_SIZE {
+        PAGE_SIZE
+    } else {
+        CACHE_LINE_SIZE
+    };
+    let chunk = length.div_ceil(num_threads).max(alignment);
+    let boundary = |index: usize| {
+        if index == 0 {
+            0
+        } else if index >= num_threads {
+            length
+        } else {
+            ((dst + index * chunk).next_multiple_of(alignment) - dst).min(length)
+        }
+    };
+    boundary(thread_num)..boundary(thread_num + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_cover_every_length_and_thread_count() {
+        let mut lengths: Vec<usize> = (0..=520).collect();
+        for pages in 1usize..=64 {
+            for delta in [-65isize, -64, -63, -1, 0, 1, 63, 64, 65] {
+                lengths.push((pages * PAGE_SIZE).saturating_add_signed(delta));
+            }
+        }
+        lengths.extend([1 << 20, (1 << 20) + 1, (16 << 20) - 1]);
+
+        for dst in [0x10000, 0x10001, 0x1003F, 0x10040, 0x10FFF] {
+            for &length in &lengths {
+                for num_threads in 1..=17 {
+                    let alignment = if length / num_threads >= PAGE_SIZE { PAGE_SIZE } else { CACHE_LINE_SIZE };
+                    let mut covered = 0;
+                    for thread_num in 0..num_threads {
+                        let segment = segment(dst, length, thread_num, num_threads);
+                        let context = format!("dst={dst:#x} length={length} threads={num_threads} thread={thread_num}");
+                        assert_eq!(segment.start, covered, "gap or overlap: {context}");
+                        assert!(segment.start <= segment.end, "reversed segment: {context}");
+                        if segment.start != 0 && segment.start != length {
+                            assert_eq!((dst + segment.start) % alignment, 0, "unaligned boundary: {context}");
+                        }
+                        covered = segment.end;
+                    }
+                    assert_eq!(covered, length, "dst={dst:#x} length={length} threads={num_threads}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,535 @@
+//! Bounded multi producer, multi consumer queue of fixed-size slots inside shared memory.
+//!
+//! Based on Dmitry Vyukov's bounded MPMC queue: every slot carries a sequence number that tells producers and
+//! consumers whose turn it is, so peers in different processes or VMs only synchronize through atomics.
+//!
+//! | Offset | Contents                                                              |
+//! |--------|-----------------------------------------------------------------------|
+//! | 0      | magic (`u64`), version (`u32`), slot size (`u32`), slot count (`u64`) |
+//! | 64     | enqueue position (`AtomicU64`)                                        |
+//! | 128    | dequeue position (`AtomicU64`)                                        |
+//! | 192    | slots: sequence (`AtomicU64`), length (`u32`), padding, payload       |
+//!
+//! A producer that crashes between claiming a slot and publishing it would block all consumers forever.
+//! Consumers therefore skip a slot that stays claimed, or stays marked as being written, for longer than the
+//! abandon timeout. A producer that was skipped before it marked its slot never touches it. One that was skipped
+//! while copying finds out when publishing and receives `QueueError::Abandoned`, but may already have overwritten
+//! part of the next lap's message. The timeout must therefore be well above the time a live producer needs to copy
+//! a single message.
+//!
+//! # Examples
+//!
+//! ```
+//! use ivshmemmap::queue::{self, Queue};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-queue", 64 * 1024).unwrap();
+//! let mut producers_side = shm.map(1).unwrap();
+//! let mut consumer_side = shm.map(1).unwrap();
+//!
+//! queue::format(&mut producers_side[..], 64).unwrap();
+//! let producers = Queue::attach(&mut producers_side[..]).unwrap();
+//! let consumer = Queue::attach(&mut consumer_side[..]).unwrap();
+//!
+//! let mut received = std::thread::scope(|scope| {
+//!     for peer in 0..4u8 {
+//!         let producers = &producers;
+//!         scope.spawn(move || {
+//!             for index in 0..1000u16 {
+//!                 let mut message = vec![peer];
+//!                 message.extend_from_slice(&index.to_le_bytes());
+//!                 while producers.try_push(&message).is_err() {
+//!                     std::thread::yield_now();
+//!                 }
+//!             }
+//!         });
+//!     }
+//!
+//!     let mut received = Vec::new();
+//!     while received.len() < 4000 {
+//!         match consumer.try_pop().unwrap() {
+//!             Some(message) => received.push(message),
+//!             None => std::thread::yield_now(),
+//!         }
+//!     }
+//!     received
+//! });
+//! received.sort();
+//! received.dedup();
+//! assert_eq!(received.len(), 4000);
+//! ```
+//!
+//! A producer that died after claiming a slot does not block the queue:
+//!
+//! ```
+//! use std::time::Duration;
+//! use ivshmemmap::queue::{self, Queue};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-abandon", 4096).unwrap();
+//! let mut device = shm.map(1).unwrap();
+//! let mut crashed_peer = shm.map(1).unwrap();
+//!
+//! queue::format(&mut device[..], 16).unwrap();
+//! // Claim the first slot by advancing the enqueue position, then "crash" without publishing it.
+//! crashed_peer[64..72].copy_from_slice(&1u64.to_ne_bytes());
+//!
+//! let mut queue = Queue::attach(&mut device[..]).unwrap();
+//! queue.set_abandon_timeout(Duration::ZERO);
+//! queue.try_push(b"alive").unwrap();
+//!
+//! assert_eq!(queue.try_pop().unwrap(), None); // Notices the stalled slot.
+//! assert_eq!(queue.try_pop().unwrap(), Some(b"alive".to_vec()));
+//! ```
+
+use crate::error::QueueError;
+use crate::header;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// "IVSHMPMC" in ASCII.
+pub const QUEUE_MAGIC: u64 = u64::from_le_bytes(*b"IVSHMPMC");
+pub const QUEUE_VERSION: u32 = 1;
+/// Size of the header preceding the slots.
+pub const HEADER_SIZE: usize = 192;
+/// Consumers skip a slot that has been claimed but not published for this long.
+pub const DEFAULT_ABANDON_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 8;
+const SLOT_SIZE_OFFSET: usize = 12;
+const SLOT_COUNT_OFFSET: usize = 16;
+const ENQUEUE_OFFSET: usize = 64;
+const DEQUEUE_OFFSET: usize = 128;
+const SLOT_HEADER_SIZE: usize = 16;
+const SLOT_ALIGNMENT: usize = 64;
+/// Set in the sequence of a slot while its producer copies the message.
+const WRITING: u64 = 1 << 63;
+
+fn slot_stride(slot_size: usize) -> usize {
+    (SLOT_HEADER_SIZE + slot_size).next_multiple_of(SLOT_ALIGNMENT)
+}
+
+/// Writes a queue header into `region` and resets all slots, discarding any previous content.
+///
+/// # Arguments
+///
+/// * `region`: The shared memory to place the queue in. Must be aligned to 8 bytes.
+/// * `slot_size`: Maximum size of a single message in bytes.
+///
+/// returns: The amount of slots, which is the largest power of two that fits in the region.
+pub fn format(region: &mut [u8], slot_size: usize) -> Result<usize, QueueError> {
+    check_alignment(region)?;
+    let slot_size_field = u32::try_from(slot_size).map_err(|_| QueueError::RegionTooSmall(region.len()))?;
+    let available = region.len().saturating_sub(HEADER_SIZE) / slot_stride(slot_size);
+    if available == 0 {
+        return Err(QueueError::RegionTooSmall(region.len()));
+    }
+    let slot_count = 1usize << available.ilog2();
+
+    let queue = RawQueue::new(region.as_mut_ptr(), slot_size, slot_count);
+    unsafe {
+        for index in 0..slot_count {
+            queue.sequence(index).store(index as u64, Ordering::Relaxed);
+        }
+        queue.position(ENQUEUE_OFFSET).store(0, Ordering::Relaxed);
+        queue.position(DEQUEUE_OFFSET).store(0, Ordering::Relaxed);
+        let base = region.as_mut_ptr();
+        std::ptr::write_volatile(base.add(SLOT_COUNT_OFFSET) as *mut u64, slot_count as u64);
+        std::ptr::write_volatile(base.add(SLOT_SIZE_OFFSET) as *mut u32, slot_size_field);
+        std::ptr::write_volatile(base.add(VERSION_OFFSET) as *mut u32, QUEUE_VERSION);
+        header::publish(base.add(MAGIC_OFFSET), QUEUE_MAGIC);
+    }
+    Ok(slot_count)
+}
+
+fn check_alignment(region: &[u8]) -> Result<(), QueueError> {
+    if region.as_ptr().align_offset(std::mem::align_of::<AtomicU64>()) != 0 {
+        return Err(QueueError::Misaligned);
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone)]
+struct RawQueue {
+    base: *mut u8,
+    slot_size: usize,
+    slot_count: usize,
+}
+
+impl RawQueue {
+    fn new(base: *mut u8, slot_size: usize, slot_count: usize) -> Self {
+        Self {
+            base,
+            slot_size,
+            slot_count,
+        }
+    }
+
+    unsafe fn position(&self, offset: usize) -> &AtomicU64 {
+        &*(self.base.add(offset) as *const AtomicU64)
+    }
+
+    unsafe fn slot(&self, index: usize) -> *mut u8 {
+        self.base.add(HEADER_SIZE + index * slot_stride(self.slot_size))
+    }
+
+    unsafe fn sequence(&self, index: usize) -> &AtomicU64 {
+        &*(self.slot(index) as *const AtomicU64)
+    }
+
+    unsafe fn length(&self, index: usize) -> &AtomicU32 {
+        &*(self.slot(index).add(8) as *const AtomicU32)
+    }
+
+    unsafe fn payload(&self, index: usize) -> *mut u8 {
+        self.slot(index).add(SLOT_HEADER_SIZE)
+    }
+}
+
+/// A handle to a queue in shared memory. Every handle can both push and pop, from any amount of threads.
+pub struct Queue<'a> {
+    queue: RawQueue,
+    abandon_timeout: Duration,
+    /// The dequeue position at which a claimed but unpublished slot was first seen, and when.
+    stall: Mutex<Option<(u64, Instant)>>,
+    _region: PhantomData<&'a mut [u8]>,
+}
+
+// All shared state lives in the region and is only accessed through atomics, or by the peer that owns a slot.
+unsafe impl Send for Queue<'_> {}
+unsafe impl Sync for Queue<'_> {}
+
+impl Debug for Queue<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Queue{{ slots: {:?} slot_size: {:?} }}",
+            self.queue.slot_count, self.queue.slot_size
+        )
+    }
+}
+
+impl<'a> Queue<'a> {
+    /// Attaches to a queue that was formatted with `format`, possibly by another process.
+    pub fn attach(region: &'a mut [u8]) -> Result<Self, QueueError> {
+        check_alignment(region)?;
+        if region.len() < HEADER_SIZE {
+            return Err(QueueError::RegionTooSmall(region.len()));
+        }
+        let base = region.as_mut_ptr();
+        let (slot_size, slot_count) = unsafe {
+            if !header::is_published(base.add(MAGIC_OFFSET), QUEUE_MAGIC) {
+                return Err(QueueError::NotFormatted);
+            }
+            let version = std::ptr::read_volatile(base.add(VERSION_OFFSET) as *const u32);
+            if version != QUEUE_VERSION {
+                return Err(QueueError::UnsupportedVersion(version));
+            }
+            (
+                std::ptr::read_volatile(base.add(SLOT_SIZE_OFFSET) as *const u32) as usize,
+                std::ptr::read_volatile(base.add(SLOT_COUNT_OFFSET) as *const u64) as usize,
+            )
+        };
+        let fits = slot_count
+            .checked_mul(slot_stride(slot_size))
+            .is_some_and(|size| size <= region.len() - HEADER_SIZE);
+        if !slot_count.is_power_of_two() || !fits {
+            return Err(QueueError::Corrupted);
+        }
+
+        Ok(Self {
+            queue: RawQueue::new(base, slot_size, slot_count),
+            abandon_timeout: DEFAULT_ABANDON_TIMEOUT,
+            stall: Mutex::new(None),
+            _region: PhantomData,
+        })
+    }
+
+    /// How long a claimed slot may remain unpublished before consumers consider its producer dead and skip it.
+    /// A producer that is merely slower than this loses its message, and receives `QueueError::Abandoned`.
+    pub fn set_abandon_timeout(&mut self, timeout: Duration) {
+        self.abandon_timeout = timeout;
+    }
+
+    /// Maximum size of a single message in bytes.
+    pub fn slot_size(&self) -> usize {
+        self.queue.slot_size
+    }
+
+    /// Maximum amount of messages in the queue.
+    pub fn slot_count(&self) -> usize {
+        self.queue.slot_count
+    }
+
+    /// Appends `message` to the queue without blocking.
+    ///
+    /// returns: `QueueError::Full` if every slot is occupied.
+    pub fn try_push(&self, message: &[u8]) -> Result<(), QueueError> {
+        if message.len() > self.queue.slot_size {
+            return Err(QueueError::MessageTooLarge {
+                length: message.len(),
+                slot_size: self.queue.slot_size,
+            });
+        }
+        unsafe {
+            let (index, position) = self.claim()?;
+            self.write(index, position, message)
+        }
+    }
+
+    /// Claims the slot at the enqueue position for a producer.
+    unsafe fn claim(&self) -> Result<(usize, u64), QueueError> {
+        let mask = self.queue.slot_count - 1;
+        let enqueue = self.queue.position(ENQUEUE_OFFSET);
+        let mut position = enqueue.load(Ordering::Relaxed);
+        loop {
+            let index = position as usize & mask;
+            let sequence = self.queue.sequence(index).load(Ordering::Acquire);
+            if sequence & WRITING != 0 {
+                return Err(QueueError::Full); // The producer of the previous lap is still writing, or died doing so.
+            }
+            match (sequence.wrapping_sub(position) as i64).cmp(&0) {
+                std::cmp::Ordering::Equal => {
+                    match enqueue.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => return Ok((index, position)),
+                        Err(current) => position = current,
+                    }
+                }
+                std::cmp::Ordering::Less => return Err(QueueError::Full),
+                std::cmp::Ordering::Greater => position = enqueue.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Copies `message` into a slot we claimed as producer, and publishes it to the consumers.
+    unsafe fn write(&self, index: usize, position: u64, message: &[u8]) -> Result<(), QueueError> {
+        self.mark(index, position)?;
+        std::ptr::copy_nonoverlapping(message.as_ptr(), self.queue.payload(index), message.len());
+        self.publish(index, position, message.len())
+    }
+
+    /// Marks a slot we claimed as being written, before touching it. A consumer may have given up on us and skipped
+    /// the slot, after which it belongs to a producer of the next lap.
+    unsafe fn mark(&self, index: usize, position: u64) -> Result<(), QueueError> {
+        self.queue
+            .sequence(index)
+            .compare_exchange(position, position | WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|_| QueueError::Abandoned)
+    }
+
+    /// Hands a slot we marked over to the consumers, unless they skipped it while we were writing.
+    unsafe fn publish(&self, index: usize, position: u64, length: usize) -> Result<(), QueueError> {
+        self.queue.length(index).store(length as u32, Ordering::Relaxed);
+        self.queue
+            .sequence(index)
+            .compare_exchange(position | WRITING, position + 1, Ordering::Release, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|_| QueueError::Abandoned)
+    }
+
+    /// Copies the next message into `buf` without blocking.
+    ///
+    /// returns: The length of the message, `None` if the queue is empty, or `QueueError::BufferTooSmall`
+    ///          if the message does not fit. The message is only consumed if it was copied.
+    pub fn try_pop_into(&self, buf: &mut [u8]) -> Result<Option<usize>, QueueError> {
+        let mask = self.queue.slot_count - 1;
+        unsafe {
+            let dequeue = self.queue.position(DEQUEUE_OFFSET);
+            let mut position = dequeue.load(Ordering::Relaxed);
+            loop {
+                let index = position as usize & mask;
+                let sequence = self.queue.sequence(index).load(Ordering::Acquire);
+                if sequence == position | WRITING {
+                    // The producer is copying its message, or died doing so.
+                    if !self.is_abandoned(position) {
+                        return Ok(None);
+                    }
+                    self.skip(index, position, sequence);
+                    position = dequeue.load(Ordering::Relaxed);
+                    continue;
+                }
+                match (sequence.wrapping_sub(position + 1) as i64).cmp(&0) {
+                    std::cmp::Ordering::Equal => {
+                        let length = self.queue.length(index).load(Ordering::Relaxed) as usize;
+                        if length > self.queue.slot_size {
+                            return Err(QueueError::Corrupted);
+                        }
+                        if length > buf.len() {
+                            return Err(QueueError::BufferTooSmall { needed: length });
+                        }
+                        match dequeue.compare_exchange_weak(position, position + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                            Ok(_) => return Ok(Some(self.take(index, position, &mut buf[..length]))),
+                            Err(current) => position = current,
+                        }
+                    }
+                    std::cmp::Ordering::Less => {
+                        if sequence != position || !self.is_abandoned(position) {
+                            return Ok(None);
+                        }
+                        self.skip(index, position, sequence);
+                        position = dequeue.load(Ordering::Relaxed);
+                    }
+                    std::cmp::Ordering::Greater => position = dequeue.load(Ordering::Relaxed),
+                }
+            }
+        }
+    }
+
+    /// Receives the next message without blocking.
+    ///
+    /// returns: The message, or `None` if the queue is empty.
+    pub fn try_pop(&self) -> Result<Option<Vec<u8>>, QueueError> {
+        let mut buf = vec![0u8; self.queue.slot_size];
+        Ok(self.try_pop_into(&mut buf)?.map(|length| {
+            buf.truncate(length);
+            buf
+        }))
+    }
+
+    /// Copies a slot we claimed as consumer into `buf`, and releases it to the producers of the next lap.
+    ///
+    /// `buf` must be exactly as long as the message, as checked against the slot size before claiming it. The
+    /// length in shared memory is not read again, as a peer may have changed it since.
+    unsafe fn take(&self, index: usize, position: u64, buf: &mut [u8]) -> usize {
+        std::ptr::copy_nonoverlapping(self.queue.payload(index), buf.as_mut_ptr(), buf.len());
+        self.queue
+            .sequence(index)
+            .store(position + self.queue.slot_count as u64, Ordering::Release);
+        buf.len()
+    }
+
+    /// Returns true if the slot at `position` is claimed by a producer, and has not been published in time.
+    /// The time is measured from the moment a consumer first saw the slot unpublished, so it also covers producers
+    /// that died after marking the slot as being written.
+    unsafe fn is_abandoned(&self, position: u64) -> bool {
+        if self.queue.position(ENQUEUE_OFFSET).load(Ordering::Relaxed) <= position {
+            return false; // Nobody claimed the slot. The queue is simply empty.
+        }
+        let mut stall = self.stall.lock().unwrap();
+        match *stall {
+            Some((stalled, since)) if stalled == position => since.elapsed() >= self.abandon_timeout,
+            _ => {
+                *stall = Some((position, Instant::now()));
+                false
+            }
+        }
+    }
+
+    /// Skips the abandoned slot at `position`, unless its producer or another consumer got to it first.
+    ///
+    /// # Arguments
+    ///
+    /// * `seen`: The sequence the slot had when it was found abandoned, either claimed or marked as being written.
+    unsafe fn skip(&self, index: usize, position: u64, seen: u64) {
+        // Releasing the slot before moving on, so a producer that wakes up later fails to mark or publish it. If the
+        // producer made progress since, the slot is left alone and consumed as usual.
+        let released = position + self.queue.slot_count as u64;
+        if self
+            .queue
+            .sequence(index)
+            .compare_exchange(seen, released, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            // Only the consumer that released the slot may move past it, so this cannot fail.
+            let _ = self.queue.position(DEQUEUE_OFFSET).compare_exchange(
+                position,
+                position + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A region with room for exactly one slot of 16 bytes, so every lap reuses the same slot.
+    fn region() -> Vec<u64> {
+        vec![0; (HEADER_SIZE + SLOT_ALIGNMENT) / 8]
+    }
+
+    fn bytes(region: &mut [u64]) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(region.as_mut_ptr() as *mut u8, region.len() * 8) }
+    }
+
+    #[test]
+    fn stalled_producer_does_not_overwrite_the_next_lap() {
+        let mut region = region();
+        assert_eq!(format(bytes(&mut region), 16).unwrap(), 1);
+        let mut queue = Queue::attach(bytes(&mut region)).unwrap();
+        queue.set_abandon_timeout(Duration::ZERO);
+
+        // The slow producer claims the slot, then stalls before writing anything.
+        let (index, position) = unsafe { queue.claim() }.unwrap();
+        assert_eq!(queue.try_pop().unwrap(), None); // Notices the stalled slot.
+        assert_eq!(queue.try_pop().unwrap(), None); // Skips it.
+        queue.try_push(b"next lap").unwrap();
+
+        assert!(matches!(unsafe { queue.write(index, position, b"stale") }, Err(QueueError::Abandoned)));
+        assert_eq!(queue.try_pop().unwrap(), Some(b"next lap".to_vec()));
+        assert_eq!(queue.try_pop().unwrap(), None);
+    }
+
+    #[test]
+    fn slot_being_written_is_kept_within_the_abandon_timeout() {
+        let mut region = region();
+        format(bytes(&mut region), 16).unwrap();
+        let queue = Queue::attach(bytes(&mut region)).unwrap();
+
+        // The producer claimed the slot and is copying its message.
+        let (index, position) = unsafe { queue.claim() }.unwrap();
+        unsafe { queue.mark(index, position) }.unwrap();
+        for _ in 0..3 {
+            assert_eq!(queue.try_pop().unwrap(), None);
+        }
+        assert!(matches!(queue.try_push(b"full"), Err(QueueError::Full)));
+
+        unsafe { queue.publish(index, position, 0) }.unwrap();
+        assert_eq!(queue.try_pop().unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn producer_killed_mid_copy_is_skipped() {
+        let mut region = region();
+        format(bytes(&mut region), 16).unwrap();
+        let mut queue = Queue::attach(bytes(&mut region)).unwrap();
+        queue.set_abandon_timeout(Duration::ZERO);
+
+        // The producer marks the slot, copies half of its message and dies.
+        let (index, position) = unsafe { queue.claim() }.unwrap();
+        unsafe {
+            queue.mark(index, position).unwrap();
+            std::ptr::copy_nonoverlapping(b"half".as_ptr(), queue.queue.payload(index), 4);
+        }
+        assert!(matches!(queue.try_push(b"blocked"), Err(QueueError::Full)));
+        assert_eq!(queue.try_pop().unwrap(), None); // Notices the stalled slot.
+        assert_eq!(queue.try_pop().unwrap(), None); // Skips it.
+
+        queue.try_push(b"next lap").unwrap();
+        assert_eq!(queue.try_pop().unwrap(), Some(b"next lap".to_vec()));
+        // Had the producer merely been slow, it learns about it when publishing.
+        assert!(matches!(unsafe { queue.publish(index, position, 4) }, Err(QueueError::Abandoned)));
+    }
+
+    #[test]
+    fn length_changed_after_the_check_is_not_trusted() {
+        let mut region = region();
+        format(bytes(&mut region), 16).unwrap();
+        let queue = Queue::attach(bytes(&mut region)).unwrap();
+        queue.try_push(b"abcd").unwrap();
+
+        // What try_pop_into checked, before a hostile peer rewrites the length in shared memory.
+        let checked = unsafe { queue.queue.length(0) }.load(Ordering::Relaxed) as usize;
+        assert_eq!(checked, 4);
+        unsafe { queue.queue.length(0) }.store(u32::MAX, Ordering::Relaxed);
+
+        let mut buf = [0xAAu8; 8];
+        assert_eq!(unsafe { queue.take(0, 0, &mut buf[..checked]) }, 4);
+        assert_eq!(&buf, b"abcd\xAA\xAA\xAA\xAA");
+    }
+}
@@ -0,0 +1,270 @@
+//! Handles to parts of the shared memory, which can be split and sent to other threads.
+//!
+//! # Examples
+//!
+//! ```
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-region", 4 * 4096).unwrap();
+//! let mut device = shm.map(2).unwrap();
+//!
+//! // Every thread owns its own frame slot.
+//! let slots = device.as_region_mut().chunks_mut(4096);
+//! std::thread::scope(|scope| {
+//!     for (index, mut slot) in slots.into_iter().enumerate() {
+//!         scope.spawn(move || slot.write_at(0, &[index as u8; 4096]).unwrap());
+//!     }
+//! });
+//!
+//! let (first, rest) = device.as_region().split_at(4096).unwrap();
+//! assert!(first.iter().all(|&byte| byte == 0));
+//! assert_eq!((rest.offset(), rest[4096]), (4096, 2));
+//! ```
+
+use crate::device::check_bounds;
+use crate::error::DeviceError;
+use crate::pool::CopyPool;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
+
+/// Accesses the 4 bytes at `offset` of a region as an atomic.
+fn atomic_u32<'r>(memory: *const u8, length: usize, offset: usize) -> Result<&'r AtomicU32, DeviceError> {
+    check_bounds(offset, 4, length)?;
+    let pointer = unsafe { memory.add(offset) };
+    if pointer.align_offset(4) != 0 {
+        return Err(DeviceError::Misaligned { offset, alignment: 4 });
+    }
+    Ok(unsafe { AtomicU32::from_ptr(pointer as *mut u32) })
+}
+
+/// Shared, read-only access to part of the shared memory of an `IvshmemDevice`.
+///
+/// All offsets are relative to the start of the region, and accesses cannot reach outside of it.
+/// Copies use the worker threads of the device the region was taken from.
+#[derive(Clone)]
+pub struct Region<'a> {
+    memory: *const u8,
+    length: usize,
+    offset: usize,
+    pool: Arc<CopyPool>,
+    _memory: PhantomData<&'a [u8]>,
+}
+
+// A region behaves like the `&[u8]` it was created from.
+unsafe impl Send for Region<'_> {}
+unsafe impl Sync for Region<'_> {}
+
+impl Debug for Region<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Region{{ offset: {:?} length: {:?} }}", self.offset, self.length)
+    }
+}
+
+impl<'a> Region<'a> {
+    /// # Safety
+    ///
+    /// `memory` must be valid for reads of `length` bytes for `'a`, and not be written through anything else during
+    /// that time, except by the other side of the shared memory.
+    pub(crate) unsafe fn new(memory: *const u8, length: usize, offset: usize, pool: Arc<CopyPool>) -> Self {
+        Self {
+            memory,
+            length,
+            offset,
+            pool,
+            _memory: PhantomData,
+        }
+    }
+
+    /// Offset of the region from the start of the shared memory.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Divides the region into `0..mid` and `mid..len()`.
+    ///
+    /// returns: An error if `mid` is larger than the region.
+    pub fn split_at(self, mid: usize) -> Result<(Region<'a>, Region<'a>), DeviceError> {
+        check_bounds(mid, 0, self.length)?;
+        unsafe {
+            Ok((
+                Region::new(self.memory, mid, self.offset, Arc::clone(&self.pool)),
+                Region::new(self.memory.add(mid), self.length - mid, self.offset + mid, self.pool),
+            ))
+        }
+    }
+
+    /// The part of the region at `offset`, of `length` bytes.
+    pub fn sub_region(&self, offset: usize, length: usize) -> Result<Region<'a>, DeviceError> {
+        check_bounds(offset, length, self.length)?;
+        unsafe {
+            Ok(Region::new(
+                self.memory.add(offset),
+                length,
+                self.offset + offset,
+                Arc::clone(&self.pool),
+            ))
+        }
+    }
+
+    /// Copies `buf.len()` bytes of the region starting at `offset` into `buf`, using all worker threads.
+    ///
+    /// returns: An error if the read range does not fit inside the region. `buf` is untouched in that case.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), DeviceError> {
+        check_bounds(offset, buf.len(), self.length)?;
+        unsafe {
+            self.pool.copy_raw(self.memory.add(offset), buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    /// Accesses the 4 bytes at `offset` as an atomic, e.g. for a lock. Must be aligned to 4 bytes.
+    pub fn atomic_u32(&self, offset: usize) -> Result<&'a AtomicU32, DeviceError> {
+        atomic_u32(self.memory, self.length, offset)
+    }
+}
+
+impl Deref for Region<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.memory, self.length) }
+    }
+}
+
+/// Exclusive access to part of the shared memory of an `IvshmemDevice`.
+///
+/// All offsets are relative to the start of the region, and accesses cannot reach outside of it.
+/// Copies use the worker threads of the device the region was taken from.
+pub struct RegionMut<'a> {
+    memory: *mut u8,
+    length: usize,
+    offset: usize,
+    pool: Arc<CopyPool>,
+    _memory: PhantomData<&'a mut [u8]>,
+}
+
+// A region behaves like the `&mut [u8]` it was created from.
+unsafe impl Send for RegionMut<'_> {}
+unsafe impl Sync for RegionMut<'_> {}
+
+impl Debug for RegionMut<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RegionMut{{ offset: {:?} length: {:?} }}", self.offset, self.length)
+    }
+}
+
+impl<'a> RegionMut<'a> {
+    /// # Safety
+    ///
+    /// `memory` must be valid for reads and writes of `length` bytes for `'a`, and not be accessed through anything
+    /// else during that time, except by the other side of the shared memory.
+    pub(crate) unsafe fn new(memory: *mut u8, length: usize, offset: usize, pool: Arc<CopyPool>) -> Self {
+        Self {
+            memory,
+            length,
+            offset,
+            pool,
+            _memory: PhantomData,
+        }
+    }
+
+    /// Offset of the region from the start of the shared memory.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Divides the region into `0..mid` and `mid..len()`, which can be used independently.
+    ///
+    /// returns: An error if `mid` is larger than the region.
+    pub fn split_at_mut(self, mid: usize) -> Result<(RegionMut<'a>, RegionMut<'a>), DeviceError> {
+        check_bounds(mid, 0, self.length)?;
+        unsafe {
+            Ok((
+                RegionMut::new(self.memory, mid, self.offset, Arc::clone(&self.pool)),
+                RegionMut::new(self.memory.add(mid), self.length - mid, self.offset + mid, self.pool),
+            ))
+        }
+    }
+
+    /// Divides the region into consecutive regions of `chunk_size` bytes. The last region may be shorter.
+    /// Panics if `chunk_size` is 0.
+    pub fn chunks_mut(self, chunk_size: usize) -> Vec<RegionMut<'a>> {
+        assert_ne!(chunk_size, 0, "Chunk size must be larger than 0.");
+        (0..self.length)
+            .step_by(chunk_size)
+            .map(|start| unsafe {
+                RegionMut::new(
+                    self.memory.add(start),
+                    chunk_size.min(self.length - start),
+                    self.offset + start,
+                    Arc::clone(&self.pool),
+                )
+            })
+            .collect()
+    }
+
+    /// Borrows the part of the region at `offset`, of `length` bytes.
+    pub fn sub_region_mut(&mut self, offset: usize, length: usize) -> Result<RegionMut<'_>, DeviceError> {
+        check_bounds(offset, length, self.length)?;
+        unsafe {
+            Ok(RegionMut::new(
+                self.memory.add(offset),
+                length,
+                self.offset + offset,
+                Arc::clone(&self.pool),
+            ))
+        }
+    }
+
+    /// Borrows the region for reading.
+    pub fn as_region(&self) -> Region<'_> {
+        unsafe { Region::new(self.memory, self.length, self.offset, Arc::clone(&self.pool)) }
+    }
+
+    /// Gives up write access. The returned region can be cloned and shared between threads.
+    pub fn into_region(self) -> Region<'a> {
+        unsafe { Region::new(self.memory, self.length, self.offset, self.pool) }
+    }
+
+    /// Copies `buf` into the region at `offset`, using all worker threads.
+    ///
+    /// returns: An error if the write range does not fit inside the region. Nothing is written in that case.
+    pub fn write_at(&mut self, offset: usize, buf: &[u8]) -> Result<(), DeviceError> {
+        check_bounds(offset, buf.len(), self.length)?;
+        unsafe {
+            self.pool.copy_raw(buf.as_ptr(), self.memory.add(offset), buf.len());
+        }
+        Ok(())
+    }
+
+    /// Copies `buf.len()` bytes of the region starting at `offset` into `buf`, using all worker threads.
+    ///
+    /// returns: An error if the read range does not fit inside the region. `buf` is untouched in that case.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<(), DeviceError> {
+        check_bounds(offset, buf.len(), self.length)?;
+        unsafe {
+            self.pool.copy_raw(self.memory.add(offset), buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    /// Accesses the 4 bytes at `offset` as an atomic, e.g. for a lock. Must be aligned to 4 bytes.
+    pub fn atomic_u32(&self, offset: usize) -> Result<&AtomicU32, DeviceError> {
+        atomic_u32(self.memory, self.length, offset)
+    }
+}
+
+impl Deref for RegionMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.memory, self.length) }
+    }
+}
+
+impl DerefMut for RegionMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.memory, self.length) }
+    }
+}
@@ -0,0 +1,94 @@
+//! The registers of an ivshmem PCI device, found in BAR0.
+//!
+//! | Offset | Register     | Access     | Contents                                                          |
+//! |--------|--------------|------------|-------------------------------------------------------------------|
+//! | 0x00   | IntrMask     | read/write | interrupt mask, used with pin based interrupts only               |
+//! | 0x04   | IntrStatus   | read/write | interrupt status, reading clears it. Pin based interrupts only    |
+//! | 0x08   | IVPosition   | read only  | our peer ID, or -1 without an ivshmem-server                      |
+//! | 0x0C   | Doorbell     | write only | `peer << 16 \| vector` raises `vector` on `peer`                  |
+//!
+//! # Examples
+//!
+//! Any mapping can serve as the BAR, which makes the register layer testable with a file:
+//!
+//! ```
+//! use ivshmemmap::registers::{Registers, DOORBELL, IV_POSITION};
+//!
+//! let bar = ivshmemmap::SharedMemory::scratch("doc-registers", 256).unwrap();
+//! let mut fake = bar.map(1).unwrap();
+//! let mut registers = Registers::new(bar.map(1).unwrap()).unwrap();
+//!
+//! assert_eq!(registers.position(), Some(0));
+//! fake.write_volatile(IV_POSITION, -1i32).unwrap();
+//! assert_eq!(registers.position(), None);
+//!
+//! registers.ring(3, 1);
+//! assert_eq!(fake.read_volatile::<u32>(DOORBELL).unwrap(), 0x0003_0001);
+//! ```
+
+use crate::device::{check_bounds, IvshmemDevice};
+use crate::error::DeviceError;
+use std::fmt::{Debug, Formatter};
+
+pub const INTERRUPT_MASK: usize = 0x00;
+pub const INTERRUPT_STATUS: usize = 0x04;
+pub const IV_POSITION: usize = 0x08;
+pub const DOORBELL: usize = 0x0C;
+/// Size of the register block. BAR0 is usually larger, the remainder is reserved.
+pub const REGISTERS_SIZE: usize = 16;
+
+/// Volatile access to the registers of an ivshmem device.
+pub struct Registers {
+    bar: IvshmemDevice,
+}
+
+impl Debug for Registers {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Registers{{ position: {:?} }}", self.position())
+    }
+}
+
+impl Registers {
+    /// Accesses the registers in `bar`, which must be BAR0 of the device, or something that pretends to be.
+    ///
+    /// returns: An error if `bar` is smaller than the register block.
+    pub fn new(bar: IvshmemDevice) -> Result<Self, DeviceError> {
+        check_bounds(0, REGISTERS_SIZE, bar.len())?;
+        Ok(Self { bar })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        self.bar
+            .read_volatile(offset)
+            .expect("Registers are within the bounds checked by new")
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        self.bar
+            .write_volatile(offset, value)
+            .expect("Registers are within the bounds checked by new")
+    }
+
+    pub fn interrupt_mask(&self) -> u32 {
+        self.read(INTERRUPT_MASK)
+    }
+
+    pub fn set_interrupt_mask(&mut self, mask: u32) {
+        self.write(INTERRUPT_MASK, mask)
+    }
+
+    /// Reads and thereby clears the pending pin based interrupts.
+    pub fn take_interrupt_status(&mut self) -> u32 {
+        self.read(INTERRUPT_STATUS)
+    }
+
+    /// Our own peer ID, as assigned by the ivshmem-server. `None` if the device is not connected to a server.
+    pub fn position(&self) -> Option<u16> {
+        u16::try_from(self.read(IV_POSITION) as i32).ok()
+    }
+
+    /// Rings the doorbell of `peer`, raising interrupt `vector` on its side.
+    pub fn ring(&mut self, peer: u16, vector: u16) {
+        self.write(DOORBELL, (peer as u32) << 16 | vector as u32)
+    }
+}
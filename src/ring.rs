@@ -0,0 +1,395 @@
+//! Single producer, single consumer byte ring buffer inside shared memory.
+//!
+//! The ring occupies a region of the mapping, starting with a header:
+//!
+//! | Offset | Contents                                                     |
+//! |--------|--------------------------------------------------------------|
+//! | 0      | magic (`u64`), version (`u32`), reserved (`u32`), capacity (`u64`) |
+//! | 64     | head (`AtomicU64`): total bytes written, owned by the producer |
+//! | 128    | tail (`AtomicU64`): total bytes read, owned by the consumer  |
+//! | 192    | data, `capacity` bytes where capacity is a power of two      |
+//!
+//! Every message is framed by a little endian `u32` length, and may wrap around the end of the data area.
+//! Both sides may live in different processes or VMs, as long as they map the same memory.
+//!
+//! # Examples
+//!
+//! Two mappings of one file behave like two processes attached to the same /dev/shm file:
+//!
+//! ```
+//! use ivshmemmap::ring::{self, Consumer, Producer};
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-ring", 4096).unwrap();
+//! let mut sending_side = shm.map(1).unwrap();
+//! let mut receiving_side = shm.map(1).unwrap();
+//!
+//! ring::format(&mut sending_side[..1024]).unwrap();
+//! let mut consumer = Consumer::attach(&mut receiving_side[..1024]).unwrap();
+//!
+//! let sender = std::thread::spawn(move || {
+//!     let mut producer = Producer::attach(&mut sending_side[..1024]).unwrap();
+//!     for index in 0..10_000u32 {
+//!         let message = vec![index as u8; index as usize % 300];
+//!         while producer.try_send(&message).is_err() {
+//!             std::thread::yield_now();
+//!         }
+//!     }
+//! });
+//!
+//! for index in 0..10_000u32 {
+//!     let message = loop {
+//!         if let Some(message) = consumer.try_recv().unwrap() {
+//!             break message;
+//!         }
+//!         std::thread::yield_now();
+//!     };
+//!     assert_eq!(message, vec![index as u8; index as usize % 300]);
+//! }
+//! sender.join().unwrap();
+//! ```
+
+use crate::error::RingError;
+use crate::header;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// "IVSHRING" in ASCII.
+pub const RING_MAGIC: u64 = u64::from_le_bytes(*b"IVSHRING");
+pub const RING_VERSION: u32 = 1;
+/// Size of the header preceding the data area.
+pub const HEADER_SIZE: usize = 192;
+
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 8;
+const CAPACITY_OFFSET: usize = 16;
+const HEAD_OFFSET: usize = 64;
+const TAIL_OFFSET: usize = 128;
+const FRAME_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Writes a ring header into `region`, discarding any previous content.
+/// The data area is the largest power of two that fits after the header.
+///
+/// returns: The capacity of the data area in bytes.
+pub fn format(region: &mut [u8]) -> Result<usize, RingError> {
+    check_alignment(region)?;
+    let available = region.len().saturating_sub(HEADER_SIZE);
+    if available < FRAME_SIZE * 2 {
+        return Err(RingError::RegionTooSmall(region.len()));
+    }
+    let capacity = 1usize << available.ilog2();
+
+    let ring = RawRing::new(region.as_mut_ptr(), capacity);
+    unsafe {
+        ring.head().store(0, Ordering::Relaxed);
+        ring.tail().store(0, Ordering::Relaxed);
+        std::ptr::write_volatile(region.as_mut_ptr().add(CAPACITY_OFFSET) as *mut u64, capacity as u64);
+        std::ptr::write_volatile(region.as_mut_ptr().add(VERSION_OFFSET) as *mut u32, RING_VERSION);
+        header::publish(region.as_mut_ptr().add(MAGIC_OFFSET), RING_MAGIC);
+    }
+    Ok(capacity)
+}
+
+/// Formats `region` and splits it into both halves of the ring, for use within a single process.
+pub fn channel(region: &mut [u8]) -> Result<(Producer<'_>, Consumer<'_>), RingError> {
+    format(region)?;
+    let ring = attach(region)?;
+    Ok((
+        Producer {
+            ring,
+            _region: PhantomData,
+        },
+        Consumer {
+            ring,
+            _region: PhantomData,
+        },
+    ))
+}
+
+fn check_alignment(region: &[u8]) -> Result<(), RingError> {
+    if region.as_ptr().align_offset(std::mem::align_of::<AtomicU64>()) != 0 {
+        return Err(RingError::Misaligned);
+    }
+    Ok(())
+}
+
+fn attach(region: &mut [u8]) -> Result<RawRing, RingError> {
+    check_alignment(region)?;
+    if region.len() < HEADER_SIZE {
+        return Err(RingError::RegionTooSmall(region.len()));
+    }
+    let base = region.as_mut_ptr();
+    unsafe {
+        if !header::is_published(base.add(MAGIC_OFFSET), RING_MAGIC) {
+            return Err(RingError::NotFormatted);
+        }
+        let version = std::ptr::read_volatile(base.add(VERSION_OFFSET) as *const u32);
+        if version != RING_VERSION {
+            return Err(RingError::UnsupportedVersion(version));
+        }
+        let capacity = std::ptr::read_volatile(base.add(CAPACITY_OFFSET) as *const u64) as usize;
+        if !capacity.is_power_of_two() || capacity > region.len() - HEADER_SIZE {
+            return Err(RingError::Corrupted);
+        }
+        Ok(RawRing::new(base, capacity))
+    }
+}
+
+/// Pointers into a formatted ring. Validity is guaranteed by the lifetime of the `Producer` or `Consumer`.
+#[derive(Copy, Clone)]
+struct RawRing {
+    base: *mut u8,
+    capacity: usize,
+}
+
+impl RawRing {
+    fn new(base: *mut u8, capacity: usize) -> Self {
+        Self { base, capacity }
+    }
+
+    fn head(&self) -> &AtomicU64 {
+        unsafe { &*(self.base.add(HEAD_OFFSET) as *const AtomicU64) }
+    }
+
+    fn tail(&self) -> &AtomicU64 {
+        unsafe { &*(self.base.add(TAIL_OFFSET) as *const AtomicU64) }
+    }
+
+    /// Copies `src` into the data area at `position`, wrapping around the end.
+    unsafe fn write(&self, position: u64, src: &[u8]) {
+        let data = self.base.add(HEADER_SIZE);
+        let index = position as usize & (self.capacity - 1);
+        let first = src.len().min(self.capacity - index);
+        std::ptr::copy_nonoverlapping(src.as_ptr(), data.add(index), first);
+        std::ptr::copy_nonoverlapping(src.as_ptr().add(first), data, src.len() - first);
+    }
+
+    /// Copies the data area at `position` into `dst`, wrapping around the end.
+    unsafe fn read(&self, position: u64, dst: &mut [u8]) {
+        let data = self.base.add(HEADER_SIZE);
+        let index = position as usize & (self.capacity - 1);
+        let first = dst.len().min(self.capacity - index);
+        std::ptr::copy_nonoverlapping(data.add(index), dst.as_mut_ptr(), first);
+        std::ptr::copy_nonoverlapping(data, dst.as_mut_ptr().add(first), dst.len() - first);
+    }
+}
+
+/// The sending half of a ring. There must be at most one producer per ring.
+pub struct Producer<'a> {
+    ring: RawRing,
+    _region: PhantomData<&'a mut [u8]>,
+}
+
+// The producer only writes the head and the free part of the data area, which the consumer never touches.
+unsafe impl Send for Producer<'_> {}
+
+impl Debug for Producer<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Producer{{ capacity: {:?} free: {:?} }}", self.ring.capacity, self.free())
+    }
+}
+
+impl<'a> Producer<'a> {
+    /// Attaches to a ring that was formatted with `format`, possibly by another process.
+    pub fn attach(region: &'a mut [u8]) -> Result<Self, RingError> {
+        Ok(Self {
+            ring: attach(region)?,
+            _region: PhantomData,
+        })
+    }
+
+    /// Size of the data area. A message occupies 4 bytes more than its length.
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity
+    }
+
+    /// Amount of bytes that can be written before the ring is full.
+    ///
+    /// returns: `RingError::Corrupted` if the consumer's tail claims more data than the ring can hold.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ivshmemmap::error::RingError;
+    /// use ivshmemmap::ring::{self, Producer};
+    ///
+    /// let shm = ivshmemmap::SharedMemory::scratch("doc-ring-free", 4096).unwrap();
+    /// let mut device = shm.map(1).unwrap();
+    /// let mut consumer_side = shm.map(1).unwrap();
+    ///
+    /// let capacity = ring::format(&mut device[..1024]).unwrap();
+    /// let mut producer = Producer::attach(&mut device[..1024]).unwrap();
+    /// assert_eq!(producer.free().unwrap(), capacity);
+    ///
+    /// // A tail ahead of the head would claim more than `capacity` bytes in use.
+    /// consumer_side[128..136].copy_from_slice(&1u64.to_ne_bytes());
+    /// assert!(matches!(producer.free(), Err(RingError::Corrupted)));
+    /// assert!(matches!(producer.try_send(b"message"), Err(RingError::Corrupted)));
+    /// ```
+    pub fn free(&self) -> Result<usize, RingError> {
+        let head = self.ring.head().load(Ordering::Relaxed);
+        let tail = self.ring.tail().load(Ordering::Acquire);
+        // The tail is written by the peer, so it cannot be trusted.
+        let used = usize::try_from(head.wrapping_sub(tail)).map_err(|_| RingError::Corrupted)?;
+        self.ring.capacity.checked_sub(used).ok_or(RingError::Corrupted)
+    }
+
+    /// Appends `message` to the ring without blocking.
+    ///
+    /// returns: `RingError::Full` if the consumer has not yet made enough room.
+    pub fn try_send(&mut self, message: &[u8]) -> Result<(), RingError> {
+        let framed = message.len() + FRAME_SIZE;
+        if message.len() > u32::MAX as usize || framed > self.ring.capacity {
+            return Err(RingError::MessageTooLarge {
+                length: message.len(),
+                capacity: self.ring.capacity - FRAME_SIZE,
+            });
+        }
+        if framed > self.free()? {
+            return Err(RingError::Full);
+        }
+
+        let head = self.ring.head().load(Ordering::Relaxed);
+        unsafe {
+            self.ring.write(head, &(message.len() as u32).to_le_bytes());
+            self.ring.write(head + FRAME_SIZE as u64, message);
+        }
+        self.ring.head().store(head + framed as u64, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// The receiving half of a ring. There must be at most one consumer per ring.
+pub struct Consumer<'a> {
+    ring: RawRing,
+    _region: PhantomData<&'a mut [u8]>,
+}
+
+// The consumer only writes the tail, and reads the part of the data area the producer has published.
+unsafe impl Send for Consumer<'_> {}
+
+impl Debug for Consumer<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Consumer{{ capacity: {:?} pending: {:?} }}", self.ring.capacity, self.pending())
+    }
+}
+
+impl<'a> Consumer<'a> {
+    /// Attaches to a ring that was formatted with `format`, possibly by another process.
+    pub fn attach(region: &'a mut [u8]) -> Result<Self, RingError> {
+        Ok(Self {
+            ring: attach(region)?,
+            _region: PhantomData,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.capacity
+    }
+
+    /// Amount of bytes, including framing, that have been sent but not yet received.
+    pub fn pending(&self) -> usize {
+        let head = self.ring.head().load(Ordering::Acquire);
+        let tail = self.ring.tail().load(Ordering::Relaxed);
+        head.wrapping_sub(tail) as usize
+    }
+
+    /// Length of the next message, without consuming it.
+    pub fn peek_len(&self) -> Result<Option<usize>, RingError> {
+        let pending = self.pending();
+        if pending == 0 {
+            return Ok(None);
+        }
+        if pending < FRAME_SIZE || pending > self.ring.capacity {
+            return Err(RingError::Corrupted);
+        }
+        let mut frame = [0u8; FRAME_SIZE];
+        unsafe {
+            self.ring.read(self.ring.tail().load(Ordering::Relaxed), &mut frame);
+        }
+        let length = u32::from_le_bytes(frame) as usize;
+        if length + FRAME_SIZE > pending {
+            return Err(RingError::Corrupted);
+        }
+        Ok(Some(length))
+    }
+
+    /// Copies the next message into `buf` without blocking.
+    ///
+    /// returns: The length of the message, `None` if the ring is empty, or `RingError::BufferTooSmall`
+    ///          if the message does not fit. The message is only consumed if it was copied.
+    pub fn try_recv_into(&mut self, buf: &mut [u8]) -> Result<Option<usize>, RingError> {
+        let Some(length) = self.peek_len()? else {
+            return Ok(None);
+        };
+        if length > buf.len() {
+            return Err(RingError::BufferTooSmall { needed: length });
+        }
+        let tail = self.ring.tail().load(Ordering::Relaxed);
+        unsafe {
+            self.ring.read(tail + FRAME_SIZE as u64, &mut buf[..length]);
+        }
+        self.ring.tail().store(tail + (length + FRAME_SIZE) as u64, Ordering::Release);
+        Ok(Some(length))
+    }
+
+    /// Receives the next message without blocking.
+    ///
+    /// returns: The message, or `None` if the ring is empty.
+    pub fn try_recv(&mut self) -> Result<Option<Vec<u8>>, RingError> {
+        let Some(length) = self.peek_len()? else {
+            return Ok(None);
+        };
+        let mut message = vec![0u8; length];
+        self.try_recv_into(&mut message)?;
+        Ok(Some(message))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn producer_in_another_process() {
+        let shm = crate::SharedMemory::scratch("test-ring-fork", 4096).unwrap();
+        let mut sending_side = shm.map(1).unwrap();
+        let mut receiving_side = shm.map(1).unwrap();
+        format(&mut sending_side[..1024]).unwrap();
+
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            // The child leaves through _exit, so it neither runs the test harness nor unlinks the shared memory.
+            let Ok(mut producer) = Producer::attach(&mut sending_side[..1024]) else {
+                unsafe { libc::_exit(1) };
+            };
+            for index in 0..10_000u32 {
+                let message = vec![index as u8; index as usize % 300];
+                loop {
+                    match producer.try_send(&message) {
+                        Ok(()) => break,
+                        Err(RingError::Full) => std::thread::yield_now(),
+                        Err(_) => unsafe { libc::_exit(2) },
+                    }
+                }
+            }
+            unsafe { libc::_exit(0) };
+        }
+        assert!(child > 0);
+
+        let mut consumer = Consumer::attach(&mut receiving_side[..1024]).unwrap();
+        for index in 0..10_000u32 {
+            let message = loop {
+                if let Some(message) = consumer.try_recv().unwrap() {
+                    break message;
+                }
+                std::thread::yield_now();
+            };
+            assert_eq!(message, vec![index as u8; index as usize % 300]);
+        }
+        assert_eq!(consumer.pending(), 0);
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "child failed with {status}");
+    }
+}
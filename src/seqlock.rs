@@ -0,0 +1,190 @@
+//! Sequence lock protecting a value inside shared memory, so readers never observe a partially written value.
+//!
+//! | Offset      | Contents                                                          |
+//! |-------------|-------------------------------------------------------------------|
+//! | 0           | sequence (`AtomicU64`), odd while a write is in progress          |
+//! | `alignment` | the value, where `alignment` is the larger of 8 and `align_of::<T>()` |
+//!
+//! Zeroed memory is a valid, unlocked sequence lock holding a zeroed value, so no formatting is required.
+//! Writers from different peers exclude each other, readers retry until they copied the value without interruption.
+//!
+//! # Examples
+//!
+//! ```
+//! use ivshmemmap::seqlock::SeqLock;
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-seqlock", 4096).unwrap();
+//! let mut writer_side = shm.map(1).unwrap();
+//! let mut reader_side = shm.map(1).unwrap();
+//!
+//! let writer = SeqLock::<[u64; 8]>::attach(&mut writer_side[..], 128).unwrap();
+//! let reader = SeqLock::<[u64; 8]>::attach(&mut reader_side[..], 128).unwrap();
+//!
+//! std::thread::scope(|scope| {
+//!     scope.spawn(|| {
+//!         for generation in 1..=10_000u64 {
+//!             writer.write(&[generation; 8]);
+//!         }
+//!     });
+//!     loop {
+//!         let status = reader.read();
+//!         assert!(status.iter().all(|&value| value == status[0]), "Torn read: {status:?}");
+//!         if status[0] == 10_000 {
+//!             break;
+//!         }
+//!     }
+//! });
+//! ```
+
+use crate::device::check_bounds;
+use crate::error::DeviceError;
+use crate::pod::Pod;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// A handle to a sequence lock at an offset in shared memory.
+pub struct SeqLock<'a, T: Pod> {
+    sequence: *const AtomicU64,
+    value: *mut T,
+    _region: PhantomData<&'a mut [u8]>,
+}
+
+// The sequence is only accessed atomically, the value only while holding the sequence or by validated copies.
+unsafe impl<T: Pod> Send for SeqLock<'_, T> {}
+unsafe impl<T: Pod> Sync for SeqLock<'_, T> {}
+
+impl<T: Pod> Debug for SeqLock<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SeqLock{{ sequence: {:?} }}", self.sequence())
+    }
+}
+
+impl<'a, T: Pod> SeqLock<'a, T> {
+    /// Offset of the value relative to the sequence, and the required alignment of the lock.
+    pub const ALIGNMENT: usize = if std::mem::align_of::<T>() > 8 { std::mem::align_of::<T>() } else { 8 };
+    /// Amount of bytes the lock occupies in shared memory.
+    pub const SIZE: usize = Self::ALIGNMENT + std::mem::size_of::<T>();
+
+    /// # Arguments
+    ///
+    /// * `region`: The shared memory containing the lock.
+    /// * `offset`: Offset of the lock in `region`. Must be aligned to `SeqLock::<T>::ALIGNMENT`.
+    ///
+    /// returns: An error if the lock does not fit in `region`, or is misaligned.
+    pub fn attach(region: &'a mut [u8], offset: usize) -> Result<Self, DeviceError> {
+        check_bounds(offset, Self::SIZE, region.len())?;
+        let base = unsafe { region.as_mut_ptr().add(offset) };
+        if base.align_offset(Self::ALIGNMENT) != 0 {
+            return Err(DeviceError::Misaligned {
+                offset,
+                alignment: Self::ALIGNMENT,
+            });
+        }
+        Ok(Self {
+            sequence: base as *const AtomicU64,
+            value: unsafe { base.add(Self::ALIGNMENT) } as *mut T,
+            _region: PhantomData,
+        })
+    }
+
+    /// The current sequence number. Increments by two for every completed write.
+    pub fn sequence(&self) -> u64 {
+        unsafe { (*self.sequence).load(Ordering::Acquire) }
+    }
+
+    /// Copies the value, retrying while a writer modifies it.
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Copies the value once.
+    ///
+    /// returns: `None` if a writer modified the value during the copy.
+    pub fn try_read(&self) -> Option<T> {
+        let sequence = unsafe { &*self.sequence };
+        let before = sequence.load(Ordering::Acquire);
+        if before & 1 == 1 {
+            return None;
+        }
+        let value = unsafe { std::ptr::read_volatile(self.value) };
+        // Orders the copy before the validation. Pairs with the release fence in `write`.
+        fence(Ordering::Acquire);
+        if sequence.load(Ordering::Relaxed) != before {
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Replaces the value. Waits for concurrent writers, possibly of another peer, to finish first.
+    pub fn write(&self, value: &T) {
+        let sequence = unsafe { &*self.sequence };
+        let mut current = sequence.load(Ordering::Relaxed);
+        loop {
+            if current & 1 == 1 {
+                std::hint::spin_loop();
+                current = sequence.load(Ordering::Relaxed);
+                continue;
+            }
+            match sequence.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        // Readers that observe any byte of the new value are guaranteed to see the odd sequence afterwards.
+        fence(Ordering::Release);
+        unsafe {
+            std::ptr::write_volatile(self.value, *value);
+        }
+        sequence.store(current + 2, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn concurrent_readers_never_see_torn_values() {
+        type Status = [u64; 32];
+        const WRITERS: usize = 2;
+        const WRITES: u64 = 20_000;
+
+        let mut region = vec![0u64; SeqLock::<Status>::SIZE / 8];
+        let region = unsafe { std::slice::from_raw_parts_mut(region.as_mut_ptr() as *mut u8, region.len() * 8) };
+        let lock = SeqLock::<Status>::attach(region, 0).unwrap();
+        let finished = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            for writer in 0..WRITERS as u64 {
+                let (lock, finished) = (&lock, &finished);
+                scope.spawn(move || {
+                    // Odd and even generations, so both writers produce distinct values.
+                    for generation in 0..WRITES {
+                        lock.write(&[2 * generation + writer; 32]);
+                    }
+                    finished.fetch_add(1, Ordering::Release);
+                });
+            }
+            for _ in 0..3 {
+                let (lock, finished) = (&lock, &finished);
+                scope.spawn(move || {
+                    loop {
+                        let done = finished.load(Ordering::Acquire) == WRITERS;
+                        let status = lock.read();
+                        assert!(status.iter().all(|&value| value == status[0]), "Torn read: {status:?}");
+                        if done {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
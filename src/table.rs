@@ -0,0 +1,390 @@
+//! A table of named sub-regions at the start of the shared memory, so independent protocols can share one device.
+//!
+//! | Offset          | Contents                                                                     |
+//! |-----------------|------------------------------------------------------------------------------|
+//! | 0               | magic (`u64`), version (`u32`), entry count (`u32`), lock (`AtomicU32`)      |
+//! | 64 + 64 * entry | name (32 bytes, NUL padded), offset (`u64`), length (`u64`), flags (`u32`), version (`u32`), in use (`u32`) |
+//! | data offset     | the sub-regions, each aligned to a page                                      |
+//!
+//! Peers modify the table under a spin lock stored in the header. Table operations hold it for microseconds, so a
+//! lock that is held longer than `LOCK_TIMEOUT` belongs to a peer that died, and is taken over.
+//!
+//! # Examples
+//!
+//! ```
+//! use ivshmemmap::table::RegionTable;
+//!
+//! let shm = ivshmemmap::SharedMemory::scratch("doc-table", 1 << 20).unwrap();
+//! let mut host = shm.map(2).unwrap();
+//! let mut guest = shm.map(2).unwrap();
+//!
+//! let mut table = RegionTable::format(&mut host, 16).unwrap();
+//! table.allocate("frames", 256 * 1024, 0, 1).unwrap();
+//! table.allocate("input", 4096, 0, 3).unwrap();
+//! table.region_mut("input").unwrap().write_at(0, b"click").unwrap();
+//! let [frames, input] = table.regions_mut(["frames", "input"]).unwrap();
+//! assert_eq!((frames.len(), input.len()), (256 * 1024, 4096));
+//!
+//! let mut table = RegionTable::attach(&mut guest).unwrap();
+//! assert_eq!(table.lookup("input").unwrap().version, 3);
+//! let mut buf = [0u8; 5];
+//! table.region_mut("input").unwrap().read_at(0, &mut buf).unwrap();
+//! assert_eq!(&buf, b"click");
+//!
+//! table.free("frames").unwrap();
+//! assert!(table.lookup("frames").is_err());
+//! ```
+
+use crate::device::{check_bounds, IvshmemDevice};
+use crate::error::RegionError;
+use crate::header;
+use crate::region::RegionMut;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// "IVSHRGNT" in ASCII.
+pub const TABLE_MAGIC: u64 = u64::from_le_bytes(*b"IVSHRGNT");
+pub const TABLE_VERSION: u32 = 1;
+/// Maximum length of a region name in bytes.
+pub const MAX_NAME_LENGTH: usize = 31;
+/// How long the table lock may be held before it is taken over from its presumably dead owner.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+const PAGE_SIZE: usize = 4096;
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 8;
+const CAPACITY_OFFSET: usize = 12;
+const LOCK_OFFSET: usize = 16;
+const ENTRIES_OFFSET: usize = 64;
+const ENTRY_SIZE: usize = 64;
+const ENTRY_NAME: usize = 0;
+const ENTRY_OFFSET: usize = 32;
+const ENTRY_LENGTH: usize = 40;
+const ENTRY_FLAGS: usize = 48;
+const ENTRY_VERSION: usize = 52;
+const ENTRY_IN_USE: usize = 56;
+
+fn data_offset(capacity: usize) -> usize {
+    (ENTRIES_OFFSET + capacity * ENTRY_SIZE).next_multiple_of(PAGE_SIZE)
+}
+
+/// A named sub-region as stored in the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionInfo {
+    pub name: String,
+    /// Offset from the start of the shared memory.
+    pub offset: usize,
+    pub length: usize,
+    /// Application defined flags.
+    pub flags: u32,
+    /// Application defined version of the protocol inside the region.
+    pub version: u32,
+}
+
+/// The region table of a device.
+pub struct RegionTable<'a> {
+    device: &'a mut IvshmemDevice,
+    capacity: usize,
+}
+
+impl Debug for RegionTable<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RegionTable{{ capacity: {:?} }}", self.capacity)
+    }
+}
+
+impl<'a> RegionTable<'a> {
+    /// Writes an empty table to the start of the shared memory, discarding any previous content.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: The shared memory.
+    /// * `capacity`: Maximum amount of regions.
+    pub fn format(device: &'a mut IvshmemDevice, capacity: usize) -> Result<Self, RegionError> {
+        let required = data_offset(capacity);
+        if capacity == 0 || capacity > u32::MAX as usize || required > device.len() {
+            return Err(RegionError::TableTooLarge {
+                capacity,
+                size: device.len(),
+            });
+        }
+        device[..required].fill(0);
+        device.write_volatile(VERSION_OFFSET, TABLE_VERSION)?;
+        device.write_volatile(CAPACITY_OFFSET, capacity as u32)?;
+        unsafe { header::publish(device.as_mut_ptr().add(MAGIC_OFFSET), TABLE_MAGIC) };
+        Ok(Self { device, capacity })
+    }
+
+    /// Attaches to a table written by `format`, possibly by another peer.
+    pub fn attach(device: &'a mut IvshmemDevice) -> Result<Self, RegionError> {
+        check_bounds(MAGIC_OFFSET, std::mem::size_of::<u64>(), device.len())?;
+        if !unsafe { header::is_published(device.as_ptr().add(MAGIC_OFFSET), TABLE_MAGIC) } {
+            return Err(RegionError::NotFormatted);
+        }
+        let version = device.read_volatile::<u32>(VERSION_OFFSET)?;
+        if version != TABLE_VERSION {
+            return Err(RegionError::UnsupportedVersion(version));
+        }
+        let capacity = device.read_volatile::<u32>(CAPACITY_OFFSET)? as usize;
+        if capacity == 0 || data_offset(capacity) > device.len() {
+            return Err(RegionError::Corrupted);
+        }
+        Ok(Self { device, capacity })
+    }
+
+    /// Maximum amount of regions.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Allocates a page aligned region of `length` bytes in the first gap that fits.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: Unique name of the region. At most `MAX_NAME_LENGTH` bytes, without NUL bytes.
+    /// * `length`: Size of the region in bytes.
+    /// * `flags`: Application defined flags.
+    /// * `version`: Application defined version of the protocol inside the region.
+    pub fn allocate(&mut self, name: &str, length: usize, flags: u32, version: u32) -> Result<RegionInfo, RegionError> {
+        check_name(name)?;
+        let _lock = self.lock()?;
+        let entries = self.entries_unlocked()?;
+        if entries.iter().any(|(_, entry)| entry.name == name) {
+            return Err(RegionError::AlreadyExists(name.to_string()));
+        }
+        let slot = (0..self.capacity)
+            .find(|slot| entries.iter().all(|(used, _)| used != slot))
+            .ok_or(RegionError::TableFull)?;
+
+        // First fit between the existing regions, which are sorted by offset.
+        let mut used: Vec<_> = entries.iter().map(|(_, entry)| entry.offset..entry.offset + entry.length).collect();
+        used.sort_by_key(|range| range.start);
+        used.push(self.device.len()..self.device.len());
+        let mut start = data_offset(self.capacity);
+        let offset = used
+            .iter()
+            .find_map(|range| {
+                let fits = start.checked_add(length).is_some_and(|end| end <= range.start);
+                let candidate = start;
+                start = start.max(range.end.next_multiple_of(PAGE_SIZE));
+                fits.then_some(candidate)
+            })
+            .ok_or(RegionError::OutOfSpace { length })?;
+
+        let info = RegionInfo {
+            name: name.to_string(),
+            offset,
+            length,
+            flags,
+            version,
+        };
+        self.write_entry(slot, &info)?;
+        Ok(info)
+    }
+
+    /// Finds the region named `name`.
+    pub fn lookup(&self, name: &str) -> Result<RegionInfo, RegionError> {
+        let _lock = self.lock()?;
+        self.entries_unlocked()?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| RegionError::NotFound(name.to_string()))
+    }
+
+    /// All allocated regions, sorted by offset.
+    pub fn regions(&self) -> Result<Vec<RegionInfo>, RegionError> {
+        let _lock = self.lock()?;
+        let mut regions: Vec<_> = self.entries_unlocked()?.into_iter().map(|(_, entry)| entry).collect();
+        regions.sort_by_key(|entry| entry.offset);
+        Ok(regions)
+    }
+
+    /// Frees the region named `name`. Its memory can be handed out again by `allocate`.
+    pub fn free(&mut self, name: &str) -> Result<(), RegionError> {
+        let _lock = self.lock()?;
+        let (slot, _) = self
+            .entries_unlocked()?
+            .into_iter()
+            .find(|(_, entry)| entry.name == name)
+            .ok_or_else(|| RegionError::NotFound(name.to_string()))?;
+        self.device.write_volatile::<u32>(entry_offset(slot) + ENTRY_IN_USE, 0)?;
+        Ok(())
+    }
+
+    /// A handle to the memory of the region named `name`, which can only access that region.
+    pub fn region_mut(&mut self, name: &str) -> Result<RegionMut<'_>, RegionError> {
+        let [region] = self.regions_mut([name])?;
+        Ok(region)
+    }
+
+    /// Handles to the memory of several regions at once, in the order of `names`.
+    ///
+    /// returns: `RegionError::Duplicate` if a name is requested twice, as the handles may not alias.
+    pub fn regions_mut<const N: usize>(&mut self, names: [&str; N]) -> Result<[RegionMut<'_>; N], RegionError> {
+        let entries = {
+            let _lock = self.lock()?;
+            self.entries_unlocked()?
+        };
+        let mut requested = Vec::with_capacity(N);
+        for (index, name) in names.iter().enumerate() {
+            if names[..index].contains(name) {
+                return Err(RegionError::Duplicate(name.to_string()));
+            }
+            let (_, info) = entries
+                .iter()
+                .find(|(_, entry)| entry.name == *name)
+                .ok_or_else(|| RegionError::NotFound(name.to_string()))?;
+            requested.push((index, info));
+        }
+        requested.sort_by_key(|(_, info)| info.offset);
+
+        let mut regions: [Option<RegionMut>; N] = std::array::from_fn(|_| None);
+        let mut rest = self.device.as_region_mut();
+        let mut end = 0;
+        for (index, info) in requested {
+            // Regions never overlap unless the table was overwritten.
+            let gap = info.offset.checked_sub(end).ok_or(RegionError::Corrupted)?;
+            let (_, tail) = rest.split_at_mut(gap)?;
+            let (region, tail) = tail.split_at_mut(info.length)?;
+            regions[index] = Some(region);
+            rest = tail;
+            end = info.offset + info.length;
+        }
+        Ok(regions.map(|region| region.expect("Every name was looked up")))
+    }
+
+    /// Takes the table lock, or takes it over after `LOCK_TIMEOUT`. The guard may not outlive the table.
+    fn lock(&self) -> Result<TableLock, RegionError> {
+        let lock = self.device.atomic_u32(LOCK_OFFSET)?;
+        let mut start = Instant::now();
+        let mut held = 0;
+        loop {
+            match lock.compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(0) => {}
+                Err(owner) if owner != held => {
+                    held = owner;
+                    start = Instant::now();
+                }
+                // Taking over changes the non-zero value, so only one of the waiters takes over a dead lock.
+                Err(owner) if start.elapsed() > LOCK_TIMEOUT => {
+                    let next = owner.checked_add(1).unwrap_or(1);
+                    if lock.compare_exchange(owner, next, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                        break;
+                    }
+                }
+                Err(_) => {}
+            }
+            std::hint::spin_loop();
+        }
+        Ok(TableLock { lock })
+    }
+
+    /// Reads every entry that is in use. The table lock must be held.
+    fn entries_unlocked(&self) -> Result<Vec<(usize, RegionInfo)>, RegionError> {
+        let mut entries = Vec::new();
+        for slot in 0..self.capacity {
+            let base = entry_offset(slot);
+            if self.device.read_volatile::<u32>(base + ENTRY_IN_USE)? == 0 {
+                continue;
+            }
+            let name = self.device.read_volatile::<[u8; 32]>(base + ENTRY_NAME)?;
+            let name_length = name.iter().position(|&byte| byte == 0).ok_or(RegionError::Corrupted)?;
+            let info = RegionInfo {
+                name: std::str::from_utf8(&name[..name_length])
+                    .map_err(|_| RegionError::Corrupted)?
+                    .to_string(),
+                offset: self.device.read_volatile::<u64>(base + ENTRY_OFFSET)? as usize,
+                length: self.device.read_volatile::<u64>(base + ENTRY_LENGTH)? as usize,
+                flags: self.device.read_volatile(base + ENTRY_FLAGS)?,
+                version: self.device.read_volatile(base + ENTRY_VERSION)?,
+            };
+            let in_bounds = info.offset >= data_offset(self.capacity)
+                && info.offset.checked_add(info.length).is_some_and(|end| end <= self.device.len());
+            if !in_bounds {
+                return Err(RegionError::Corrupted);
+            }
+            entries.push((slot, info));
+        }
+        Ok(entries)
+    }
+
+    fn write_entry(&mut self, slot: usize, info: &RegionInfo) -> Result<(), RegionError> {
+        let base = entry_offset(slot);
+        let mut name = [0u8; 32];
+        name[..info.name.len()].copy_from_slice(info.name.as_bytes());
+        self.device.write_volatile(base + ENTRY_NAME, name)?;
+        self.device.write_volatile(base + ENTRY_OFFSET, info.offset as u64)?;
+        self.device.write_volatile(base + ENTRY_LENGTH, info.length as u64)?;
+        self.device.write_volatile(base + ENTRY_FLAGS, info.flags)?;
+        self.device.write_volatile(base + ENTRY_VERSION, info.version)?;
+        self.device.write_volatile(base + ENTRY_IN_USE, 1u32)?;
+        Ok(())
+    }
+}
+
+fn entry_offset(slot: usize) -> usize {
+    ENTRIES_OFFSET + slot * ENTRY_SIZE
+}
+
+fn check_name(name: &str) -> Result<(), RegionError> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('\0') {
+        return Err(RegionError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Releases the table lock when dropped.
+struct TableLock {
+    lock: *const AtomicU32,
+}
+
+impl Drop for TableLock {
+    fn drop(&mut self) {
+        unsafe { (*self.lock).store(0, Ordering::Release) };
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_mut_hands_out_disjoint_regions() {
+        let shm = crate::SharedMemory::scratch("test-table-regions", 1 << 20).unwrap();
+        let mut device = shm.map(1).unwrap();
+        let mut table = RegionTable::format(&mut device, 4).unwrap();
+        let a = table.allocate("a", 4096, 0, 0).unwrap();
+        let b = table.allocate("b", 100, 0, 0).unwrap();
+        let c = table.allocate("c", 8192, 0, 0).unwrap();
+
+        let [mut c_region, mut a_region, b_region] = table.regions_mut(["c", "a", "b"]).unwrap();
+        assert_eq!((a_region.offset(), a_region.len()), (a.offset, a.length));
+        assert_eq!((b_region.offset(), b_region.len()), (b.offset, b.length));
+        assert_eq!((c_region.offset(), c_region.len()), (c.offset, c.length));
+        a_region.write_at(0, b"a").unwrap();
+        c_region.write_at(0, b"c").unwrap();
+        drop((a_region, b_region, c_region));
+
+        assert!(matches!(table.regions_mut(["a", "b", "a"]), Err(RegionError::Duplicate(name)) if name == "a"));
+        assert!(matches!(table.regions_mut(["a", "d"]), Err(RegionError::NotFound(name)) if name == "d"));
+        assert_eq!(device[a.offset], b'a');
+        assert_eq!(device[c.offset], b'c');
+    }
+
+    #[test]
+    fn lock_of_a_dead_peer_is_taken_over() {
+        let shm = crate::SharedMemory::scratch("test-table-lock", 1 << 20).unwrap();
+        let mut device = shm.map(1).unwrap();
+        let mut table = RegionTable::format(&mut device, 4).unwrap();
+        // A peer died while allocating.
+        std::mem::forget(table.lock().unwrap());
+
+        let start = Instant::now();
+        table.allocate("a", 4096, 0, 0).unwrap();
+        assert!(start.elapsed() >= LOCK_TIMEOUT);
+        assert_eq!(table.lookup("a").unwrap().length, 4096);
+    }
+}
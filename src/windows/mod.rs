@@ -1,8 +1,11 @@
+use crate::backend::Backend;
 use crate::device::IvshmemDevice;
-use crate::windows::winerror::WindowsError;
-use anyhow::{bail, Context, Result};
+use crate::error::{Error, WindowsError};
+use crate::kernel::CopyKernel;
+use crate::pool::CopyPool;
 use std::fmt::Debug;
 use std::fmt::Formatter;
+use std::sync::{Arc, Mutex};
 use windows::core::{GUID, PCWSTR};
 use windows::imp::GetLastError;
 use windows::Win32::Devices::DeviceAndDriverInstallation::{
@@ -11,19 +14,42 @@ use windows::Win32::Devices::DeviceAndDriverInstallation::{
     HDEVINFO, SP_DEVICE_INTERFACE_DATA, SP_DEVICE_INTERFACE_DETAIL_DATA_W, SP_DEVINFO_DATA,
 };
 use windows::Win32::Foundation::{
-    ERROR_DEVICE_ALREADY_ATTACHED, GENERIC_READ, GENERIC_WRITE, HANDLE, HWND, INVALID_HANDLE_VALUE,
+    CloseHandle, ERROR_DEVICE_ALREADY_ATTACHED, GENERIC_READ, GENERIC_WRITE, HANDLE, HWND, INVALID_HANDLE_VALUE,
 };
 use windows::Win32::Storage::FileSystem::{
     CreateFileW, FILE_FLAGS_AND_ATTRIBUTES, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
 };
 use windows::Win32::System::IO::DeviceIoControl;
 
-mod winerror;
-
 //DF576976-569D-4672-95A0-F57E4EA0B210
 const IVSHMEM_CLASS_GUID: GUID = GUID::from_u128(296871711915466174647497163522302849552u128);
 
+const REQUEST_SIZE_CODE: u32 = (0x00000022u32 << 16) | (0x801u32 << 2);
+const REQUEST_MMAP_CODE: u32 = ((0x00000022) << 16) | ((0x802) << 2);
+const REQUEST_RELEASE_MMAP_CODE: u32 = ((0x00000022) << 16) | ((0x803) << 2);
+const RING_DOORBELL_CODE: u32 = ((0x00000022) << 16) | ((0x804) << 2);
+const IVSHMEM_CACHE_WRITECOMBINED: u8 = 2;
+
+/// Input of `RING_DOORBELL_CODE`.
+#[repr(C)]
+struct IvshmemRingRequest {
+    peer_id: u16,
+    vector: u16,
+}
+
+/// An open handle to the IVSHMEM driver. Closing it also releases any mapping the driver still holds.
+struct DeviceHandle(HANDLE);
+
+impl Drop for DeviceHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0);
+        }
+    }
+}
+
 #[derive(Debug)]
+#[repr(C)]
 struct IvshmemMemoryMapResponse {
     peer_id: u64,
     size: u64,
@@ -47,26 +73,23 @@ impl IvshmemMemoryMapResponse {
         }
     }
 
-    pub fn upgrade(self, ivshmem_size: u64) -> Result<WindowsMemoryMap> {
-        if self.size != ivshmem_size {
-            panic!(
-                "Tried to allocate invalid memory. Assumed {:?}b but found {:?}b",
-                ivshmem_size, self.size
-            )
-        }
-        let ptr = unsafe {
-            std::slice::from_raw_parts_mut::<'static>(
-                self.memory_address as *mut u8,
-                self.size as usize,
-            )
-        };
-
-        Ok(WindowsMemoryMap::from_parts(
+    /// Takes ownership of the mapping. If its size does not match `ivshmem_size`, the mapping is released again.
+    fn upgrade(self, handle: Arc<DeviceHandle>, ivshmem_size: u64, device: &str) -> Result<WindowsMemoryMap, WindowsError> {
+        let memory_map = WindowsMemoryMap::from_parts(
+            handle,
             self.peer_id,
             self.size,
             self.vectors,
-            ptr,
-        ))
+            self.memory_address as *mut u8,
+        );
+        if self.size != ivshmem_size {
+            return Err(WindowsError::SizeMismatch {
+                device: device.to_string(),
+                expected: ivshmem_size,
+                actual: self.size,
+            });
+        }
+        Ok(memory_map)
     }
 }
 
@@ -83,7 +106,7 @@ impl std::fmt::Debug for IvshmemDescriptor {
 }
 
 impl IvshmemDescriptor {
-    fn load(device_info_set: HDEVINFO, mut device_info_data: SP_DEVINFO_DATA) -> Result<Self> {
+    fn load(device_info_set: HDEVINFO, mut device_info_data: SP_DEVINFO_DATA) -> Result<Self, WindowsError> {
         assert!(device_info_set.0 > 0);
         assert!(device_info_data.cbSize > 0);
         unsafe {
@@ -101,7 +124,10 @@ impl IvshmemDescriptor {
             )
             .as_bool()
             {
-                bail!("Failed to enumerate device: {:?}", device_info_set);
+                return Err(WindowsError::SetupApiFailed {
+                    call: "SetupDiEnumDeviceInterfaces",
+                    code: GetLastError(),
+                });
             };
 
             device_info_data.cbSize = std::mem::size_of::<SP_DEVINFO_DATA>() as u32;
@@ -115,7 +141,10 @@ impl IvshmemDescriptor {
                 Some(&mut device_info_data),
             );
             if buffer_size == 0 {
-                bail!("Failed to fetch buffer size for SP_DEVINFO_DATA")
+                return Err(WindowsError::SetupApiFailed {
+                    call: "SetupDiGetDeviceInterfaceDetailW",
+                    code: GetLastError(),
+                });
             }
 
             let mut detail_data_buffer = vec![0; buffer_size as usize].into_boxed_slice();
@@ -132,7 +161,10 @@ impl IvshmemDescriptor {
             )
             .as_bool()
             {
-                bail!("Failed to parse device interface: {:?}", GetLastError());
+                return Err(WindowsError::SetupApiFailed {
+                    call: "SetupDiGetDeviceInterfaceDetailW",
+                    code: GetLastError(),
+                });
             }
 
             let mut path_bytes = vec![];
@@ -149,7 +181,10 @@ impl IvshmemDescriptor {
         }
     }
 
-    unsafe fn open(self, worker_threads: usize) -> Result<IvshmemDevice> {
+    /// Opens the device and queries the size of its shared memory.
+    unsafe fn open(self) -> Result<WindowsBackend, WindowsError> {
+        let device = self.pcwstr().to_string().unwrap_or_default();
+
         // This will fail if an existing handle isn't dropped.
         // It takes a while for the device to be freed up after the program is terminated.
         let handle = CreateFileW(
@@ -161,26 +196,24 @@ impl IvshmemDescriptor {
             FILE_FLAGS_AND_ATTRIBUTES(0),
             HANDLE(0),
         )
-        .with_context(|| {
-            format!(
-                "Unable to open IVSHMEM file path: {:?}",
-                self.pcwstr().to_string(),
-            )
+        .map_err(|_| WindowsError::OpenFailed {
+            device: device.clone(),
+            code: GetLastError(),
         })?;
 
-        WindowsError::current().check()?;
-
         if handle == INVALID_HANDLE_VALUE {
-            bail!("Unable to obtain IVSHMEM file handle");
+            return Err(WindowsError::OpenFailed {
+                device,
+                code: GetLastError(),
+            });
         }
-
-        const REQUEST_SIZE_CODE: u32 = (0x00000022u32 << 16) | (0x801u32 << 2);
+        let handle = DeviceHandle(handle);
 
         let mut ivshmem_size = 0u64;
         let mut bytes_returned = 0u32;
 
         if !DeviceIoControl(
-            handle,
+            handle.0,
             REQUEST_SIZE_CODE,
             None,
             0,
@@ -191,51 +224,23 @@ impl IvshmemDescriptor {
         )
         .as_bool()
         {
-            bail!(
-                "Failed to request IVSHMEM device size. Error code: {}",
-                GetLastError()
-            );
+            return Err(WindowsError::IoctlFailed {
+                device,
+                request: "IOCTL_IVSHMEM_REQUEST_SIZE",
+                code: GetLastError(),
+            });
         }
 
         if ivshmem_size == 0 {
-            bail!(
-                "Found IVSHMEM device with insufficient memory. ({:?} bytes)",
-                ivshmem_size
-            );
-        }
-
-        const REQUEST_MMAP_CODE: u32 = ((0x00000022) << 16) | ((0x802) << 2);
-        const IVSHMEM_CACHE_WRITECOMBINED: u8 = 2;
-
-        let mut memory_map = IvshmemMemoryMapResponse::new();
-
-        if !DeviceIoControl(
-            handle,
-            REQUEST_MMAP_CODE,
-            Some(&IVSHMEM_CACHE_WRITECOMBINED as *const _ as *const _),
-            1,
-            Some(&mut memory_map as *mut _ as *mut _),
-            std::mem::size_of::<IvshmemMemoryMapResponse>() as u32, // IVSHMEM_MMAP size should be equal to 32.
-            None,
-            None,
-        )
-        .as_bool()
-        {
-            let error_code = GetLastError();
-            if error_code == ERROR_DEVICE_ALREADY_ATTACHED.0 {
-                bail!("IVSHMEM device is already in use");
-            } else {
-                bail!(
-                    "Failed to obtain IVSHMEM memory map. Error code: {}",
-                    error_code
-                );
-            }
+            return Err(WindowsError::EmptyDevice(device));
         }
 
-        Ok(IvshmemDevice::with_memory(
-            memory_map.upgrade(ivshmem_size)?.ptr,
-            worker_threads
-        ))
+        Ok(WindowsBackend {
+            handle: Arc::new(handle),
+            device,
+            size: ivshmem_size,
+            mapped: Mutex::new(None),
+        })
     }
 
     // PCWSTR is actually a pointer to a buffer. Storing this value is NOT recommended
@@ -252,63 +257,203 @@ impl IvshmemDescriptor {
     }
 }
 
-pub fn pick_ivshmem_device<F>(picker: F, worker_threads: usize) -> Result<IvshmemDevice>
+pub fn pick_ivshmem_device<F>(picker: F, worker_threads: usize) -> Result<IvshmemDevice, WindowsError>
 where
     F: FnOnce(Vec<IvshmemDescriptor>) -> IvshmemDescriptor,
 {
-    unsafe {
-        let device_info = SetupDiGetClassDevsW(
-            Some(&IVSHMEM_CLASS_GUID),
-            PCWSTR::null(),
-            HWND::default(),
-            DIGCF_PRESENT | DIGCF_DEVICEINTERFACE,
-        )
-        .with_context(|| "Failed to fetch device info")?;
+    WindowsBackend::pick(picker)?.map_device(worker_threads)
+}
 
-        WindowsError::current().check()?;
+/// The IVSHMEM driver of the virtio-win project, as seen from a Windows guest.
+///
+/// The driver hands out a single mapping per handle. Our peer ID and the amount of vectors are only known once
+/// the device is mapped.
+///
+/// Doorbells can be rung, but `wait` returns `Error::Unsupported`. The driver signals interrupts through events
+/// registered with `IOCTL_IVSHMEM_REGISTER_EVENT`, which this backend does not implement yet. Poll the shared memory
+/// instead.
+pub struct WindowsBackend {
+    handle: Arc<DeviceHandle>,
+    device: String,
+    size: u64,
+    /// Peer ID and amount of vectors reported by the driver when the memory was mapped.
+    mapped: Mutex<Option<(u16, usize)>>,
+}
 
-        let mut choices = Vec::new();
-        let mut index = 0;
-        loop {
-            let mut device_info_data: SP_DEVINFO_DATA = std::mem::zeroed();
+// The handle is only used for driver requests, which the driver serializes.
+unsafe impl Send for WindowsBackend {}
+unsafe impl Sync for WindowsBackend {}
 
-            // This is important. Without specifying the cbSize, the result will not be filled by the API.
-            device_info_data.cbSize = std::mem::size_of::<SP_DEVINFO_DATA>() as u32;
+impl Debug for WindowsBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WindowsBackend{{ device: {:?} size: {:?} }}", self.device, self.size)
+    }
+}
 
-            if SetupDiEnumDeviceInfo(device_info, index, &mut device_info_data).as_bool() {
-                // We found a device.
-                index += 1;
-                let descriptor = IvshmemDescriptor::load(device_info, device_info_data)
-                    .with_context(|| "Unable to fetch IVSHMEM device info")?;
-                choices.push(descriptor);
-            } else {
-                // There are no more devices to load.
-                break;
+impl WindowsBackend {
+    /// Opens one of the IVSHMEM devices of this computer.
+    ///
+    /// # Arguments
+    ///
+    /// * `picker`: Removes the selected device from the vec and returns it. The vec contains at least one device.
+    pub fn pick<F>(picker: F) -> Result<Self, WindowsError>
+    where
+        F: FnOnce(Vec<IvshmemDescriptor>) -> IvshmemDescriptor,
+    {
+        unsafe {
+            let device_info = SetupDiGetClassDevsW(
+                Some(&IVSHMEM_CLASS_GUID),
+                PCWSTR::null(),
+                HWND::default(),
+                DIGCF_PRESENT | DIGCF_DEVICEINTERFACE,
+            )
+            .map_err(|_| WindowsError::SetupApiFailed {
+                call: "SetupDiGetClassDevsW",
+                code: GetLastError(),
+            })?;
+
+            let mut choices = Vec::new();
+            let mut index = 0;
+            loop {
+                let mut device_info_data: SP_DEVINFO_DATA = std::mem::zeroed();
+
+                // This is important. Without specifying the cbSize, the result will not be filled by the API.
+                device_info_data.cbSize = std::mem::size_of::<SP_DEVINFO_DATA>() as u32;
+
+                if SetupDiEnumDeviceInfo(device_info, index, &mut device_info_data).as_bool() {
+                    // We found a device.
+                    index += 1;
+                    match IvshmemDescriptor::load(device_info, device_info_data) {
+                        Ok(descriptor) => choices.push(descriptor),
+                        Err(error) => {
+                            SetupDiDestroyDeviceInfoList(device_info);
+                            return Err(error);
+                        }
+                    }
+                } else {
+                    // There are no more devices to load.
+                    break;
+                }
             }
+
+            if choices.is_empty() {
+                SetupDiDestroyDeviceInfoList(device_info);
+                return Err(WindowsError::DeviceNotFound);
+            }
+
+            let backend = picker(choices).open();
+            // The device list is only needed for the lookup. Failing to free it leaks a little memory, but is no reason to fail.
+            SetupDiDestroyDeviceInfoList(device_info);
+            backend
         }
+    }
 
-        if choices.is_empty() {
-            bail!("Unable to find any IVSHMEM device");
+    /// Maps the shared memory write-combined.
+    pub fn map_device(&self, worker_threads: usize) -> Result<IvshmemDevice, WindowsError> {
+        let mut memory_map = IvshmemMemoryMapResponse::new();
+        unsafe {
+            if !DeviceIoControl(
+                self.handle.0,
+                REQUEST_MMAP_CODE,
+                Some(&IVSHMEM_CACHE_WRITECOMBINED as *const _ as *const _),
+                1,
+                Some(&mut memory_map as *mut _ as *mut _),
+                std::mem::size_of::<IvshmemMemoryMapResponse>() as u32, // IVSHMEM_MMAP size should be equal to 32.
+                None,
+                None,
+            )
+            .as_bool()
+            {
+                let code = GetLastError();
+                if code == ERROR_DEVICE_ALREADY_ATTACHED.0 {
+                    return Err(WindowsError::AlreadyAttached(self.device.clone()));
+                }
+                return Err(WindowsError::MapFailed {
+                    device: self.device.clone(),
+                    size: self.size,
+                    code,
+                });
+            }
         }
 
-        let ivshmem_device = picker(choices)
-            .open(worker_threads)
-            .with_context(|| "Unable to open IVSHMEM device")?;
+        *self.mapped.lock().unwrap() = Some((memory_map.peer_id as u16, memory_map.vectors as usize));
+        Ok(memory_map
+            .upgrade(Arc::clone(&self.handle), self.size, &self.device)?
+            .into_device(worker_threads))
+    }
+}
+
+impl Backend for WindowsBackend {
+    type Options = Box<dyn FnOnce(Vec<IvshmemDescriptor>) -> IvshmemDescriptor + Send>;
+
+    fn open(picker: Self::Options) -> Result<Self, Error> {
+        Ok(WindowsBackend::pick(picker)?)
+    }
+
+    fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn map(&self, worker_threads: usize) -> Result<IvshmemDevice, Error> {
+        Ok(self.map_device(worker_threads)?)
+    }
 
-        if !SetupDiDestroyDeviceInfoList(device_info).as_bool() {
-            panic!("Failed to free memory for IVSHMEM devices.");
+    fn peer_id(&self) -> Option<u16> {
+        self.mapped.lock().unwrap().map(|(peer_id, _)| peer_id)
+    }
+
+    fn vectors(&self) -> usize {
+        self.mapped.lock().unwrap().map_or(0, |(_, vectors)| vectors)
+    }
+
+    fn ring(&self, peer: u16, vector: usize) -> Result<(), Error> {
+        let request = IvshmemRingRequest {
+            peer_id: peer,
+            vector: u16::try_from(vector).map_err(|_| Error::InvalidVector(vector))?,
+        };
+        unsafe {
+            if !DeviceIoControl(
+                self.handle.0,
+                RING_DOORBELL_CODE,
+                Some(&request as *const _ as *const _),
+                std::mem::size_of::<IvshmemRingRequest>() as u32,
+                None,
+                0,
+                None,
+                None,
+            )
+            .as_bool()
+            {
+                return Err(WindowsError::IoctlFailed {
+                    device: self.device.clone(),
+                    request: "IOCTL_IVSHMEM_RING_DOORBELL",
+                    code: GetLastError(),
+                }
+                .into());
+            }
         }
-        Ok(ivshmem_device)
+        Ok(())
+    }
+
+    /// Not supported yet, see `WindowsBackend`.
+    fn wait(&self, _vector: usize) -> Result<u64, Error> {
+        Err(Error::Unsupported("waiting for interrupts"))
     }
 }
 
+/// The driver mapping of an IVSHMEM device. Released when dropped. The device handle is closed once it is unused.
 pub(crate) struct WindowsMemoryMap {
+    handle: Arc<DeviceHandle>,
     peer_id: u64,
     size: u64,
     vectors: u64,
-    ptr: &'static mut [u8],
+    memory: *mut u8,
 }
 
+// The mapping is shared memory. Access is synchronized by the IvshmemDevice that owns it.
+unsafe impl Send for WindowsMemoryMap {}
+unsafe impl Sync for WindowsMemoryMap {}
+
 impl Debug for WindowsMemoryMap {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -321,12 +466,28 @@ impl Debug for WindowsMemoryMap {
 }
 
 impl WindowsMemoryMap {
-    pub fn from_parts(peer_id: u64, size: u64, vectors: u64, ptr: &'static mut [u8]) -> Self {
+    fn from_parts(handle: Arc<DeviceHandle>, peer_id: u64, size: u64, vectors: u64, memory: *mut u8) -> Self {
         Self {
+            handle,
             peer_id,
             size,
             vectors,
-            ptr,
+            memory,
+        }
+    }
+
+    /// Hands the mapping over to a new IvshmemDevice, which releases it when dropped.
+    /// The memory is mapped write-combined, so copies use non-temporal stores when the CPU supports them.
+    pub fn into_device(self, worker_threads: usize) -> IvshmemDevice {
+        let pool = Arc::new(CopyPool::new(worker_threads).with_kernel(CopyKernel::detect()));
+        IvshmemDevice::with_mapping(self.memory, self.size as usize, Some(Box::new(self)), pool)
+    }
+}
+
+impl Drop for WindowsMemoryMap {
+    fn drop(&mut self) {
+        unsafe {
+            DeviceIoControl(self.handle.0, REQUEST_RELEASE_MMAP_CODE, None, 0, None, 0, None, None);
         }
     }
 }